@@ -0,0 +1,328 @@
+use std::{
+    io,
+    fs,
+    path::{Path, PathBuf},
+    fs::File,
+    io::BufRead,
+    io::Write,
+};
+use log::{info, warn};
+use rustc_hash::{FxHashMap, FxHashSet};
+use crate::input::open_input;
+use crate::f2m::{load_cells, matrix_market_header, FeatureCounts};
+use crate::features::FeatureRegistry;
+use crate::output::OutputCompression;
+
+/// An existing `matrix` output directory that new cells are added to.
+/// Existing entries are copied unchanged and only new cells are counted.
+pub struct ExistingMatrix {
+    dir: PathBuf,
+    features: Vec<String>,
+    barcodes: Vec<String>,
+    features_path: PathBuf,
+    barcodes_path: PathBuf,
+    matrix_path: PathBuf,
+    // MatrixMarket field type, e.g. integer or pattern
+    field: String,
+    nonzero: usize,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Find `name` in `dir`, uncompressed or with a compression extension
+fn find_output(dir: &Path, name: &str) -> io::Result<PathBuf> {
+    ["", ".gz", ".zst"].iter()
+        .map(|extension| dir.join(format!("{}{}", name, extension)))
+        .find(|path| path.exists())
+        .ok_or_else(|| io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No {} found in {:?}", name, dir),
+        ))
+}
+
+fn read_lines(path: &Path) -> io::Result<Vec<String>> {
+    open_input(path)?.lines().collect()
+}
+
+/// Path that `path` is written to before being renamed into place
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    PathBuf::from(temp_path)
+}
+
+impl ExistingMatrix {
+    /// Read the features, barcodes and matrix header of `dir`. Everything
+    /// needed is read here, so the output directory may be the same as `dir`.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let features_path = find_output(dir, "features.tsv")?;
+        let barcodes_path = dir.join("barcodes.tsv");
+        let features = read_lines(&features_path)?;
+        let barcodes = read_lines(&barcodes_path)?;
+        let matrix_path = find_output(dir, "matrix.mtx")?;
+
+        let mut field = String::new();
+        let mut size: Option<(usize, usize, usize)> = None;
+        for line in open_input(&matrix_path)?.lines() {
+            let line = line?;
+            if line.starts_with("%%MatrixMarket") {
+                field = line.split_whitespace().nth(3).unwrap_or_default().to_string();
+                continue;
+            }
+            if line.starts_with('%') {
+                continue;
+            }
+            let fields: Vec<usize> = line.split_whitespace()
+                .map(str::parse::<usize>)
+                .collect::<Result<Vec<usize>, _>>()
+                .map_err(|_| invalid(format!("Failed to parse matrix size line in {:?}", matrix_path)))?;
+            if let [nrow, ncol, nonzero] = fields[..] {
+                size = Some((nrow, ncol, nonzero));
+            }
+            break;
+        }
+        let (nrow, ncol, nonzero) = size
+            .ok_or_else(|| invalid(format!("Missing matrix size line in {:?}", matrix_path)))?;
+        if nrow != features.len() || ncol != barcodes.len() {
+            return Err(invalid(format!(
+                "{:?} is {} x {}, but there are {} features and {} barcodes",
+                matrix_path, nrow, ncol, features.len(), barcodes.len()
+            )));
+        }
+        info!("Appending to a matrix of {} features and {} cells", nrow, ncol);
+
+        Ok(ExistingMatrix {
+            dir: dir.to_path_buf(),
+            features,
+            barcodes,
+            features_path,
+            barcodes_path,
+            matrix_path,
+            field,
+            nonzero,
+        })
+    }
+
+    /// Check that appending in place won't leave existing output files next
+    /// to ones with a different compression
+    pub fn check_output(&self, output: &Path, compression: &OutputCompression) -> io::Result<()> {
+        if fs::canonicalize(output)? != fs::canonicalize(&self.dir)? {
+            return Ok(());
+        }
+        for (existing, name) in [
+            (&self.features_path, compression.file_name("features.tsv")),
+            (&self.barcodes_path, "barcodes.tsv".to_string()),
+            (&self.matrix_path, compression.file_name("matrix.mtx")),
+        ] {
+            if existing.file_name().is_some_and(|existing_name| *existing_name != *name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Appending in place needs the same --compression as the existing {:?}", existing),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the features of this run match the existing features by
+    /// name, line by line, and that the counts are of the same type, i.e.
+    /// --binarize and --weights match the existing matrix
+    pub fn check_compatible(&self, registry: &FeatureRegistry, field: &str) -> io::Result<()> {
+        if field != self.field {
+            return Err(invalid(format!(
+                "The existing matrix holds {} values but this run counts {} values, check --binarize and --weights",
+                self.field, field
+            )));
+        }
+        let existing_names = self.features.iter()
+            .map(|line| line.split('\t').next().unwrap_or_default());
+        if !registry.names().eq(existing_names) {
+            return Err(invalid(
+                "Features differ from the existing matrix, use the same BED file and --group setting".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Cells from `cell_file` that are not in the existing matrix, numbered
+    /// after the existing cells
+    pub fn new_cells(&self, cell_file: &Path) -> io::Result<(Vec<String>, FxHashMap<String, u32>)> {
+        let existing: FxHashSet<&str> = self.barcodes.iter().map(String::as_str).collect();
+        let (loaded, _) = load_cells(cell_file)?;
+        let (barcodes, skipped): (Vec<String>, Vec<String>) = loaded.into_iter()
+            .partition(|barcode| !existing.contains(barcode.as_str()));
+        if !skipped.is_empty() {
+            warn!("{} cells are already in the matrix and were not recounted", skipped.len());
+        }
+        if barcodes.is_empty() {
+            warn!("No new cells to add");
+        }
+        info!("Counting {} new cells", barcodes.len());
+        let cells: FxHashMap<String, u32> = barcodes.iter()
+            .enumerate()
+            .map(|(index, barcode)| (barcode.clone(), (self.barcodes.len() + index) as u32))
+            .collect();
+        Ok((barcodes, cells))
+    }
+
+    /// Write the features, the merged matrix and the barcodes to `output`.
+    /// All three are written to temporary files and only renamed into place
+    /// once complete, so an interrupted write never leaves a truncated file
+    /// or touches the existing output, which may be in `output`. The renames
+    /// are not atomic as a group, so a failure between them can leave new
+    /// files next to old ones.
+    pub fn write(
+        &self,
+        output: &Path,
+        registry: &FeatureRegistry,
+        counts: &FeatureCounts,
+        cells: &[String],
+        compression: &OutputCompression,
+    ) -> io::Result<()> {
+        let features_path = output.join(compression.file_name("features.tsv"));
+        let matrix_path = output.join(compression.file_name("matrix.mtx"));
+        let barcodes_path = output.join("barcodes.tsv");
+
+        registry.write(&temp_path(&features_path), compression)?;
+        self.write_merged(&temp_path(&matrix_path), counts, cells.len(), compression)?;
+        self.write_barcodes(&temp_path(&barcodes_path), cells)?;
+
+        for path in [&matrix_path, &barcodes_path, &features_path] {
+            fs::rename(temp_path(path), path)?;
+        }
+        Ok(())
+    }
+
+    /// Write the existing barcodes followed by the new cells
+    fn write_barcodes(&self, outfile: &Path, cells: &[String]) -> io::Result<()> {
+        let mut writer = io::BufWriter::new(File::create(outfile)?);
+        for barcode in self.barcodes.iter().chain(cells.iter()) {
+            writeln!(writer, "{}", barcode)?;
+        }
+        writer.flush()
+    }
+
+    /// Write the existing entries followed by the counts for the new cells
+    fn write_merged(
+        &self,
+        outfile: &Path,
+        counts: &FeatureCounts,
+        new_cells: usize,
+        compression: &OutputCompression,
+    ) -> io::Result<()> {
+        let mut writer = compression.create(outfile)?;
+        let header = matrix_market_header(
+            &self.field,
+            self.features.len(),
+            self.barcodes.len() + new_cells,
+            self.nonzero + counts.nonzero(),
+        );
+        writer.write_all(header.as_bytes())?;
+
+        // copy the entries after the size line
+        let mut past_header = false;
+        for line in open_input(&self.matrix_path)?.lines() {
+            let line = line?;
+            if past_header {
+                writeln!(writer, "{}", line)?;
+            } else if !line.starts_with('%') {
+                past_header = true;
+            }
+        }
+
+        let mut output = String::new();
+        for index in 0..counts.len() {
+            counts.push_entries(index, &mut output);
+            if output.len() >= 1 << 20 {
+                writer.write_all(output.as_bytes())?;
+                output.clear();
+            }
+        }
+        writer.write_all(output.as_bytes())?;
+        writer.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::BedInterval;
+    use crate::testing::TestDir;
+
+    const MATRIX: &str = "%%MatrixMarket matrix coordinate integer general\n%comment\n2 2 2\n1 1 3\n2 2 1\n";
+
+    fn write_existing(dir: &TestDir, barcodes: &str) {
+        dir.write("features.tsv", "chr1-100-200\tchr1:100-200\t.\nchr1-300-400\tchr1:300-400\t.\n");
+        dir.write("barcodes.tsv", barcodes);
+        dir.write("matrix.mtx", MATRIX);
+    }
+
+    fn registry(ends: &[u32]) -> FeatureRegistry {
+        let mut registry = FeatureRegistry::default();
+        for (line, &end) in ends.iter().enumerate() {
+            registry.add_interval(BedInterval { chrom: "chr1".to_string(), start: end - 100, end, line }, None);
+        }
+        registry
+    }
+
+    fn uncompressed() -> OutputCompression {
+        let matches = clap::Command::new("test")
+            .args(crate::compression_args("none"))
+            .get_matches_from(["test"]);
+        OutputCompression::from_matches(&matches, 1).unwrap()
+    }
+
+    #[test]
+    fn reads_the_matrix_header() {
+        let dir = TestDir::new("append-open");
+        write_existing(&dir, "A\nB\n");
+        let existing = ExistingMatrix::open(dir.path()).unwrap();
+        assert_eq!(existing.field, "integer");
+        assert_eq!(existing.nonzero, 2);
+        assert_eq!(existing.barcodes, ["A", "B"]);
+
+        // the size line must match the features and barcodes
+        dir.write("barcodes.tsv", "A\nB\nC\n");
+        let error = ExistingMatrix::open(dir.path()).map(|_| ()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn features_and_field_must_match() {
+        let dir = TestDir::new("append-compatible");
+        write_existing(&dir, "A\nB\n");
+        let existing = ExistingMatrix::open(dir.path()).unwrap();
+        existing.check_compatible(&registry(&[200, 400]), "integer").unwrap();
+        for (registry, field) in [(registry(&[200, 400]), "pattern"), (registry(&[200, 500]), "integer"), (registry(&[200]), "integer")] {
+            let error = existing.check_compatible(&registry, field).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn new_cells_are_appended_after_existing_ones() {
+        let dir = TestDir::new("append-merge");
+        write_existing(&dir, "A\nB\n");
+        let existing = ExistingMatrix::open(dir.path()).unwrap();
+        let cell_file = dir.write("cells.txt", "B\nC\n\nC\n");
+        let (barcodes, cells) = existing.new_cells(&cell_file).unwrap();
+        assert_eq!(barcodes, ["C"]);
+        assert_eq!(cells["C"], 2);
+
+        let mut counts = FeatureCounts::new(2, false);
+        if let FeatureCounts::Counts(features) = &mut counts {
+            features[0].insert(2, 5);
+        }
+        let registry = registry(&[200, 400]);
+        existing.write(dir.path(), &registry, &counts, &barcodes, &uncompressed()).unwrap();
+
+        assert_eq!(fs::read_to_string(dir.join("barcodes.tsv")).unwrap(), "A\nB\nC\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("matrix.mtx")).unwrap(),
+            matrix_market_header("integer", 2, 3, 3) + "1 1 3\n2 2 1\n1 3 5\n"
+        );
+        assert!(!dir.join("matrix.mtx.tmp").exists());
+    }
+}
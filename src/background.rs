@@ -0,0 +1,210 @@
+use std::io;
+use log::warn;
+use rand::{rngs::StdRng, SeedableRng, Rng};
+use rand::distributions::WeightedIndex;
+use rand::prelude::Distribution;
+use crate::fasta::Fasta;
+use crate::regions::RegionIndex;
+
+// grid points along each axis of the GC / accessibility space
+const GRID_SIZE: usize = 50;
+// bandwidth of the gaussian weighting of grid points, in whitened units
+const BANDWIDTH: f64 = 0.1;
+
+/// Fraction of G and C bases in each feature. Features made of several
+/// intervals use the bases from all of them.
+pub fn gc_content(fasta: &mut Fasta, regions: &RegionIndex, total_features: usize) -> io::Result<Vec<f64>> {
+    let mut gc = vec![0u64; total_features];
+    let mut bases = vec![0u64; total_features];
+    let mut sequence: Vec<u8> = Vec::new();
+
+    for (chrom, lapper) in regions.iter() {
+        if !fasta.contains(chrom) {
+            warn!("Chromosome {} not found in FASTA file, GC content of its features will be zero", chrom);
+            continue;
+        }
+        for interval in lapper.intervals.iter() {
+            if interval.val >= total_features {
+                continue;
+            }
+            fasta.fetch(chrom, interval.start as u64, interval.stop as u64, &mut sequence)?;
+            gc[interval.val] += sequence.iter().filter(|&&base| base == b'G' || base == b'C').count() as u64;
+            bases[interval.val] += sequence.len() as u64;
+        }
+    }
+
+    Ok(gc.iter()
+        .zip(bases.iter())
+        .map(|(&gc, &bases)| if bases > 0 { gc as f64 / bases as f64 } else { 0.0 })
+        .collect())
+}
+
+/// Sample background features matched on GC content and accessibility, as
+/// in chromVAR's getBackgroundPeaks. Both variables are whitened and binned
+/// on a regular grid; each feature draws `iterations` background features,
+/// choosing bins with gaussian weights on their distance from its own bin
+/// and features uniformly within a bin. A feature never draws itself, so
+/// with a single feature there is no background.
+pub fn background_peaks(gc: &[f64], totals: &[u64], iterations: usize, seed: u64) -> Vec<Vec<usize>> {
+    let n = gc.len();
+    if n < 2 {
+        return vec![Vec::new(); n];
+    }
+
+    let points: Vec<[f64; 2]> = whiten(
+        &totals.iter().map(|&total| (total as f64 + 1.0).log10()).collect::<Vec<f64>>(),
+        gc,
+    );
+
+    // regular grid spanning the whitened data
+    let mut grids: Vec<(f64, f64)> = Vec::with_capacity(2);
+    for axis in 0..2 {
+        let min = points.iter().map(|point| point[axis]).fold(f64::INFINITY, f64::min);
+        let max = points.iter().map(|point| point[axis]).fold(f64::NEG_INFINITY, f64::max);
+        let step = (max - min) / (GRID_SIZE - 1) as f64;
+        grids.push((min, step));
+    }
+    let nearest = |value: f64, (min, step): (f64, f64)| -> usize {
+        if step > 0.0 {
+            (((value - min) / step).round() as usize).min(GRID_SIZE - 1)
+        } else {
+            0
+        }
+    };
+    let coordinate = |bin: usize| -> [f64; 2] {
+        [
+            grids[0].0 + (bin / GRID_SIZE) as f64 * grids[0].1,
+            grids[1].0 + (bin % GRID_SIZE) as f64 * grids[1].1,
+        ]
+    };
+
+    let mut members: Vec<Vec<usize>> = vec![Vec::new(); GRID_SIZE * GRID_SIZE];
+    for (feature, point) in points.iter().enumerate() {
+        let bin = nearest(point[0], grids[0]) * GRID_SIZE + nearest(point[1], grids[1]);
+        members[bin].push(feature);
+    }
+    let occupied: Vec<usize> = (0..members.len()).filter(|&bin| !members[bin].is_empty()).collect();
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut background: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for (own, &bin) in occupied.iter().enumerate() {
+        let center = coordinate(bin);
+        // summed over a bin, the per-feature weight (density / bin size) is the density
+        let mut weights: Vec<f64> = occupied.iter()
+            .map(|&other| {
+                let point = coordinate(other);
+                let distance = ((point[0] - center[0]).powi(2) + (point[1] - center[1]).powi(2)).sqrt();
+                (-0.5 * (distance / BANDWIDTH).powi(2)).exp()
+            })
+            .collect();
+        // a feature alone in its bin draws from the other bins, or from all
+        // other features when every other bin is too far away to have weight
+        if members[bin].len() == 1 {
+            weights[own] = 0.0;
+        }
+        let sampler = WeightedIndex::new(&weights).ok();
+
+        for (position, &feature) in members[bin].iter().enumerate() {
+            background[feature] = (0..iterations)
+                .map(|_| match &sampler {
+                    Some(sampler) => {
+                        let chosen = occupied[sampler.sample(&mut rng)];
+                        let skip = (chosen == bin).then_some(position);
+                        choose_other(&members[chosen], skip, &mut rng)
+                    }
+                    None => {
+                        let other = rng.gen_range(0..n - 1);
+                        if other >= feature { other + 1 } else { other }
+                    }
+                })
+                .collect();
+        }
+    }
+    background
+}
+
+/// A uniformly chosen feature of `members`, other than the one at `skip`
+fn choose_other(members: &[usize], skip: Option<usize>, rng: &mut StdRng) -> usize {
+    match skip {
+        Some(skip) => {
+            let index = rng.gen_range(0..members.len() - 1);
+            members[if index >= skip { index + 1 } else { index }]
+        }
+        None => members[rng.gen_range(0..members.len())],
+    }
+}
+
+/// Decorrelate and scale two variables using the Cholesky factor of their
+/// covariance matrix
+fn whiten(x: &[f64], y: &[f64]) -> Vec<[f64; 2]> {
+    let n = x.len() as f64;
+    let mean_x = x.iter().sum::<f64>() / n;
+    let mean_y = y.iter().sum::<f64>() / n;
+    let mut var_x = 0.0;
+    let mut var_y = 0.0;
+    let mut cov = 0.0;
+    for (a, b) in x.iter().zip(y.iter()) {
+        var_x += (a - mean_x).powi(2);
+        var_y += (b - mean_y).powi(2);
+        cov += (a - mean_x) * (b - mean_y);
+    }
+    let denominator = (n - 1.0).max(1.0);
+    var_x /= denominator;
+    var_y /= denominator;
+    cov /= denominator;
+
+    // lower triangular factor [[l11, 0], [l21, l22]]; constant variables are left unscaled
+    let l11 = if var_x > 0.0 { var_x.sqrt() } else { 1.0 };
+    let l21 = cov / l11;
+    let residual = var_y - l21 * l21;
+    let l22 = if residual > 0.0 { residual.sqrt() } else { 1.0 };
+
+    x.iter()
+        .zip(y.iter())
+        .map(|(a, b)| {
+            let first = a / l11;
+            [first, (b - l21 * first) / l22]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whitened_variables_are_uncorrelated_with_unit_variance() {
+        let x = [1.0, 2.0, 3.0, 4.0, 5.0];
+        let y = [2.0, 4.5, 5.5, 8.5, 9.0];
+        let points = whiten(&x, &y);
+        let n = points.len() as f64;
+        let mean = |axis: usize| points.iter().map(|point| point[axis]).sum::<f64>() / n;
+        let (mean_a, mean_b) = (mean(0), mean(1));
+        let covariance = |a: usize, b: usize, mean_a: f64, mean_b: f64| {
+            points.iter().map(|point| (point[a] - mean_a) * (point[b] - mean_b)).sum::<f64>() / (n - 1.0)
+        };
+        assert!((covariance(0, 0, mean_a, mean_a) - 1.0).abs() < 1e-9);
+        assert!((covariance(1, 1, mean_b, mean_b) - 1.0).abs() < 1e-9);
+        assert!(covariance(0, 1, mean_a, mean_b).abs() < 1e-9);
+    }
+
+    #[test]
+    fn background_is_matched_and_never_the_feature_itself() {
+        // two groups far apart in GC content, with similar accessibility
+        let gc = [0.2, 0.21, 0.22, 0.8, 0.81, 0.82, 0.5];
+        let totals = [100, 110, 120, 100, 110, 120, 1000];
+        let background = background_peaks(&gc, &totals, 50, 1);
+        assert_eq!(background.len(), gc.len());
+        for (feature, draws) in background.iter().enumerate() {
+            assert_eq!(draws.len(), 50);
+            assert!(!draws.contains(&feature), "feature {} drew itself", feature);
+        }
+        assert!(background[0].iter().all(|&other| other < 3));
+        assert!(background[4].iter().all(|&other| (3..6).contains(&other)));
+        // the isolated feature still draws other features
+        assert!(background[6].iter().all(|&other| other < 6));
+
+        assert_eq!(background, background_peaks(&gc, &totals, 50, 1));
+        assert_eq!(background_peaks(&[0.5], &[10], 50, 1), [Vec::<usize>::new()]);
+    }
+}
@@ -0,0 +1,314 @@
+use std::{
+    io,
+    path::Path,
+    fs::File,
+    io::BufReader,
+    io::Read,
+};
+use flate2::read::MultiGzDecoder;
+
+pub const FLAG_PAIRED: u16 = 0x1;
+pub const FLAG_PROPER_PAIR: u16 = 0x2;
+pub const FLAG_UNMAPPED: u16 = 0x4;
+pub const FLAG_MATE_UNMAPPED: u16 = 0x8;
+pub const FLAG_REVERSE: u16 = 0x10;
+pub const FLAG_SECONDARY: u16 = 0x100;
+pub const FLAG_QC_FAIL: u16 = 0x200;
+pub const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+/// Sequential reader for BAM files. Only the fields needed to build
+/// fragments are decoded.
+pub struct BamReader {
+    reader: BufReader<MultiGzDecoder<File>>,
+    pub references: Vec<String>,
+    pub coordinate_sorted: bool,
+}
+
+/// One alignment. `pos` is 0-based and `ref_len` is the number of
+/// reference bases covered by the alignment.
+#[derive(Default)]
+pub struct BamRecord {
+    pub ref_id: i32,
+    pub pos: i32,
+    pub mapq: u8,
+    pub flag: u16,
+    pub next_ref_id: i32,
+    pub name: Vec<u8>,
+    pub ref_len: u32,
+    // auxiliary data following the quality scores
+    aux: Vec<u8>,
+    buffer: Vec<u8>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+impl BamReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let mut reader = BufReader::with_capacity(1024 * 1024, MultiGzDecoder::new(file));
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != b"BAM\x01" {
+            return Err(invalid("Not a BAM file"));
+        }
+
+        let l_text = read_u32(&mut reader)? as usize;
+        let mut text = vec![0u8; l_text];
+        reader.read_exact(&mut text)?;
+        let text = String::from_utf8_lossy(&text);
+        let coordinate_sorted = text.lines()
+            .find(|line| line.starts_with("@HD"))
+            .is_some_and(|line| line.split('\t').any(|field| field == "SO:coordinate"));
+
+        let n_ref = read_u32(&mut reader)? as usize;
+        let mut references: Vec<String> = Vec::with_capacity(n_ref);
+        for _ in 0..n_ref {
+            let l_name = read_u32(&mut reader)? as usize;
+            let mut name = vec![0u8; l_name];
+            reader.read_exact(&mut name)?;
+            name.pop(); // NUL terminator
+            references.push(String::from_utf8_lossy(&name).into_owned());
+            read_u32(&mut reader)?; // reference length
+        }
+
+        Ok(BamReader { reader, references, coordinate_sorted })
+    }
+
+    /// Read the next alignment into `record`. Returns false at the end of the file.
+    pub fn next_record(&mut self, record: &mut BamRecord) -> io::Result<bool> {
+        let block_size = match read_u32(&mut self.reader) {
+            Ok(size) => size as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if block_size < 32 {
+            return Err(invalid("BAM record is too short"));
+        }
+        let buffer = &mut record.buffer;
+        buffer.resize(block_size, 0);
+        self.reader.read_exact(buffer)?;
+
+        let u16_at = |offset: usize| u16::from_le_bytes([buffer[offset], buffer[offset + 1]]);
+        let i32_at = |offset: usize| i32::from_le_bytes([buffer[offset], buffer[offset + 1], buffer[offset + 2], buffer[offset + 3]]);
+
+        record.ref_id = i32_at(0);
+        record.pos = i32_at(4);
+        let l_read_name = buffer[8] as usize;
+        record.mapq = buffer[9];
+        let n_cigar_op = u16_at(12) as usize;
+        record.flag = u16_at(14);
+        let l_seq = usize::try_from(i32_at(16)).map_err(|_| invalid("BAM record has a negative sequence length"))?;
+        record.next_ref_id = i32_at(20);
+        if l_read_name == 0 {
+            return Err(invalid("BAM record has an empty read name"));
+        }
+
+        // the read name, CIGAR, sequence and qualities follow the fixed fields
+        let name_start: usize = 32;
+        let cigar_start = name_start + l_read_name;
+        let aux_start = (cigar_start + 4 * n_cigar_op)
+            .checked_add(l_seq.div_ceil(2))
+            .and_then(|offset| offset.checked_add(l_seq))
+            .filter(|&offset| offset <= block_size)
+            .ok_or_else(|| invalid("BAM record is truncated"))?;
+
+        record.name.clear();
+        record.name.extend_from_slice(&buffer[name_start..cigar_start - 1]);
+
+        // operations M, D, N, = and X consume the reference
+        record.ref_len = (0..n_cigar_op)
+            .map(|op| i32_at(cigar_start + 4 * op) as u32)
+            .filter(|cigar| matches!(cigar & 0xf, 0 | 2 | 3 | 7 | 8))
+            .map(|cigar| cigar >> 4)
+            .sum();
+
+        record.aux.clear();
+        record.aux.extend_from_slice(&buffer[aux_start..]);
+        Ok(true)
+    }
+}
+
+impl BamRecord {
+    /// Value of a string (type Z) auxiliary tag
+    pub fn string_tag(&self, tag: &[u8; 2]) -> Option<&[u8]> {
+        let aux = &self.aux;
+        let mut offset = 0;
+        while offset + 3 <= aux.len() {
+            let name = &aux[offset..offset + 2];
+            let value_type = aux[offset + 2];
+            offset += 3;
+            let size = match value_type {
+                b'A' | b'c' | b'C' => 1,
+                b's' | b'S' => 2,
+                b'i' | b'I' | b'f' => 4,
+                b'Z' | b'H' => {
+                    let length = aux[offset..].iter().position(|&byte| byte == 0)?;
+                    if name == tag && value_type == b'Z' {
+                        return Some(&aux[offset..offset + length]);
+                    }
+                    length + 1
+                }
+                b'B' => {
+                    let element = match aux.get(offset)? {
+                        b'c' | b'C' => 1,
+                        b's' | b'S' => 2,
+                        b'i' | b'I' | b'f' => 4,
+                        _ => return None,
+                    };
+                    let count = u32::from_le_bytes(aux.get(offset + 1..offset + 5)?.try_into().ok()?) as usize;
+                    5 + element * count
+                }
+                _ => return None,
+            };
+            offset += size;
+        }
+        None
+    }
+
+    /// 0-based position of the read's 5' end on the reference
+    pub fn five_prime(&self) -> i64 {
+        if self.flag & FLAG_REVERSE != 0 {
+            self.pos as i64 + self.ref_len as i64
+        } else {
+            self.pos as i64
+        }
+    }
+}
+
+/// An alignment to encode in a test BAM file
+#[cfg(test)]
+pub struct TestRead<'a> {
+    pub name: &'a str,
+    pub ref_id: i32,
+    pub pos: i32,
+    pub flag: u16,
+    pub mapq: u8,
+    /// (length, operation) pairs
+    pub cigar: &'a [(u32, u32)],
+    pub barcode: Option<&'a str>,
+}
+
+#[cfg(test)]
+impl TestRead<'_> {
+    /// Encode as a BAM record, including the block size
+    pub fn encode(&self) -> Vec<u8> {
+        let l_seq: u32 = self.cigar.iter()
+            .filter(|(_, op)| matches!(op, 0 | 1 | 4 | 7 | 8))
+            .map(|(length, _)| length)
+            .sum();
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(&self.ref_id.to_le_bytes());
+        data.extend_from_slice(&self.pos.to_le_bytes());
+        data.push(self.name.len() as u8 + 1);
+        data.push(self.mapq);
+        data.extend_from_slice(&0u16.to_le_bytes()); // bin
+        data.extend_from_slice(&(self.cigar.len() as u16).to_le_bytes());
+        data.extend_from_slice(&self.flag.to_le_bytes());
+        data.extend_from_slice(&l_seq.to_le_bytes());
+        data.extend_from_slice(&self.ref_id.to_le_bytes()); // mate reference
+        data.extend_from_slice(&0i32.to_le_bytes()); // mate position
+        data.extend_from_slice(&0i32.to_le_bytes()); // template length
+        data.extend_from_slice(self.name.as_bytes());
+        data.push(0);
+        for &(length, op) in self.cigar {
+            data.extend_from_slice(&(length << 4 | op).to_le_bytes());
+        }
+        data.extend(std::iter::repeat_n(0u8, (l_seq as usize).div_ceil(2) + l_seq as usize));
+        data.extend_from_slice(b"NMC\x00");
+        if let Some(barcode) = self.barcode {
+            data.extend_from_slice(b"CBZ");
+            data.extend_from_slice(barcode.as_bytes());
+            data.push(0);
+        }
+        let mut record = (data.len() as u32).to_le_bytes().to_vec();
+        record.extend(data);
+        record
+    }
+}
+
+/// Write a gzip-compressed BAM file with the given references and records
+#[cfg(test)]
+pub fn write_test_bam(path: &Path, header: &str, references: &[(&str, u32)], records: &[Vec<u8>]) {
+    use std::io::Write;
+    let mut data: Vec<u8> = b"BAM\x01".to_vec();
+    data.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    data.extend_from_slice(header.as_bytes());
+    data.extend_from_slice(&(references.len() as u32).to_le_bytes());
+    for (name, length) in references {
+        data.extend_from_slice(&(name.len() as u32 + 1).to_le_bytes());
+        data.extend_from_slice(name.as_bytes());
+        data.push(0);
+        data.extend_from_slice(&length.to_le_bytes());
+    }
+    for record in records {
+        data.extend_from_slice(record);
+    }
+    let mut encoder = flate2::write::GzEncoder::new(File::create(path).unwrap(), flate2::Compression::default());
+    encoder.write_all(&data).unwrap();
+    encoder.finish().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    fn read(name: &'static str, pos: i32, flag: u16, cigar: &'static [(u32, u32)]) -> TestRead<'static> {
+        TestRead { name, ref_id: 1, pos, flag, mapq: 30, cigar, barcode: Some("AAAC-1") }
+    }
+
+    #[test]
+    fn decodes_record_fields_and_tags() {
+        let dir = TestDir::new("bam-decode");
+        let path = dir.join("reads.bam");
+        // 10M 2I 5M 3D 4S covers 18 reference bases
+        let record = read("pair1", 1000, FLAG_PAIRED | FLAG_REVERSE, &[(10, 0), (2, 1), (5, 0), (3, 2), (4, 4)]);
+        write_test_bam(&path, "@HD\tVN:1.6\tSO:coordinate\n", &[("chr1", 5000), ("chr2", 8000)], &[record.encode()]);
+
+        let mut bam = BamReader::open(&path).unwrap();
+        assert_eq!(bam.references, ["chr1", "chr2"]);
+        assert!(bam.coordinate_sorted);
+
+        let mut record = BamRecord::default();
+        assert!(bam.next_record(&mut record).unwrap());
+        assert_eq!((record.ref_id, record.pos, record.mapq, record.next_ref_id), (1, 1000, 30, 1));
+        assert_eq!(record.name, b"pair1");
+        assert_eq!(record.ref_len, 18);
+        assert_eq!(record.five_prime(), 1018);
+        assert_eq!(record.string_tag(b"CB"), Some(&b"AAAC-1"[..]));
+        assert_eq!(record.string_tag(b"XX"), None);
+        assert!(!bam.next_record(&mut record).unwrap());
+    }
+
+    #[test]
+    fn malformed_records_are_errors() {
+        let dir = TestDir::new("bam-malformed");
+        let path = dir.join("reads.bam");
+        let valid = read("pair1", 1000, FLAG_PAIRED, &[(10, 0)]).encode();
+
+        // l_read_name of zero, a negative l_seq, and an l_seq past the end of the record
+        let mut empty_name = valid.clone();
+        empty_name[4 + 8] = 0;
+        let mut negative_seq = valid.clone();
+        negative_seq[4 + 16..4 + 20].copy_from_slice(&(-1i32).to_le_bytes());
+        let mut long_seq = valid.clone();
+        long_seq[4 + 16..4 + 20].copy_from_slice(&i32::MAX.to_le_bytes());
+        let short: Vec<u8> = [&16u32.to_le_bytes()[..], &[0u8; 16]].concat();
+
+        for record in [empty_name, negative_seq, long_seq, short] {
+            write_test_bam(&path, "", &[("chr1", 5000)], &[record]);
+            let mut bam = BamReader::open(&path).unwrap();
+            let error = bam.next_record(&mut BamRecord::default()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    io,
+    path::Path,
+    io::BufRead,
+    borrow::Cow,
+};
+use log::{info, warn};
+use rustc_hash::FxHashMap;
+use crate::input::open_input;
+
+/// Translation of fragment barcodes to new names, applied while streaming.
+/// Barcodes without an entry are kept unchanged.
+pub struct BarcodeMap {
+    // multiome ATAC -> GEX whitelist translation, applied to the barcode
+    // without its GEM group suffix (e.g. "-1")
+    whitelist: FxHashMap<String, String>,
+    // arbitrary old -> new barcode names
+    rename: FxHashMap<String, String>,
+}
+
+impl BarcodeMap {
+    pub fn from_matches(matches: &clap::ArgMatches) -> io::Result<Option<Self>> {
+        let mut whitelist = FxHashMap::default();
+        let mut rename = FxHashMap::default();
+
+        if let (Some(atac), Some(gex)) = (
+            matches.get_one::<String>("atac-whitelist"),
+            matches.get_one::<String>("gex-whitelist"),
+        ) {
+            info!("Translating ATAC barcodes {:?} to GEX barcodes {:?}", atac, gex);
+            whitelist = load_whitelist_pair(Path::new(atac), Path::new(gex))?;
+        }
+
+        if let Some(map_file) = matches.get_one::<String>("barcode-map") {
+            info!("Received barcode map: {:?}", map_file);
+            rename = load_barcode_map(Path::new(map_file))?;
+        }
+
+        if whitelist.is_empty() && rename.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(BarcodeMap { whitelist, rename }))
+    }
+
+    pub fn translate<'a>(&'a self, barcode: &'a str) -> Cow<'a, str> {
+        let mut barcode = Cow::Borrowed(barcode);
+
+        if !self.whitelist.is_empty() {
+            let (core, suffix) = match barcode.find('-') {
+                Some(pos) => barcode.split_at(pos),
+                None => (barcode.as_ref(), ""),
+            };
+            if let Some(translated) = self.whitelist.get(core) {
+                barcode = Cow::Owned(format!("{}{}", translated, suffix));
+            }
+        }
+
+        if let Some(renamed) = self.rename.get(barcode.as_ref()) {
+            return Cow::Borrowed(renamed);
+        }
+        barcode
+    }
+}
+
+fn load_barcode_map(path: &Path) -> io::Result<FxHashMap<String, String>> {
+    let mut rename: FxHashMap<String, String> = FxHashMap::default();
+
+    for (index, line) in open_input(path)?.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        match (fields.next(), fields.next()) {
+            (Some(from), Some(to)) => {
+                rename.insert(from.to_string(), to.to_string());
+            }
+            _ => warn!("Line {}: Barcode map requires two tab-separated columns", index + 1),
+        }
+    }
+
+    Ok(rename)
+}
+
+/// Pair barcodes by line number in the ATAC and GEX whitelists, as for
+/// the 10x multiome whitelists
+fn load_whitelist_pair(atac: &Path, gex: &Path) -> io::Result<FxHashMap<String, String>> {
+    let mut translation: FxHashMap<String, String> = FxHashMap::default();
+
+    let mut gex_lines = open_input(gex)?.lines();
+    for atac_line in open_input(atac)?.lines() {
+        let atac_line = atac_line?;
+        let gex_line = match gex_lines.next() {
+            Some(line) => line?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "ATAC and GEX whitelists have a different number of barcodes",
+                ));
+            }
+        };
+        translation.insert(atac_line.trim().to_string(), gex_line.trim().to_string());
+    }
+    if gex_lines.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "ATAC and GEX whitelists have a different number of barcodes",
+        ));
+    }
+
+    Ok(translation)
+}
+
+/// Cell groups read from a two-column file of barcode and group name.
+/// Groups are numbered in order of first appearance.
+pub struct CellGroups {
+    pub names: Vec<String>,
+    pub cells: FxHashMap<String, usize>,
+}
+
+pub fn load_groups(path: &Path) -> io::Result<CellGroups> {
+    let mut names: Vec<String> = Vec::new();
+    let mut group_index: FxHashMap<String, usize> = FxHashMap::default();
+    let mut cells: FxHashMap<String, usize> = FxHashMap::default();
+
+    for (index, line) in open_input(path)?.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        match (fields.next(), fields.next()) {
+            (Some(barcode), Some(group)) => {
+                let idx = *group_index.entry(group.to_string()).or_insert_with(|| {
+                    names.push(group.to_string());
+                    names.len() - 1
+                });
+                cells.insert(barcode.to_string(), idx);
+            }
+            _ => warn!("Line {}: Group file requires two tab-separated columns", index + 1),
+        }
+    }
+    info!("Loaded {} cells in {} groups", cells.len(), names.len());
+
+    Ok(CellGroups { names, cells })
+}
+
+/// Make a group name safe to use as part of a file name
+fn group_file_name(group: &str) -> String {
+    group.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+        .collect()
+}
+
+impl CellGroups {
+    /// File-safe name for each group, in group order. Fails if two groups
+    /// map to the same name, e.g. "T cell" and "T_cell", as their output
+    /// files would overwrite each other.
+    pub fn file_names(&self) -> io::Result<Vec<String>> {
+        let mut seen: FxHashMap<String, &str> = FxHashMap::default();
+        let mut file_names = Vec::with_capacity(self.names.len());
+        for name in &self.names {
+            let file_name = group_file_name(name);
+            if let Some(other) = seen.insert(file_name.clone(), name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Groups {:?} and {:?} would both be written to files named {:?}", other, name, file_name),
+                ));
+            }
+            file_names.push(file_name);
+        }
+        Ok(file_names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn groups(names: &[&str]) -> CellGroups {
+        CellGroups { names: names.iter().map(|name| name.to_string()).collect(), cells: FxHashMap::default() }
+    }
+
+    #[test]
+    fn file_names_are_sanitized() {
+        assert_eq!(groups(&["T cell", "B/cell", "NK-1.a"]).file_names().unwrap(), ["T_cell", "B_cell", "NK-1.a"]);
+    }
+
+    #[test]
+    fn colliding_file_names_are_an_error() {
+        let error = groups(&["T cell", "B", "T_cell"]).file_names().unwrap_err();
+        assert!(error.to_string().contains("\"T cell\" and \"T_cell\""));
+    }
+}
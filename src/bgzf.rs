@@ -0,0 +1,375 @@
+use std::{
+    io,
+    fs::File,
+    fs::OpenOptions,
+    io::BufRead,
+    io::BufReader,
+    io::Read,
+    io::Seek,
+    io::SeekFrom,
+    io::Write,
+    path::{Path, PathBuf},
+    thread,
+};
+use flate2::{Compression, Crc};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+/// Uncompressed size of each BGZF block. Every block except the last holds
+/// exactly this many bytes, so virtual offsets can be derived from the
+/// uncompressed position.
+pub const BLOCK_SIZE: usize = 0xff00;
+
+/// Empty BGZF block marking the end of the file
+pub const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+    0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Compress a single BGZF block
+pub fn compress_block(data: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len() / 2), level);
+    encoder.write_all(data)?;
+    let mut cdata = encoder.finish()?;
+
+    // incompressible data may not fit in a block, store it instead
+    if cdata.len() + 26 > 0x10000 {
+        let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len() + 64), Compression::none());
+        encoder.write_all(data)?;
+        cdata = encoder.finish()?;
+    }
+
+    let mut crc = Crc::new();
+    crc.update(data);
+
+    let block_size = cdata.len() + 26;
+    let mut block = Vec::with_capacity(block_size);
+    block.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0]);
+    block.extend_from_slice(&((block_size - 1) as u16).to_le_bytes());
+    block.extend_from_slice(&cdata);
+    block.extend_from_slice(&crc.sum().to_le_bytes());
+    block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    Ok(block)
+}
+
+/// Compress data into consecutive BGZF blocks using multiple threads
+pub fn compress_blocks(data: &[u8], level: Compression, num_threads: usize) -> io::Result<Vec<Vec<u8>>> {
+    let chunks: Vec<&[u8]> = data.chunks(BLOCK_SIZE).collect();
+    if num_threads <= 1 || chunks.len() <= 1 {
+        return chunks.iter().map(|chunk| compress_block(chunk, level)).collect();
+    }
+
+    let per_thread = chunks.len().div_ceil(num_threads);
+    thread::scope(|scope| {
+        let handles: Vec<_> = chunks
+            .chunks(per_thread)
+            .map(|work| {
+                scope.spawn(move || {
+                    work.iter()
+                        .map(|chunk| compress_block(chunk, level))
+                        .collect::<io::Result<Vec<Vec<u8>>>>()
+                })
+            })
+            .collect();
+
+        let mut blocks = Vec::with_capacity(chunks.len());
+        for handle in handles {
+            blocks.extend(handle.join().expect("Compression thread panicked")?);
+        }
+        Ok(blocks)
+    })
+}
+
+/// Position of each block in the compressed file, used to convert
+/// uncompressed positions to BGZF virtual offsets
+pub struct BlockLayout {
+    block_offsets: Vec<u64>,
+    compressed_len: u64,
+}
+
+impl BlockLayout {
+    pub fn virtual_offset(&self, position: u64) -> u64 {
+        let block = (position / BLOCK_SIZE as u64) as usize;
+        let within = position % BLOCK_SIZE as u64;
+        match self.block_offsets.get(block) {
+            Some(offset) => (offset << 16) | within,
+            // end of the data, point at the EOF block
+            None => self.compressed_len << 16,
+        }
+    }
+}
+
+/// BGZF writer that buffers data in memory and only opens the output file
+/// while appending compressed blocks, so that many writers can be active
+/// without exhausting file handles
+pub struct BgzfWriter {
+    path: PathBuf,
+    buffer: Vec<u8>,
+    flushed: u64,
+    layout: BlockLayout,
+    level: Compression,
+    num_threads: usize,
+}
+
+impl BgzfWriter {
+    pub fn create(path: &Path, level: Compression, num_threads: usize) -> io::Result<Self> {
+        File::create(path)?;
+        Ok(BgzfWriter {
+            path: path.to_path_buf(),
+            buffer: Vec::new(),
+            flushed: 0,
+            layout: BlockLayout { block_offsets: Vec::new(), compressed_len: 0 },
+            level,
+            num_threads,
+        })
+    }
+
+    /// Current uncompressed position
+    pub fn position(&self) -> u64 {
+        self.flushed + self.buffer.len() as u64
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Buffer `data` until the next `flush_blocks` or `finish`
+    pub fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.buffer.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Compress and append all complete blocks held in the buffer
+    pub fn flush_blocks(&mut self) -> io::Result<()> {
+        let complete = (self.buffer.len() / BLOCK_SIZE) * BLOCK_SIZE;
+        if complete == 0 {
+            return Ok(());
+        }
+        self.append(complete)?;
+        self.buffer.drain(..complete);
+        Ok(())
+    }
+
+    /// Write any remaining data and the EOF marker
+    pub fn finish(mut self) -> io::Result<BlockLayout> {
+        let remaining = self.buffer.len();
+        if remaining > 0 {
+            self.append(remaining)?;
+        }
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        file.write_all(&EOF_BLOCK)?;
+        Ok(self.layout)
+    }
+
+    fn append(&mut self, len: usize) -> io::Result<()> {
+        let blocks = compress_blocks(&self.buffer[..len], self.level, self.num_threads)?;
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        for block in blocks {
+            self.layout.block_offsets.push(self.layout.compressed_len);
+            self.layout.compressed_len += block.len() as u64;
+            file.write_all(&block)?;
+        }
+        self.flushed += len as u64;
+        Ok(())
+    }
+}
+
+/// Whether the file starts with a BGZF block header
+pub fn is_bgzf(path: &Path) -> io::Result<bool> {
+    let mut header = [0u8; 16];
+    let mut file = File::open(path)?;
+    let read = file.read(&mut header)?;
+    Ok(read == 16 && header[..4] == [0x1f, 0x8b, 0x08, 0x04] && header[12..14] == [b'B', b'C'])
+}
+
+/// Sequential BGZF reader that keeps track of the virtual offset of the
+/// next unread byte, so that reading can later resume from the same point
+pub struct BgzfReader {
+    file: BufReader<File>,
+    // compressed offset of the block held in `data`, and of the next block
+    block_offset: u64,
+    next_block_offset: u64,
+    data: Vec<u8>,
+    position: usize,
+    compressed: Vec<u8>,
+}
+
+fn invalid_block(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid BGZF block: {}", message))
+}
+
+impl BgzfReader {
+    /// Open a BGZF file at `virtual_offset`, 0 for the start of the file
+    pub fn open(path: &Path, virtual_offset: u64) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let block_offset = virtual_offset >> 16;
+        file.seek(SeekFrom::Start(block_offset))?;
+        let mut reader = BgzfReader {
+            file: BufReader::with_capacity(1024 * 1024, file),
+            block_offset,
+            next_block_offset: block_offset,
+            data: Vec::new(),
+            position: 0,
+            compressed: Vec::new(),
+        };
+        reader.read_block()?;
+        let within = (virtual_offset & 0xffff) as usize;
+        if within > reader.data.len() {
+            return Err(invalid_block("virtual offset is past the end of the block"));
+        }
+        reader.position = within;
+        Ok(reader)
+    }
+
+    pub fn virtual_offset(&self) -> u64 {
+        (self.block_offset << 16) | self.position as u64
+    }
+
+    /// Decompress the next block into `data`. Returns false at the end of the file.
+    fn read_block(&mut self) -> io::Result<bool> {
+        self.block_offset = self.next_block_offset;
+        self.data.clear();
+        self.position = 0;
+        if self.file.fill_buf()?.is_empty() {
+            return Ok(false);
+        }
+
+        let mut header = [0u8; 12];
+        self.file.read_exact(&mut header)?;
+        if header[..4] != [0x1f, 0x8b, 0x08, 0x04] {
+            return Err(invalid_block("missing gzip header"));
+        }
+        let extra_len = u16::from_le_bytes([header[10], header[11]]) as usize;
+        let mut extra = vec![0u8; extra_len];
+        self.file.read_exact(&mut extra)?;
+
+        // the BC subfield holds the total block size minus 1
+        let mut block_size: Option<usize> = None;
+        let mut offset = 0;
+        while offset + 4 <= extra.len() {
+            let length = u16::from_le_bytes([extra[offset + 2], extra[offset + 3]]) as usize;
+            if extra[offset..offset + 2] == [b'B', b'C'] && length == 2 && offset + 6 <= extra.len() {
+                block_size = Some(u16::from_le_bytes([extra[offset + 4], extra[offset + 5]]) as usize + 1);
+            }
+            offset += 4 + length;
+        }
+        let block_size = block_size.ok_or_else(|| invalid_block("missing BC field"))?;
+        let remaining = block_size
+            .checked_sub(12 + extra_len)
+            .filter(|&remaining| remaining >= 8)
+            .ok_or_else(|| invalid_block("block size is too small"))?;
+
+        self.compressed.resize(remaining, 0);
+        self.file.read_exact(&mut self.compressed)?;
+        // the last 8 bytes are the CRC32 and uncompressed size
+        DeflateDecoder::new(&self.compressed[..remaining - 8]).read_to_end(&mut self.data)?;
+        self.next_block_offset += block_size as u64;
+        Ok(true)
+    }
+}
+
+impl Read for BgzfReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let length = available.len().min(buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.consume(length);
+        Ok(length)
+    }
+}
+
+impl BufRead for BgzfReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // skip empty blocks such as the EOF marker
+        while self.position >= self.data.len() {
+            if !self.read_block()? {
+                break;
+            }
+        }
+        Ok(&self.data[self.position..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position = (self.position + amount).min(self.data.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use flate2::read::MultiGzDecoder;
+    use crate::testing::TestDir;
+
+    /// Data longer than two blocks, with a distinct byte at every position
+    fn test_data() -> Vec<u8> {
+        (0..BLOCK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn virtual_offsets_across_block_boundary() {
+        let dir = TestDir::new("voffset");
+        let path = dir.join("data.gz");
+        let data = test_data();
+        let mut writer = BgzfWriter::create(&path, Compression::default(), 1).unwrap();
+        writer.write_all(&data).unwrap();
+        let layout = writer.finish().unwrap();
+        let file = fs::read(&path).unwrap();
+
+        // the second block starts after the first, whose size is in its BC field
+        let first_block_size = u16::from_le_bytes([file[16], file[17]]) as u64 + 1;
+        let block = BLOCK_SIZE as u64;
+        assert_eq!(layout.virtual_offset(0), 0);
+        assert_eq!(layout.virtual_offset(block - 1), block - 1);
+        assert_eq!(layout.virtual_offset(block), first_block_size << 16);
+        assert_eq!(layout.virtual_offset(block + 10), (first_block_size << 16) | 10);
+        // the end of the data is within the last block, and positions after
+        // the last block point at the EOF block
+        assert_eq!(layout.virtual_offset(data.len() as u64), layout.virtual_offset(2 * block) | 100);
+        assert_eq!(layout.virtual_offset(3 * block), ((file.len() - EOF_BLOCK.len()) as u64) << 16);
+
+        for position in [0, block - 1, block, block + 10, 2 * block + 99] {
+            let mut reader = BgzfReader::open(&path, layout.virtual_offset(position)).unwrap();
+            let mut byte = [0u8; 1];
+            reader.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], data[position as usize]);
+        }
+
+        // reading across the boundary tracks the virtual offset of the next byte
+        let mut reader = BgzfReader::open(&path, layout.virtual_offset(block - 2)).unwrap();
+        let mut bytes = [0u8; 4];
+        reader.read_exact(&mut bytes[..2]).unwrap();
+        reader.fill_buf().unwrap();
+        assert_eq!(reader.virtual_offset(), layout.virtual_offset(block));
+        reader.read_exact(&mut bytes[2..]).unwrap();
+        assert_eq!(bytes[..], data[BLOCK_SIZE - 2..BLOCK_SIZE + 2]);
+    }
+
+    #[test]
+    fn output_is_gzip_with_eof_block() {
+        let dir = TestDir::new("eof");
+        let path = dir.join("data.gz");
+        let data = test_data();
+        let mut writer = BgzfWriter::create(&path, Compression::default(), 2).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.flush_blocks().unwrap();
+        writer.finish().unwrap();
+        let file = fs::read(&path).unwrap();
+
+        assert!(is_bgzf(&path).unwrap());
+        assert_eq!(file[file.len() - EOF_BLOCK.len()..], EOF_BLOCK);
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(file.as_slice()).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn eof_block_is_empty() {
+        // the BC field holds the block size minus one
+        assert_eq!(u16::from_le_bytes([EOF_BLOCK[16], EOF_BLOCK[17]]) as usize, EOF_BLOCK.len() - 1);
+        assert_eq!(compress_block(&[], Compression::default()).unwrap().len(), EOF_BLOCK.len());
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(&EOF_BLOCK[..]).read_to_end(&mut decompressed).unwrap();
+        assert!(decompressed.is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    io,
+    path::Path,
+    error::Error,
+    io::BufRead,
+    io::Write,
+};
+use log::{info, warn};
+use rustc_hash::FxHashSet;
+use crate::barcodes::BarcodeMap;
+use crate::chroms::ChromFilter;
+use crate::fasta::Fasta;
+use crate::fragments::FragmentStream;
+use crate::input::{input_path, open_input};
+use crate::output::OutputCompression;
+use crate::motifs::encode;
+
+const BASES: [char; 4] = ['A', 'C', 'G', 'T'];
+
+/// K-mers are centered on the insertion site, so k must be even
+fn valid_k(k: usize) -> bool {
+    k > 0 && k % 2 == 0 && k <= 10
+}
+
+pub fn bias(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = input_path(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+
+    let fasta_file = Path::new(matches.get_one::<String>("fasta").unwrap());
+    info!("Received FASTA file: {:?}", fasta_file);
+
+    let output = Path::new(matches.get_one::<String>("output").unwrap());
+    let k = *matches.get_one::<usize>("kmer").unwrap();
+    if !valid_k(k) {
+        return Err("K-mer length must be an even number between 2 and 10".into());
+    }
+
+    let chrom_filter = ChromFilter::from_matches(matches)?;
+    let compression = OutputCompression::from_matches(matches, 1)?;
+    let barcode_map = BarcodeMap::from_matches(matches)?;
+    let mut fasta = Fasta::open(fasta_file)?;
+
+    let mut observed = vec![0u64; 1 << (2 * k)];
+    let mut expected = vec![0u64; 1 << (2 * k)];
+    let mut sequence = ChromSequence::default();
+    let mut counted: FxHashSet<String> = FxHashSet::default();
+
+    info!("Counting k-mers at insertion sites");
+    let mut fragments = FragmentStream::open(&frag_file, &chrom_filter, barcode_map.as_ref())?;
+    while let Some(fragment) = fragments.next_fragment()? {
+        if fragment.chrom != sequence.chrom {
+            sequence.load(&mut fasta, fragment.chrom)?;
+            // expected frequencies come from every chromosome with fragments,
+            // counted once even if the fragment file is not sorted
+            if counted.insert(sequence.chrom.clone()) {
+                for start in 0..sequence.sequence.len().saturating_sub(k - 1) {
+                    if let Some(kmer) = kmer_code(&sequence.sequence, start, k) {
+                        expected[kmer] += 1;
+                    }
+                }
+            }
+        }
+        for position in fragment.insertions() {
+            if let Some(kmer) = sequence.kmer_at(position, k) {
+                observed[kmer] += 1;
+            }
+        }
+    }
+
+    let table = BiasTable::from_counts(k, &observed, &expected);
+    info!("Writing bias table: {:?}", output);
+    let mut writer = compression.create(output)?;
+    writeln!(writer, "kmer\tobserved\texpected\tbias")?;
+    for (kmer, value) in table.bias.iter().enumerate() {
+        writeln!(writer, "{}\t{}\t{}\t{:.6}", kmer_string(kmer, k), observed[kmer], expected[kmer], value)?;
+    }
+    writer.finish()?;
+
+    Ok(())
+}
+
+/// Tn5 insertion preference for each k-mer centered on the insertion site,
+/// in genome orientation: its frequency at insertion sites divided by its
+/// frequency in the reference
+pub struct BiasTable {
+    pub k: usize,
+    pub bias: Vec<f64>,
+}
+
+impl BiasTable {
+    pub fn from_counts(k: usize, observed: &[u64], expected: &[u64]) -> Self {
+        let observed_total: u64 = observed.iter().sum();
+        let expected_total: u64 = expected.iter().sum();
+        let bias = observed.iter()
+            .zip(expected.iter())
+            .map(|(&observed, &expected)| {
+                if observed_total == 0 || expected == 0 {
+                    0.0
+                } else {
+                    (observed as f64 / observed_total as f64) / (expected as f64 / expected_total as f64)
+                }
+            })
+            .collect();
+        BiasTable { k, bias }
+    }
+
+    /// Read a table written by `fragtk bias`
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let invalid = |line: usize, message: &str| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{:?} line {}: {}", path, line, message))
+        };
+
+        let mut k: usize = 0;
+        let mut bias: Vec<f64> = Vec::new();
+        for (index, line) in open_input(path)?.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with("kmer\t") {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 4 {
+                return Err(invalid(index + 1, "expected four tab-separated columns"));
+            }
+            if k == 0 {
+                k = fields[0].len();
+                if !valid_k(k) {
+                    return Err(invalid(index + 1, "k-mer length must be an even number between 2 and 10"));
+                }
+                bias = vec![0.0; 1 << (2 * k)];
+            }
+            let mut kmer = fields[0].as_bytes().to_vec();
+            encode(&mut kmer);
+            let code = match kmer_code(&kmer, 0, k) {
+                Some(code) if kmer.len() == k => code,
+                _ => return Err(invalid(index + 1, "invalid k-mer")),
+            };
+            bias[code] = fields[3].parse().map_err(|_| invalid(index + 1, "invalid bias value"))?;
+        }
+        if k == 0 {
+            return Err(invalid(0, "bias table is empty"));
+        }
+        Ok(BiasTable { k, bias })
+    }
+
+    /// Weight that corrects an insertion with the given k-mer for Tn5 bias.
+    /// Insertions without a k-mer, or with a k-mer never seen, count as one.
+    pub fn weight(&self, kmer: Option<usize>) -> f64 {
+        match kmer.map(|kmer| self.bias[kmer]) {
+            Some(bias) if bias > 0.0 => 1.0 / bias,
+            _ => 1.0,
+        }
+    }
+}
+
+/// The encoded sequence of one chromosome, reloaded when the chromosome changes
+#[derive(Default)]
+pub struct ChromSequence {
+    pub chrom: String,
+    pub sequence: Vec<u8>,
+}
+
+impl ChromSequence {
+    pub fn load(&mut self, fasta: &mut Fasta, chrom: &str) -> io::Result<()> {
+        self.chrom = chrom.to_string();
+        if !fasta.fetch(chrom, 0, u64::MAX, &mut self.sequence)? {
+            warn!("Chromosome {} not found in FASTA file", chrom);
+        }
+        encode(&mut self.sequence);
+        Ok(())
+    }
+
+    /// Code of the k-mer centered on `position`
+    pub fn kmer_at(&self, position: u32, k: usize) -> Option<usize> {
+        let start = (position as usize).checked_sub(k / 2)?;
+        kmer_code(&self.sequence, start, k)
+    }
+}
+
+/// 2-bit code of the k-mer starting at `start` in an encoded sequence (see
+/// `motifs::encode`), or None if it contains N or runs past the end
+pub fn kmer_code(sequence: &[u8], start: usize, k: usize) -> Option<usize> {
+    let kmer = sequence.get(start..start + k)?;
+    kmer.iter().try_fold(0usize, |code, &base| {
+        if base > 3 {
+            None
+        } else {
+            Some((code << 2) | base as usize)
+        }
+    })
+}
+
+fn kmer_string(code: usize, k: usize) -> String {
+    (0..k).rev().map(|index| BASES[(code >> (2 * index)) & 3]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    fn encoded(sequence: &str) -> Vec<u8> {
+        let mut sequence = sequence.as_bytes().to_vec();
+        encode(&mut sequence);
+        sequence
+    }
+
+    #[test]
+    fn kmer_codes_round_trip() {
+        let sequence = encoded("ACGTNAC");
+        assert_eq!(kmer_code(&sequence, 0, 4), Some(0b00_01_10_11));
+        assert_eq!(kmer_string(0b00_01_10_11, 4), "ACGT");
+        assert_eq!(kmer_code(&sequence, 2, 2), Some(0b10_11));
+        // k-mers containing N or running past the end have no code
+        assert_eq!(kmer_code(&sequence, 3, 2), None);
+        assert_eq!(kmer_code(&sequence, 6, 2), None);
+
+        let chrom = ChromSequence { chrom: "chr1".to_string(), sequence };
+        assert_eq!(chrom.kmer_at(2, 2), kmer_code(&chrom.sequence, 1, 2));
+        assert_eq!(chrom.kmer_at(0, 2), None);
+    }
+
+    #[test]
+    fn weights_invert_the_bias() {
+        // AA is inserted into twice as often as expected, CC never
+        let mut observed = vec![0u64; 16];
+        let mut expected = vec![0u64; 16];
+        observed[0] = 4;
+        observed[1] = 2;
+        expected[0] = 2;
+        expected[1] = 2;
+        expected[5] = 2;
+        let table = BiasTable::from_counts(2, &observed, &expected);
+        assert!((table.bias[0] - 2.0).abs() < 1e-9);
+        assert!((table.weight(Some(0)) - 0.5).abs() < 1e-9);
+        assert!((table.weight(Some(1)) - 1.0).abs() < 1e-9);
+        assert_eq!(table.bias[5], 0.0);
+        assert_eq!(table.weight(Some(5)), 1.0);
+        assert_eq!(table.weight(None), 1.0);
+    }
+
+    #[test]
+    fn reads_written_tables() {
+        let dir = TestDir::new("bias-table");
+        let table = BiasTable::read(&dir.write("bias.tsv", "kmer\tobserved\texpected\tbias\nAC\t3\t1\t1.5\nGT\t1\t2\t0.25\n")).unwrap();
+        assert_eq!(table.k, 2);
+        assert_eq!(table.bias[0b00_01], 1.5);
+        assert_eq!(table.bias[0b10_11], 0.25);
+        assert_eq!(table.bias.iter().filter(|&&bias| bias > 0.0).count(), 2);
+
+        for contents in ["ACG\t1\t1\t1.0\n", "AN\t1\t1\t1.0\n", "AC\t1\t1\n", "kmer\tobserved\texpected\tbias\n"] {
+            let error = BiasTable::read(&dir.write("bad.tsv", contents)).map(|_| ()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,681 @@
+use std::{
+    io,
+    fs::File,
+    io::BufWriter,
+    io::Write,
+    path::Path,
+};
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use rustc_hash::FxHashMap;
+
+const BIGWIG_MAGIC: u32 = 0x888F_FC26;
+const CHROM_TREE_MAGIC: u32 = 0x78CA_8C91;
+const RTREE_MAGIC: u32 = 0x2468_ACE0;
+const HEADER_SIZE: u64 = 64;
+const ZOOM_HEADER_SIZE: u64 = 24;
+const SUMMARY_SIZE: u64 = 40;
+const ITEMS_PER_SLOT: usize = 1024;
+const RTREE_BLOCK_SIZE: usize = 256;
+const CHROM_BLOCK_SIZE: usize = 256;
+const CHROM_TREE_HEADER_SIZE: u64 = 32;
+const ZOOM_INCREMENT: u32 = 4;
+const MAX_ZOOM_LEVELS: usize = 10;
+
+/// Location of a compressed data section, with offsets relative to the
+/// start of the buffer holding the sections
+struct Section {
+    chrom_id: u32,
+    start: u32,
+    end_chrom_id: u32,
+    end: u32,
+    offset: u64,
+    size: u64,
+}
+
+/// Summary statistics for a zoom level record or the whole file
+#[derive(Clone, Copy)]
+struct Summary {
+    bases: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    sum_squares: f64,
+}
+
+impl Summary {
+    fn new() -> Self {
+        Summary { bases: 0, min: f64::INFINITY, max: f64::NEG_INFINITY, sum: 0.0, sum_squares: 0.0 }
+    }
+
+    fn add(&mut self, bases: u32, value: f32) {
+        let value = value as f64;
+        let bases_f = bases as f64;
+        self.bases += bases as u64;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value * bases_f;
+        self.sum_squares += value * value * bases_f;
+    }
+}
+
+/// Compressed sections and index entries for the full data or one zoom level
+struct SectionBuffer {
+    data: Vec<u8>,
+    sections: Vec<Section>,
+    max_uncompressed: usize,
+    item_count: u64,
+}
+
+impl SectionBuffer {
+    fn new() -> Self {
+        SectionBuffer { data: Vec::new(), sections: Vec::new(), max_uncompressed: 0, item_count: 0 }
+    }
+
+    fn push(&mut self, bounds: (u32, u32, u32, u32), items: u64, raw: &[u8]) -> io::Result<()> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(raw)?;
+        let compressed = encoder.finish()?;
+        let (chrom_id, start, end_chrom_id, end) = bounds;
+        self.sections.push(Section {
+            chrom_id,
+            start,
+            end_chrom_id,
+            end,
+            offset: self.data.len() as u64,
+            size: compressed.len() as u64,
+        });
+        self.data.extend_from_slice(&compressed);
+        self.max_uncompressed = self.max_uncompressed.max(raw.len());
+        self.item_count += items;
+        Ok(())
+    }
+}
+
+/// Accumulates summary records for one zoom level
+struct ZoomLevel {
+    reduction: u32,
+    records: Vec<(u32, u32, u32, Summary)>,
+    current: Option<(u32, u32, u32, Summary)>,
+}
+
+impl ZoomLevel {
+    fn add(&mut self, chrom_id: u32, start: u32, end: u32, value: f32) {
+        let mut pos = start;
+        while pos < end {
+            let bin_start = pos - pos % self.reduction;
+            let overlap_end = end.min(bin_start.saturating_add(self.reduction));
+            match &mut self.current {
+                Some((id, current_start, current_end, summary))
+                    if *id == chrom_id && *current_start == bin_start => {
+                    summary.add(overlap_end - pos, value);
+                    *current_end = overlap_end;
+                }
+                _ => {
+                    self.flush();
+                    let mut summary = Summary::new();
+                    summary.add(overlap_end - pos, value);
+                    self.current = Some((chrom_id, bin_start, overlap_end, summary));
+                }
+            }
+            pos = overlap_end;
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(record) = self.current.take() {
+            self.records.push(record);
+        }
+    }
+}
+
+/// Writes a bigWig file from bedGraph-style records. Records must be
+/// sorted by position and each chromosome must be contiguous. Data are
+/// held in memory and written by `finish`.
+pub struct BigWigWriter {
+    sizes: FxHashMap<String, u32>,
+    // chromosomes in order of appearance, the index is the chromosome id
+    chroms: Vec<(String, u32)>,
+    chrom_ids: FxHashMap<String, u32>,
+    full: SectionBuffer,
+    pending: Vec<(u32, u32, f32)>,
+    pending_chrom: u32,
+    zooms: Vec<ZoomLevel>,
+    summary: Summary,
+}
+
+impl BigWigWriter {
+    /// Create a writer for the given chromosome sizes. `resolution` is the
+    /// smallest span of the records, used to choose zoom levels.
+    pub fn new(sizes: &FxHashMap<String, u32>, resolution: u32) -> Self {
+        let longest = sizes.values().copied().max().unwrap_or(0);
+        let mut zooms = Vec::new();
+        let mut reduction = resolution.max(1).saturating_mul(ZOOM_INCREMENT);
+        while zooms.len() < MAX_ZOOM_LEVELS && reduction < longest {
+            zooms.push(ZoomLevel { reduction, records: Vec::new(), current: None });
+            reduction = reduction.saturating_mul(ZOOM_INCREMENT);
+        }
+
+        BigWigWriter {
+            sizes: sizes.clone(),
+            chroms: Vec::new(),
+            chrom_ids: FxHashMap::default(),
+            full: SectionBuffer::new(),
+            pending: Vec::with_capacity(ITEMS_PER_SLOT),
+            pending_chrom: 0,
+            zooms,
+            summary: Summary::new(),
+        }
+    }
+
+    pub fn add(&mut self, chrom: &str, start: u32, end: u32, value: f32) -> io::Result<()> {
+        let chrom_id = match self.chrom_ids.get(chrom) {
+            Some(&id) => id,
+            None => {
+                let size = match self.sizes.get(chrom) {
+                    Some(&size) => size,
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("Chromosome {} has no size", chrom),
+                        ));
+                    }
+                };
+                let id = self.chroms.len() as u32;
+                self.chroms.push((chrom.to_string(), size));
+                self.chrom_ids.insert(chrom.to_string(), id);
+                id
+            }
+        };
+        if chrom_id < self.pending_chrom
+            || (chrom_id == self.pending_chrom && self.pending.last().is_some_and(|last| start < last.1)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "bigWig records must be sorted by chromosome and position",
+            ));
+        }
+        let end = end.min(self.chroms[chrom_id as usize].1);
+        if start >= end {
+            return Ok(());
+        }
+        if chrom_id != self.pending_chrom || self.pending.len() == ITEMS_PER_SLOT {
+            self.flush_section()?;
+            self.pending_chrom = chrom_id;
+        }
+        self.pending.push((start, end, value));
+        self.summary.add(end - start, value);
+        for zoom in &mut self.zooms {
+            zoom.add(chrom_id, start, end, value);
+        }
+        Ok(())
+    }
+
+    fn flush_section(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let start = self.pending[0].0;
+        let end = self.pending[self.pending.len() - 1].1;
+
+        let mut raw = Vec::with_capacity(24 + self.pending.len() * 12);
+        for value in [self.pending_chrom, start, end, 0, 0] {
+            raw.extend_from_slice(&value.to_le_bytes());
+        }
+        raw.push(1); // bedGraph section
+        raw.push(0);
+        raw.extend_from_slice(&(self.pending.len() as u16).to_le_bytes());
+        for &(item_start, item_end, value) in &self.pending {
+            raw.extend_from_slice(&item_start.to_le_bytes());
+            raw.extend_from_slice(&item_end.to_le_bytes());
+            raw.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let items = self.pending.len() as u64;
+        self.full.push((self.pending_chrom, start, self.pending_chrom, end), items, &raw)?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self, path: &Path) -> io::Result<()> {
+        self.flush_section()?;
+
+        // compress zoom level records
+        let mut zoom_buffers = Vec::with_capacity(self.zooms.len());
+        for zoom in &mut self.zooms {
+            zoom.flush();
+            let mut buffer = SectionBuffer::new();
+            for chunk in zoom.records.chunks(ITEMS_PER_SLOT) {
+                let mut raw = Vec::with_capacity(chunk.len() * 32);
+                for (chrom_id, start, end, summary) in chunk {
+                    raw.extend_from_slice(&chrom_id.to_le_bytes());
+                    raw.extend_from_slice(&start.to_le_bytes());
+                    raw.extend_from_slice(&end.to_le_bytes());
+                    raw.extend_from_slice(&(summary.bases as u32).to_le_bytes());
+                    raw.extend_from_slice(&(summary.min as f32).to_le_bytes());
+                    raw.extend_from_slice(&(summary.max as f32).to_le_bytes());
+                    raw.extend_from_slice(&(summary.sum as f32).to_le_bytes());
+                    raw.extend_from_slice(&(summary.sum_squares as f32).to_le_bytes());
+                }
+                // zoom sections may span chromosomes
+                let (first_chrom, first_start, _, _) = chunk[0];
+                let (last_chrom, _, last_end, _) = chunk[chunk.len() - 1];
+                buffer.push((first_chrom, first_start, last_chrom, last_end), chunk.len() as u64, &raw)?;
+            }
+            zoom_buffers.push((zoom.reduction, buffer));
+        }
+
+        // chromosomes without data are still listed in the header
+        let mut unused: Vec<(String, u32)> = self.sizes.iter()
+            .filter(|(name, _)| !self.chrom_ids.contains_key(*name))
+            .map(|(name, size)| (name.clone(), *size))
+            .collect();
+        unused.sort();
+        self.chroms.extend(unused);
+
+        // layout
+        let zoom_headers_offset = HEADER_SIZE;
+        let summary_offset = zoom_headers_offset + ZOOM_HEADER_SIZE * zoom_buffers.len() as u64;
+        let chrom_tree_offset = summary_offset + SUMMARY_SIZE;
+        let chrom_tree = self.chrom_tree(chrom_tree_offset);
+        let full_data_offset = chrom_tree_offset + chrom_tree.len() as u64;
+        let full_sections_offset = full_data_offset + 8;
+        let full_index_offset = full_sections_offset + self.full.data.len() as u64;
+        let full_index = rtree(&self.full.sections, full_sections_offset, full_index_offset);
+
+        let mut zoom_offsets = Vec::with_capacity(zoom_buffers.len());
+        let mut zoom_blobs = Vec::with_capacity(zoom_buffers.len());
+        let mut offset = full_index_offset + full_index.len() as u64;
+        for (reduction, buffer) in &zoom_buffers {
+            let data_offset = offset;
+            let sections_offset = data_offset + 4;
+            let index_offset = sections_offset + buffer.data.len() as u64;
+            let index = rtree(&buffer.sections, sections_offset, index_offset);
+            offset = index_offset + index.len() as u64;
+            zoom_offsets.push((*reduction, data_offset, index_offset));
+            zoom_blobs.push(index);
+        }
+
+        let max_uncompressed = zoom_buffers.iter()
+            .map(|(_, buffer)| buffer.max_uncompressed)
+            .fold(self.full.max_uncompressed, usize::max)
+            .max(1);
+
+        let mut out = BufWriter::new(File::create(path)?);
+
+        // header
+        out.write_all(&BIGWIG_MAGIC.to_le_bytes())?;
+        out.write_all(&4u16.to_le_bytes())?;
+        out.write_all(&(zoom_buffers.len() as u16).to_le_bytes())?;
+        out.write_all(&chrom_tree_offset.to_le_bytes())?;
+        out.write_all(&full_data_offset.to_le_bytes())?;
+        out.write_all(&full_index_offset.to_le_bytes())?;
+        out.write_all(&0u16.to_le_bytes())?; // field count
+        out.write_all(&0u16.to_le_bytes())?; // defined field count
+        out.write_all(&0u64.to_le_bytes())?; // autoSql offset
+        out.write_all(&summary_offset.to_le_bytes())?;
+        out.write_all(&(max_uncompressed as u32).to_le_bytes())?;
+        out.write_all(&0u64.to_le_bytes())?; // reserved
+
+        for (reduction, data_offset, index_offset) in &zoom_offsets {
+            out.write_all(&reduction.to_le_bytes())?;
+            out.write_all(&0u32.to_le_bytes())?;
+            out.write_all(&data_offset.to_le_bytes())?;
+            out.write_all(&index_offset.to_le_bytes())?;
+        }
+
+        let summary = if self.summary.bases == 0 {
+            Summary { bases: 0, min: 0.0, max: 0.0, sum: 0.0, sum_squares: 0.0 }
+        } else {
+            self.summary
+        };
+        out.write_all(&summary.bases.to_le_bytes())?;
+        out.write_all(&summary.min.to_le_bytes())?;
+        out.write_all(&summary.max.to_le_bytes())?;
+        out.write_all(&summary.sum.to_le_bytes())?;
+        out.write_all(&summary.sum_squares.to_le_bytes())?;
+
+        out.write_all(&chrom_tree)?;
+
+        out.write_all(&(self.full.sections.len() as u64).to_le_bytes())?;
+        out.write_all(&self.full.data)?;
+        out.write_all(&full_index)?;
+
+        for ((_, buffer), index) in zoom_buffers.iter().zip(zoom_blobs) {
+            out.write_all(&(buffer.item_count as u32).to_le_bytes())?;
+            out.write_all(&buffer.data)?;
+            out.write_all(&index)?;
+        }
+
+        // trailing magic
+        out.write_all(&BIGWIG_MAGIC.to_le_bytes())?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Build the chromosome B+ tree, written at file offset `tree_offset`.
+    /// Nodes are padded to `block_size` items, as in the UCSC tools, so that
+    /// each level can be located from the node counts alone.
+    fn chrom_tree(&self, tree_offset: u64) -> Vec<u8> {
+        let mut sorted: Vec<(usize, &(String, u32))> = self.chroms.iter().enumerate().collect();
+        sorted.sort_by(|a, b| a.1.0.as_bytes().cmp(b.1.0.as_bytes()));
+
+        let key_size = self.chroms.iter().map(|(name, _)| name.len()).max().unwrap_or(1).max(1);
+        let block_size = sorted.len().clamp(1, CHROM_BLOCK_SIZE);
+        // leaf values (id and size) and child offsets are both 8 bytes
+        let item_size = key_size + 8;
+        let node_size = (4 + block_size * item_size) as u64;
+        let key = |name: &str| -> Vec<u8> {
+            let mut key = name.as_bytes().to_vec();
+            key.resize(key_size, 0);
+            key
+        };
+
+        // number of nodes at each level, from the leaves up to the root
+        let mut level_nodes: Vec<usize> = vec![sorted.len().div_ceil(block_size).max(1)];
+        while level_nodes[level_nodes.len() - 1] > 1 {
+            level_nodes.push(level_nodes[level_nodes.len() - 1].div_ceil(block_size));
+        }
+
+        let mut tree = Vec::new();
+        tree.extend_from_slice(&CHROM_TREE_MAGIC.to_le_bytes());
+        tree.extend_from_slice(&(block_size as u32).to_le_bytes());
+        tree.extend_from_slice(&(key_size as u32).to_le_bytes());
+        tree.extend_from_slice(&8u32.to_le_bytes());
+        tree.extend_from_slice(&(sorted.len() as u64).to_le_bytes());
+        tree.extend_from_slice(&0u64.to_le_bytes());
+
+        // levels are written from the root down to the leaves
+        let mut level_offset = tree_offset + CHROM_TREE_HEADER_SIZE;
+        for depth in (0..level_nodes.len()).rev() {
+            let child_level_offset = level_offset + level_nodes[depth] as u64 * node_size;
+            let items = if depth == 0 { sorted.len() } else { level_nodes[depth - 1] };
+            // leaf items below each child node
+            let span = block_size.pow(depth as u32);
+            for node in 0..level_nodes[depth] {
+                let first = node * block_size;
+                let last = (first + block_size).min(items);
+                tree.push(if depth == 0 { 1 } else { 0 });
+                tree.push(0);
+                tree.extend_from_slice(&((last - first) as u16).to_le_bytes());
+                for item in first..last {
+                    if depth == 0 {
+                        let (id, (name, size)) = sorted[item];
+                        tree.extend_from_slice(&key(name));
+                        tree.extend_from_slice(&(id as u32).to_le_bytes());
+                        tree.extend_from_slice(&size.to_le_bytes());
+                    } else {
+                        tree.extend_from_slice(&key(&sorted[item * span].1.0));
+                        tree.extend_from_slice(&(child_level_offset + item as u64 * node_size).to_le_bytes());
+                    }
+                }
+                tree.resize(tree.len() + (block_size - (last - first)) * item_size, 0);
+            }
+            level_offset = child_level_offset;
+        }
+        tree
+    }
+}
+
+/// Build an R-tree index over data sections. `sections_offset` is the file
+/// offset of the first section and `index_offset` where the index is written.
+fn rtree(sections: &[Section], sections_offset: u64, index_offset: u64) -> Vec<u8> {
+    // bounds of each item: (start chrom, start, end chrom, end)
+    type Bounds = (u32, u32, u32, u32);
+    let leaf_bounds: Vec<Bounds> = sections.iter()
+        .map(|s| (s.chrom_id, s.start, s.end_chrom_id, s.end))
+        .collect();
+
+    // nodes at each level, as ranges into the level below
+    let mut levels: Vec<Vec<(usize, usize, Bounds)>> = Vec::new();
+    let mut below: Vec<Bounds> = leaf_bounds.clone();
+    loop {
+        let nodes: Vec<(usize, usize, Bounds)> = if below.is_empty() {
+            vec![(0, 0, (0, 0, 0, 0))]
+        } else {
+            (0..below.len())
+                .step_by(RTREE_BLOCK_SIZE)
+                .map(|first| {
+                    let last = (first + RTREE_BLOCK_SIZE).min(below.len());
+                    let bounds = (below[first].0, below[first].1, below[last - 1].2, below[last - 1].3);
+                    (first, last, bounds)
+                })
+                .collect()
+        };
+        let done = nodes.len() == 1;
+        below = nodes.iter().map(|n| n.2).collect();
+        levels.push(nodes);
+        if done {
+            break;
+        }
+    }
+
+    // node offsets, written from the root level down to the leaves
+    let mut level_offsets = vec![0u64; levels.len()];
+    let mut offset = index_offset + 48;
+    for (depth, nodes) in levels.iter().enumerate().rev() {
+        level_offsets[depth] = offset;
+        let item_size = if depth == 0 { 32 } else { 24 };
+        offset += nodes.iter().map(|(first, last, _)| 4 + item_size * (last - first) as u64).sum::<u64>();
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&RTREE_MAGIC.to_le_bytes());
+    out.extend_from_slice(&(RTREE_BLOCK_SIZE as u32).to_le_bytes());
+    out.extend_from_slice(&(sections.len() as u64).to_le_bytes());
+    let (start_chrom, start_base, end_chrom, end_base) = match (leaf_bounds.first(), leaf_bounds.last()) {
+        (Some(first), Some(last)) => (first.0, first.1, last.2, last.3),
+        _ => (0, 0, 0, 0),
+    };
+    for value in [start_chrom, start_base, end_chrom, end_base] {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    let end_file_offset = sections.last().map(|s| sections_offset + s.offset + s.size).unwrap_or(sections_offset);
+    out.extend_from_slice(&end_file_offset.to_le_bytes());
+    out.extend_from_slice(&(ITEMS_PER_SLOT as u32).to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+
+    for depth in (0..levels.len()).rev() {
+        // offsets of the nodes in the level below, for child pointers
+        let child_offsets: Vec<u64> = if depth == 0 {
+            Vec::new()
+        } else {
+            let item_size = if depth - 1 == 0 { 32 } else { 24 };
+            let mut offset = level_offsets[depth - 1];
+            levels[depth - 1].iter()
+                .map(|(first, last, _)| {
+                    let node_offset = offset;
+                    offset += 4 + item_size * (last - first) as u64;
+                    node_offset
+                })
+                .collect()
+        };
+
+        for &(first, last, _) in &levels[depth] {
+            out.push(if depth == 0 { 1 } else { 0 });
+            out.push(0);
+            out.extend_from_slice(&((last - first) as u16).to_le_bytes());
+            for item in first..last {
+                if depth == 0 {
+                    let bounds = leaf_bounds[item];
+                    for value in [bounds.0, bounds.1, bounds.2, bounds.3] {
+                        out.extend_from_slice(&value.to_le_bytes());
+                    }
+                    out.extend_from_slice(&(sections_offset + sections[item].offset).to_le_bytes());
+                    out.extend_from_slice(&sections[item].size.to_le_bytes());
+                } else {
+                    let bounds = levels[depth - 1][item].2;
+                    for value in [bounds.0, bounds.1, bounds.2, bounds.3] {
+                        out.extend_from_slice(&value.to_le_bytes());
+                    }
+                    out.extend_from_slice(&child_offsets[item].to_le_bytes());
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, io::Read};
+    use flate2::read::ZlibDecoder;
+
+    fn u16_at(data: &[u8], offset: u64) -> u16 {
+        let offset = offset as usize;
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], offset: u64) -> u32 {
+        let offset = offset as usize;
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], offset: u64) -> u64 {
+        let offset = offset as usize;
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// Look up a chromosome's id and size in the B+ tree at `tree_offset`
+    fn find_chrom(data: &[u8], tree_offset: u64, name: &str) -> Option<(u32, u32)> {
+        assert_eq!(u32_at(data, tree_offset), CHROM_TREE_MAGIC);
+        let key_size = u32_at(data, tree_offset + 8) as usize;
+        let mut key = name.as_bytes().to_vec();
+        key.resize(key_size, 0);
+
+        let mut node = tree_offset + CHROM_TREE_HEADER_SIZE;
+        loop {
+            let is_leaf = data[node as usize] == 1;
+            let count = u16_at(data, node + 2) as u64;
+            let item_size = key_size as u64 + 8;
+            let item_key = |item: u64| {
+                let offset = (node + 4 + item * item_size) as usize;
+                &data[offset..offset + key_size]
+            };
+            if is_leaf {
+                return (0..count)
+                    .find(|&item| item_key(item) == key.as_slice())
+                    .map(|item| {
+                        let value = node + 4 + item * item_size + key_size as u64;
+                        (u32_at(data, value), u32_at(data, value + 4))
+                    });
+            }
+            // last child whose first key is not after the key
+            let child = (0..count).rev().find(|&item| item_key(item) <= key.as_slice())?;
+            node = u64_at(data, node + 4 + child * item_size + key_size as u64);
+        }
+    }
+
+    /// Leaf items of the R-tree at `index_offset`: bounds, data offset and size
+    fn rtree_leaves(data: &[u8], index_offset: u64) -> Vec<([u32; 4], u64, u64)> {
+        assert_eq!(u32_at(data, index_offset), RTREE_MAGIC);
+        let mut leaves = Vec::new();
+        let mut nodes = vec![index_offset + 48];
+        while let Some(node) = nodes.pop() {
+            let is_leaf = data[node as usize] == 1;
+            let count = u16_at(data, node + 2) as u64;
+            for item in (0..count).rev() {
+                if is_leaf {
+                    let offset = node + 4 + item * 32;
+                    let bounds = [0, 1, 2, 3].map(|i| u32_at(data, offset + 4 * i));
+                    leaves.push((bounds, u64_at(data, offset + 16), u64_at(data, offset + 24)));
+                } else {
+                    nodes.push(u64_at(data, node + 4 + item * 24 + 16));
+                }
+            }
+        }
+        leaves.reverse();
+        leaves
+    }
+
+    fn write_bigwig(name: &str, writer: BigWigWriter) -> Vec<u8> {
+        let dir = crate::testing::TestDir::new(name);
+        let path = dir.join("track.bw");
+        writer.finish(&path).unwrap();
+        fs::read(&path).unwrap()
+    }
+
+    #[test]
+    fn round_trip_two_chromosomes() {
+        let sizes: FxHashMap<String, u32> = [("chr1".to_string(), 10_000), ("chr2".to_string(), 5_000)]
+            .into_iter()
+            .collect();
+        let mut writer = BigWigWriter::new(&sizes, 10);
+        writer.add("chr1", 0, 10, 1.0).unwrap();
+        writer.add("chr1", 100, 120, 2.0).unwrap();
+        writer.add("chr2", 50, 60, 4.0).unwrap();
+        let data = write_bigwig("roundtrip", writer);
+
+        // header
+        assert_eq!(u32_at(&data, 0), BIGWIG_MAGIC);
+        assert_eq!(u32_at(&data, data.len() as u64 - 4), BIGWIG_MAGIC);
+        let zoom_levels = u16_at(&data, 6) as u64;
+        let chrom_tree_offset = u64_at(&data, 8);
+        let full_data_offset = u64_at(&data, 16);
+        let full_index_offset = u64_at(&data, 24);
+        let summary_offset = u64_at(&data, 44);
+        // reductions of 40, 160, 640 and 2560 are below the longest chromosome
+        assert_eq!(zoom_levels, 4);
+        assert_eq!(summary_offset, HEADER_SIZE + ZOOM_HEADER_SIZE * zoom_levels);
+        assert_eq!(chrom_tree_offset, summary_offset + SUMMARY_SIZE);
+
+        // summary
+        assert_eq!(u64_at(&data, summary_offset), 40);
+        assert_eq!(f64::from_bits(u64_at(&data, summary_offset + 24)), 10.0 + 40.0 + 40.0);
+
+        // chromosome tree
+        assert_eq!(find_chrom(&data, chrom_tree_offset, "chr1"), Some((0, 10_000)));
+        assert_eq!(find_chrom(&data, chrom_tree_offset, "chr2"), Some((1, 5_000)));
+        assert_eq!(find_chrom(&data, chrom_tree_offset, "chr3"), None);
+
+        // data sections, one per chromosome, located through the R-tree
+        assert_eq!(u64_at(&data, full_data_offset), 2);
+        let leaves = rtree_leaves(&data, full_index_offset);
+        assert_eq!(leaves.len(), 2);
+        assert_eq!(leaves[0].0, [0, 0, 0, 120]);
+        assert_eq!(leaves[1].0, [1, 50, 1, 60]);
+        assert_eq!(leaves[0].1, full_data_offset + 8);
+
+        let (_, offset, size) = leaves[1];
+        let mut raw = Vec::new();
+        ZlibDecoder::new(&data[offset as usize..(offset + size) as usize]).read_to_end(&mut raw).unwrap();
+        assert_eq!(u32_at(&raw, 0), 1); // chromosome id
+        assert_eq!(raw[20], 1); // bedGraph section
+        assert_eq!(u16_at(&raw, 22), 1);
+        assert_eq!((u32_at(&raw, 24), u32_at(&raw, 28)), (50, 60));
+        assert_eq!(f32::from_bits(u32_at(&raw, 32)), 4.0);
+
+        // zoom levels
+        let mut expected_data_offset = full_index_offset;
+        for level in 0..zoom_levels {
+            let header = HEADER_SIZE + ZOOM_HEADER_SIZE * level;
+            assert_eq!(u32_at(&data, header), 40 << (2 * level));
+            let data_offset = u64_at(&data, header + 8);
+            let index_offset = u64_at(&data, header + 16);
+            assert!(data_offset > expected_data_offset);
+            let leaves = rtree_leaves(&data, index_offset);
+            assert_eq!(leaves.len(), 1);
+            assert_eq!(leaves[0].1, data_offset + 4);
+            assert_eq!(leaves[0].1 + leaves[0].2, index_offset);
+            expected_data_offset = index_offset;
+        }
+    }
+
+    #[test]
+    fn chrom_tree_with_several_levels() {
+        let sizes: FxHashMap<String, u32> = (0..70_000)
+            .map(|index| (format!("contig{}", index), 1000 + index))
+            .collect();
+        let writer = BigWigWriter::new(&sizes, 10);
+        let data = write_bigwig("chromtree", writer);
+
+        let chrom_tree_offset = u64_at(&data, 8);
+        assert_eq!(u32_at(&data, chrom_tree_offset + 4), CHROM_BLOCK_SIZE as u32);
+        assert_eq!(u64_at(&data, chrom_tree_offset + 16), 70_000);
+        for index in [0, 1, 255, 256, 65_535, 65_536, 69_999] {
+            let (_, size) = find_chrom(&data, chrom_tree_offset, &format!("contig{}", index)).unwrap();
+            assert_eq!(size, 1000 + index);
+        }
+        assert_eq!(find_chrom(&data, chrom_tree_offset, "contig70000"), None);
+    }
+}
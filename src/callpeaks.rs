@@ -0,0 +1,302 @@
+use std::{
+    io,
+    fs,
+    path::Path,
+    error::Error,
+    io::Write,
+    thread,
+};
+use log::info;
+use rustc_hash::FxHashSet;
+use crate::barcodes::{load_groups, BarcodeMap};
+use crate::chroms::{load_chrom_sizes, ChromFilter};
+use crate::fragments::FragmentStream;
+use crate::input::{input_path, is_stdin, stdin_unsupported};
+use crate::output::OutputCompression;
+use crate::peaks::{iterative_overlap_merge, normalize_scores, write_bed, Peak};
+use crate::stats::poisson_upper_log10;
+
+// significant regions closer than this are merged into one peak
+const MAX_GAP: u32 = 50;
+
+pub fn callpeaks(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = input_path(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+    if is_stdin(&frag_file) {
+        return Err(stdin_unsupported("callpeaks").into());
+    }
+
+    let group_file = Path::new(matches.get_one::<String>("groups").unwrap());
+    info!("Received group file: {:?}", group_file);
+
+    let output_path = Path::new(matches.get_one::<String>("outdir").unwrap());
+    info!("Received output directory: {:?}", output_path);
+    fs::create_dir_all(output_path)?;
+
+    let genome_size = match matches.get_one::<String>("genome-size").unwrap().as_str() {
+        "hs" => 2.7e9,
+        "mm" => 1.87e9,
+        size => size.parse::<f64>().map_err(|_| "Failed to parse genome size")?,
+    };
+    let pvalue = *matches.get_one::<f64>("pvalue").unwrap();
+    let params = PeakParams {
+        extsize: *matches.get_one::<u32>("extsize").unwrap(),
+        llocal: *matches.get_one::<u32>("llocal").unwrap(),
+        threshold: -pvalue.log10(),
+    };
+    let width = *matches.get_one::<u32>("width").unwrap();
+    let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let compression = OutputCompression::from_matches(matches, num_threads)?;
+    if params.extsize == 0 || params.llocal == 0 || width == 0 {
+        return Err("Extension size, local lambda window and peak width must be greater than zero".into());
+    }
+    info!(
+        "Extension size: {}, local window: {}, p-value cutoff: {}, genome size: {}",
+        params.extsize, params.llocal, pvalue, genome_size
+    );
+
+    let groups = load_groups(group_file)?;
+    let file_names = groups.file_names()?;
+    let chrom_filter = ChromFilter::from_matches(matches)?;
+    let barcode_map = BarcodeMap::from_matches(matches)?;
+    let chrom_sizes = match matches.get_one::<String>("genome") {
+        Some(genome) => Some(load_chrom_sizes(Path::new(genome))?),
+        None => None,
+    };
+
+    // first pass for the genome-wide background rate of each group
+    info!("Counting insertions per group");
+    let mut totals = vec![0u64; groups.names.len()];
+    let mut fragments = FragmentStream::open(&frag_file, &chrom_filter, barcode_map.as_ref())?;
+    while let Some(fragment) = fragments.next_fragment()? {
+        if let Some(&group) = groups.cells.get(fragment.barcode.as_ref()) {
+            totals[group] += 2;
+        }
+    }
+    let lambda_bg: Vec<f64> = totals.iter()
+        .map(|&total| total as f64 * params.extsize as f64 / genome_size)
+        .collect();
+
+    // second pass: collect insertions for one chromosome at a time
+    let mut peaks: Vec<Vec<Peak>> = groups.names.iter().map(|_| Vec::new()).collect();
+    let mut positions: Vec<Vec<u32>> = groups.names.iter().map(|_| Vec::new()).collect();
+    let mut current_chrom = String::new();
+    // peaks are called once per chromosome, so each must be contiguous
+    let mut finished_chroms: FxHashSet<String> = FxHashSet::default();
+
+    let mut fragments = FragmentStream::open(&frag_file, &chrom_filter, barcode_map.as_ref())?;
+    while let Some(fragment) = fragments.next_fragment()? {
+        if fragment.chrom != current_chrom {
+            if finished_chroms.contains(fragment.chrom) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Fragment file is not sorted by chromosome: {} appears in more than one place", fragment.chrom),
+                ).into());
+            }
+            call_all_groups(&current_chrom, &mut positions, &mut peaks, &lambda_bg, &params, num_threads);
+            finished_chroms.insert(std::mem::replace(&mut current_chrom, fragment.chrom.to_string()));
+        }
+        let group = match groups.cells.get(fragment.barcode.as_ref()) {
+            Some(&group) => group,
+            None => continue,
+        };
+        positions[group].extend(fragment.insertions());
+    }
+    call_all_groups(&current_chrom, &mut positions, &mut peaks, &lambda_bg, &params, num_threads);
+
+    // write peaks for each group
+    let mut all_peaks: Vec<Peak> = Vec::new();
+    for (group, mut group_peaks) in peaks.into_iter().enumerate() {
+        let name = &groups.names[group];
+        for (index, peak) in group_peaks.iter_mut().enumerate() {
+            peak.name = format!("{}_peak_{}", file_names[group], index + 1);
+        }
+        let path = output_path.join(compression.file_name(&format!("{}_peaks.narrowPeak", file_names[group])));
+        info!("Writing {} peaks for group {}: {:?}", group_peaks.len(), name, path);
+        write_narrowpeak(&path, &group_peaks, &compression)?;
+
+        normalize_scores(&mut group_peaks);
+        all_peaks.extend(group_peaks);
+    }
+
+    // merged, fixed-width, non-overlapping peak set
+    let merged = iterative_overlap_merge(all_peaks, width, chrom_sizes.as_ref());
+    let merged_path = output_path.join(compression.file_name("peaks.bed"));
+    info!("Writing {} merged peaks: {:?}", merged.len(), merged_path);
+    write_bed(&merged_path, &merged, &compression)?;
+
+    Ok(())
+}
+
+struct PeakParams {
+    extsize: u32,
+    llocal: u32,
+    // -log10 p-value cutoff
+    threshold: f64,
+}
+
+/// A region of significant enrichment and the statistics at its summit
+struct Region {
+    start: u32,
+    end: u32,
+    summit: u32,
+    pileup: u32,
+    log10p: f64,
+    fold: f64,
+}
+
+impl Region {
+    fn into_peak(self, chrom: &str) -> Peak {
+        Peak {
+            chrom: chrom.to_string(),
+            start: self.start,
+            end: self.end,
+            summit: self.summit,
+            name: String::new(),
+            score: self.log10p,
+            signal: self.fold,
+            pvalue: self.log10p,
+        }
+    }
+}
+
+/// Call peaks for every group on the current chromosome and clear the insertions
+fn call_all_groups(
+    chrom: &str,
+    positions: &mut [Vec<u32>],
+    peaks: &mut [Vec<Peak>],
+    lambda_bg: &[f64],
+    params: &PeakParams,
+    num_threads: usize,
+) {
+    if chrom.is_empty() {
+        return;
+    }
+    info!("Calling peaks on {}", chrom);
+
+    let per_thread = positions.len().div_ceil(num_threads.max(1)).max(1);
+    thread::scope(|scope| {
+        for ((positions, peaks), lambda_bg) in positions.chunks_mut(per_thread)
+            .zip(peaks.chunks_mut(per_thread))
+            .zip(lambda_bg.chunks(per_thread)) {
+            scope.spawn(move || {
+                for ((positions, peaks), &lambda) in positions.iter_mut().zip(peaks.iter_mut()).zip(lambda_bg) {
+                    positions.sort_unstable();
+                    peaks.extend(
+                        call_chrom_peaks(positions, lambda, params)
+                            .into_iter()
+                            .map(|region| region.into_peak(chrom))
+                    );
+                    positions.clear();
+                    positions.shrink_to_fit();
+                }
+            });
+        }
+    });
+}
+
+/// Find enriched regions from sorted insertion positions. Each insertion is
+/// extended to `extsize` bp centered on the cut site, and the pileup is
+/// tested against the larger of the genome-wide and local background.
+fn call_chrom_peaks(positions: &[u32], lambda_bg: f64, params: &PeakParams) -> Vec<Region> {
+    let mut regions: Vec<Region> = Vec::new();
+    if positions.is_empty() {
+        return regions;
+    }
+
+    let half = params.extsize / 2;
+    let half_window = params.llocal / 2;
+    let extsize = params.extsize as f64;
+
+    let local_lambda = |position: u32| -> f64 {
+        let low = positions.partition_point(|&p| p < position.saturating_sub(half_window));
+        let high = positions.partition_point(|&p| p < position.saturating_add(half_window));
+        (high - low) as f64 * extsize / params.llocal as f64
+    };
+
+    let mut current: Option<Region> = None;
+    let close = |region: Region, regions: &mut Vec<Region>| {
+        if region.end - region.start >= params.extsize {
+            regions.push(region);
+        }
+    };
+
+    // sweep over the extended insertions, which start and end in sorted order
+    let starts = positions.iter().map(|&p| p.saturating_sub(half));
+    let ends = positions.iter().map(|&p| p.saturating_sub(half) + params.extsize);
+    let mut starts = starts.peekable();
+    let mut ends = ends.peekable();
+    let mut pileup: u32 = 0;
+    let mut position: u32 = 0;
+
+    loop {
+        let next = match (starts.peek(), ends.peek()) {
+            (Some(&s), Some(&e)) => s.min(e),
+            (None, Some(&e)) => e,
+            _ => break,
+        };
+
+        // segment [position, next) has constant pileup
+        if pileup > 0 && next > position {
+            let middle = position + (next - position) / 2;
+            let lambda = lambda_bg.max(local_lambda(middle));
+            let log10p = poisson_upper_log10(pileup as u64, lambda);
+            if log10p >= params.threshold {
+                let fold = pileup as f64 / lambda;
+                match &mut current {
+                    Some(region) if position <= region.end + MAX_GAP => {
+                        region.end = next;
+                        if pileup > region.pileup {
+                            region.pileup = pileup;
+                            region.summit = middle;
+                            region.log10p = log10p;
+                            region.fold = fold;
+                        }
+                    }
+                    _ => {
+                        if let Some(region) = current.take() {
+                            close(region, &mut regions);
+                        }
+                        current = Some(Region { start: position, end: next, summit: middle, pileup, log10p, fold });
+                    }
+                }
+            }
+        }
+
+        while starts.peek() == Some(&next) {
+            starts.next();
+            pileup += 1;
+        }
+        while ends.peek() == Some(&next) {
+            ends.next();
+            pileup -= 1;
+        }
+        position = next;
+    }
+    if let Some(region) = current.take() {
+        close(region, &mut regions);
+    }
+
+    regions
+}
+
+/// Write peaks in ENCODE narrowPeak format, without q-values
+fn write_narrowpeak(path: &Path, peaks: &[Peak], compression: &OutputCompression) -> io::Result<()> {
+    let mut writer = compression.create(path)?;
+    for peak in peaks {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t.\t{:.5}\t{:.5}\t-1\t{}",
+            peak.chrom,
+            peak.start,
+            peak.end,
+            peak.name,
+            ((peak.pvalue * 10.0) as u32).min(1000),
+            peak.signal,
+            peak.pvalue,
+            peak.summit - peak.start,
+        )?;
+    }
+    writer.finish()
+}
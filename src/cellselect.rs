@@ -1,21 +1,20 @@
 use std::io;
 use std::error::Error;
 use std::path::Path;
-use std::fs::File;
-use std::io::BufReader;
 use std::io::BufRead;
 use std::io::Write;
 use std::thread;
 use std::sync::mpsc;
-use flate2::read::MultiGzDecoder;
 use rustc_hash::FxHashMap;
 use log::info;
+use crate::chroms::{ChromCache, ChromFilter};
+use crate::fragments::{detect_format, FragmentFormat, FragmentStream};
+use crate::input::{input_path, open_input};
+use crate::output::OutputCompression;
 
 pub fn cellselect(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
 
-    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap())
-        .canonicalize()
-        .expect("Can't find path to input fragment file");
+    let frag_file = input_path(matches.get_one::<String>("fragments").unwrap());
     info!("Received fragment file: {:?}", frag_file);
 
     let output_file = matches.get_one::<String>("outfile").unwrap();
@@ -31,17 +30,21 @@ pub fn cellselect(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
         });
     info!("Cell count cutoff: {:?}", threshold);
 
-    let bc_count = count_barcodes(&frag_file)?;
+    let chrom_filter = ChromFilter::from_matches(matches)?;
+    let compression = OutputCompression::from_matches(matches, 1)?;
+
+    let bc_count = count_barcodes(&frag_file, &chrom_filter)?;
     let selected = select_barcodes(&bc_count, &threshold)?;
 
     // Output results to the specified file
-    let mut writer = File::create(output_file)?;
+    let mut writer = compression.create(Path::new(output_file))?;
     let mut output = String::new();
 
     for (barcode, count) in &bc_count {
         output.push_str(&format!("{}\t{}\n", barcode, count));
     }
     writer.write_all(output.as_bytes())?;
+    writer.finish()?;
 
     // print selected cells to stdout
     for cell in selected {
@@ -67,18 +70,34 @@ fn select_barcodes(
     Ok(filtered_cells)
 }
 
-fn count_barcodes(frag_file: &Path,) -> io::Result<FxHashMap<String, usize>> {
+fn count_barcodes(
+    frag_file: &Path,
+    chrom_filter: &ChromFilter,
+) -> io::Result<FxHashMap<String, usize>> {
 
     // hashmap for cell barcode counts
     let mut cells: FxHashMap<String, usize> = FxHashMap::default();
 
+    if detect_format(frag_file)? != FragmentFormat::Text {
+        let mut fragments = FragmentStream::open(frag_file, chrom_filter, None)?;
+        while let Some(fragment) = fragments.next_fragment()? {
+            match cells.get_mut(fragment.barcode.as_ref()) {
+                Some(count) => *count += 1,
+                None => {
+                    cells.insert(fragment.barcode.into_owned(), 1);
+                }
+            }
+        }
+        return Ok(cells);
+    }
+
     // Create a channel for communication between the decompression and processing threads
     let (tx, rx) = mpsc::sync_channel(500);
 
     // Spawn the decompression thread
     let frag_file = frag_file.to_path_buf();
     let decompress_handle = thread::spawn(move || {
-        let reader = BufReader::new(MultiGzDecoder::new(File::open(frag_file).expect("Failed to open fragment file")));
+        let reader = open_input(&frag_file).expect("Failed to open fragment file");
         for line in reader.lines() {
             let line = line.expect("Failed to read line");
             if tx.send(line).is_err() {
@@ -90,6 +109,7 @@ fn count_barcodes(frag_file: &Path,) -> io::Result<FxHashMap<String, usize>> {
     // Progress counter
     let mut line_count: u64 = 0;
     let update_interval = 1_000_000;
+    let mut chrom_cache = ChromCache::default();
 
     for line in rx {
 
@@ -107,6 +127,11 @@ fn count_barcodes(frag_file: &Path,) -> io::Result<FxHashMap<String, usize>> {
         // parse bed entry
         let fields: Vec<&str> = line.split('\t').collect();
 
+        // skip fragments on excluded chromosomes
+        if chrom_cache.resolve(chrom_filter, fields[0]).is_none() {
+            continue;
+        }
+
         // update count for cell barcode
         if let Some(cell_barcode) = fields.get(3) {
             let cell_barcode = cell_barcode.to_string();
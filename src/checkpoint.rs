@@ -0,0 +1,332 @@
+use std::{
+    io,
+    fs,
+    path::{Path, PathBuf},
+    fs::File,
+    io::BufReader,
+    io::BufWriter,
+    io::Read,
+    io::Write,
+    hash::{Hash, Hasher},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+use log::{info, warn};
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+use crate::bgzf::is_bgzf;
+use crate::f2m::FeatureCounts;
+use crate::input::is_stdin;
+
+const MAGIC: &[u8; 8] = b"FTKCKPT2";
+pub const CHECKPOINT_FILE: &str = "checkpoint.bin";
+
+// fragments read between checks of the elapsed time
+const CHECK_EVERY: u64 = 1_000_000;
+
+/// Periodically saves the partial counts of a `matrix` run together with
+/// the BGZF virtual offset of the next unread fragment, so that an
+/// interrupted run can continue from the last checkpoint.
+///
+/// The checkpoint records the size and modification time of the fragment
+/// file, the number of features and cells, and a fingerprint of the cells,
+/// features and counting options, and is only resumed if they are unchanged.
+pub struct Checkpointer {
+    path: PathBuf,
+    interval: Duration,
+    last_saved: Instant,
+    fragments_since_check: u64,
+    source: [u64; 2],
+    fingerprint: RunFingerprint,
+    /// virtual offset to start reading the fragment file from
+    pub resume_offset: u64,
+}
+
+/// Hashes of the inputs and options that determine the counts
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RunFingerprint {
+    pub cells: u64,
+    pub features: u64,
+    pub options: u64,
+}
+
+/// Hash a value, e.g. a list of cell barcodes in index order
+pub fn hash_value<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = FxHasher::default();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Add the contents of a file to `hasher`
+pub fn hash_file(path: &Path, hasher: &mut FxHasher) -> io::Result<()> {
+    let mut reader = BufReader::with_capacity(1024 * 1024, File::open(path)?);
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let bytes = reader.read(&mut buffer)?;
+        if bytes == 0 {
+            return Ok(());
+        }
+        hasher.write(&buffer[..bytes]);
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn mismatch(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Can't resume from checkpoint: {}. Remove {} to start again", message, CHECKPOINT_FILE),
+    )
+}
+
+// type of counts, stored in the checkpoint header
+fn counts_kind(counts: &FeatureCounts) -> u64 {
+    match counts {
+        FeatureCounts::Counts(_) => 0,
+        FeatureCounts::Binary(_) => 1,
+        FeatureCounts::Weighted(_) => 2,
+    }
+}
+
+impl Checkpointer {
+    pub fn new(output: &Path, frag_file: &Path, interval: Duration, fingerprint: RunFingerprint) -> io::Result<Self> {
+        if is_stdin(frag_file) || !is_bgzf(frag_file)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Checkpoints need a bgzip-compressed fragment file",
+            ));
+        }
+        let metadata = fs::metadata(frag_file)?;
+        let modified = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0);
+        Ok(Checkpointer {
+            path: output.join(CHECKPOINT_FILE),
+            interval,
+            last_saved: Instant::now(),
+            fragments_since_check: 0,
+            source: [metadata.len(), modified],
+            fingerprint,
+            resume_offset: 0,
+        })
+    }
+
+    /// Load the last checkpoint into `counts`, which must be empty, and set
+    /// `resume_offset`. Starts from the beginning if there is no checkpoint.
+    pub fn resume(&mut self, counts: &mut FeatureCounts, num_cells: usize) -> io::Result<()> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                warn!("No checkpoint found in the output directory, starting from the beginning");
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let mut reader = BufReader::with_capacity(1024 * 1024, file);
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(mismatch("not a checkpoint file from this version of fragtk"));
+        }
+        if [read_u64(&mut reader)?, read_u64(&mut reader)?] != self.source {
+            return Err(mismatch("the fragment file has changed"));
+        }
+        let fingerprint = RunFingerprint {
+            cells: read_u64(&mut reader)?,
+            features: read_u64(&mut reader)?,
+            options: read_u64(&mut reader)?,
+        };
+        if fingerprint.cells != self.fingerprint.cells {
+            return Err(mismatch("the cells differ from the checkpointed run"));
+        }
+        if fingerprint.features != self.fingerprint.features {
+            return Err(mismatch("the BED file differs from the checkpointed run"));
+        }
+        if fingerprint.options != self.fingerprint.options {
+            return Err(mismatch("the counting options differ from the checkpointed run"));
+        }
+        let kind = read_u64(&mut reader)?;
+        let num_features = read_u64(&mut reader)? as usize;
+        let saved_cells = read_u64(&mut reader)? as usize;
+        if kind != counts_kind(counts) {
+            return Err(mismatch("--binarize or --weights differs from the checkpointed run"));
+        }
+        if num_features != counts.len() || saved_cells != num_cells {
+            return Err(mismatch("the features or cells differ from the checkpointed run"));
+        }
+        let resume_offset = read_u64(&mut reader)?;
+
+        match counts {
+            FeatureCounts::Counts(counts) => {
+                for feature in counts.iter_mut() {
+                    let entries = read_u32(&mut reader)? as usize;
+                    let mut cells: FxHashMap<u32, u32> = FxHashMap::default();
+                    cells.reserve(entries);
+                    for _ in 0..entries {
+                        cells.insert(read_u32(&mut reader)?, read_u32(&mut reader)?);
+                    }
+                    *feature = cells;
+                }
+            }
+            FeatureCounts::Binary(counts) => {
+                for feature in counts.iter_mut() {
+                    let entries = read_u32(&mut reader)? as usize;
+                    let mut cells: FxHashSet<u32> = FxHashSet::default();
+                    cells.reserve(entries);
+                    for _ in 0..entries {
+                        cells.insert(read_u32(&mut reader)?);
+                    }
+                    *feature = cells;
+                }
+            }
+            FeatureCounts::Weighted(counts) => {
+                for feature in counts.iter_mut() {
+                    let entries = read_u32(&mut reader)? as usize;
+                    let mut cells: FxHashMap<u32, f32> = FxHashMap::default();
+                    cells.reserve(entries);
+                    for _ in 0..entries {
+                        cells.insert(read_u32(&mut reader)?, f32::from_bits(read_u32(&mut reader)?));
+                    }
+                    *feature = cells;
+                }
+            }
+        }
+
+        self.resume_offset = resume_offset;
+        info!("Resuming from checkpoint at virtual offset {}", resume_offset);
+        Ok(())
+    }
+
+    /// Whether the checkpoint interval has passed. Called once per fragment.
+    pub fn due(&mut self) -> bool {
+        self.fragments_since_check += 1;
+        if self.fragments_since_check < CHECK_EVERY {
+            return false;
+        }
+        self.fragments_since_check = 0;
+        self.last_saved.elapsed() >= self.interval
+    }
+
+    /// Save `counts` and the virtual offset of the next unread fragment.
+    /// The checkpoint is written to a temporary file and then renamed, so
+    /// an interruption while saving leaves the previous checkpoint intact.
+    pub fn save(&mut self, virtual_offset: u64, counts: &FeatureCounts, num_cells: usize) -> io::Result<()> {
+        let mut temp_path = self.path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        let mut writer = BufWriter::with_capacity(1024 * 1024, File::create(&temp_path)?);
+        writer.write_all(MAGIC)?;
+        for value in [
+            self.source[0],
+            self.source[1],
+            self.fingerprint.cells,
+            self.fingerprint.features,
+            self.fingerprint.options,
+            counts_kind(counts),
+            counts.len() as u64,
+            num_cells as u64,
+            virtual_offset,
+        ] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        match counts {
+            FeatureCounts::Counts(counts) => {
+                for cells in counts.iter() {
+                    writer.write_all(&(cells.len() as u32).to_le_bytes())?;
+                    for (&cell, &count) in cells.iter() {
+                        writer.write_all(&cell.to_le_bytes())?;
+                        writer.write_all(&count.to_le_bytes())?;
+                    }
+                }
+            }
+            FeatureCounts::Binary(counts) => {
+                for cells in counts.iter() {
+                    writer.write_all(&(cells.len() as u32).to_le_bytes())?;
+                    for &cell in cells.iter() {
+                        writer.write_all(&cell.to_le_bytes())?;
+                    }
+                }
+            }
+            FeatureCounts::Weighted(counts) => {
+                for cells in counts.iter() {
+                    writer.write_all(&(cells.len() as u32).to_le_bytes())?;
+                    for (&cell, &weight) in cells.iter() {
+                        writer.write_all(&cell.to_le_bytes())?;
+                        writer.write_all(&weight.to_bits().to_le_bytes())?;
+                    }
+                }
+            }
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+
+        info!("Saved checkpoint at virtual offset {}", virtual_offset);
+        self.last_saved = Instant::now();
+        Ok(())
+    }
+
+    /// Remove the checkpoint once the matrix has been written
+    pub fn finish(self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use crate::bgzf::BgzfWriter;
+    use crate::testing::TestDir;
+
+    #[test]
+    fn resume_requires_the_same_fingerprint() {
+        let test_dir = TestDir::new("checkpoint");
+        let dir = test_dir.path();
+        let frag_file = dir.join("fragments.tsv.gz");
+        let mut writer = BgzfWriter::create(&frag_file, Compression::default(), 1).unwrap();
+        writer.write_all(b"chr1\t100\t200\tA\t1\n").unwrap();
+        writer.finish().unwrap();
+
+        let fingerprint = RunFingerprint { cells: 1, features: 2, options: 3 };
+        let mut counts = FeatureCounts::new(2, false);
+        if let FeatureCounts::Counts(features) = &mut counts {
+            features[1].insert(0, 4);
+        }
+        let mut checkpoint = Checkpointer::new(dir, &frag_file, Duration::from_secs(60), fingerprint).unwrap();
+        checkpoint.save(42, &counts, 1).unwrap();
+
+        for changed in [
+            RunFingerprint { cells: 0, ..fingerprint },
+            RunFingerprint { features: 0, ..fingerprint },
+            RunFingerprint { options: 0, ..fingerprint },
+        ] {
+            let mut checkpoint = Checkpointer::new(dir, &frag_file, Duration::from_secs(60), changed).unwrap();
+            let error = checkpoint.resume(&mut FeatureCounts::new(2, false), 1).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+
+        let mut checkpoint = Checkpointer::new(dir, &frag_file, Duration::from_secs(60), fingerprint).unwrap();
+        let mut resumed = FeatureCounts::new(2, false);
+        checkpoint.resume(&mut resumed, 1).unwrap();
+        assert_eq!(checkpoint.resume_offset, 42);
+        match resumed {
+            FeatureCounts::Counts(features) => assert_eq!(features[1].get(&0), Some(&4)),
+            _ => panic!("expected integer counts"),
+        }
+        checkpoint.finish().unwrap();
+    }
+}
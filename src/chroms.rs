@@ -0,0 +1,135 @@
+use std::{
+    io,
+    path::Path,
+    io::BufRead,
+};
+use log::{info, warn};
+use rustc_hash::FxHashMap;
+use crate::input::open_input;
+
+/// Chromosome renaming and selection applied to fragments as they are read.
+/// Names are first translated using the chromosome map, then the keep and
+/// drop patterns are matched against the translated name.
+#[derive(Default)]
+pub struct ChromFilter {
+    rename: FxHashMap<String, String>,
+    keep: Vec<String>,
+    drop: Vec<String>,
+}
+
+impl ChromFilter {
+    pub fn from_matches(matches: &clap::ArgMatches) -> io::Result<Self> {
+        let mut filter = ChromFilter::default();
+
+        if let Some(map_file) = matches.get_one::<String>("chrom-map") {
+            info!("Received chromosome map: {:?}", map_file);
+            filter.rename = load_chrom_map(Path::new(map_file))?;
+        }
+        if let Some(patterns) = matches.get_many::<String>("keep-chroms") {
+            filter.keep = patterns.cloned().collect();
+            info!("Keeping chromosomes matching: {:?}", filter.keep);
+        }
+        if let Some(patterns) = matches.get_many::<String>("drop-chroms") {
+            filter.drop = patterns.cloned().collect();
+            info!("Dropping chromosomes matching: {:?}", filter.drop);
+        }
+
+        Ok(filter)
+    }
+
+    /// Returns the (possibly renamed) chromosome name, or None if the
+    /// chromosome is excluded
+    pub fn resolve<'a>(&'a self, chrom: &'a str) -> Option<&'a str> {
+        let name = self.rename.get(chrom).map(String::as_str).unwrap_or(chrom);
+        if !self.keep.is_empty() && !self.keep.iter().any(|p| pattern_matches(p, name)) {
+            return None;
+        }
+        if self.drop.iter().any(|p| pattern_matches(p, name)) {
+            return None;
+        }
+        Some(name)
+    }
+}
+
+/// Caches the result of the last lookup, as fragment files are sorted
+/// and the chromosome only changes rarely
+#[derive(Default)]
+pub struct ChromCache {
+    raw: Option<String>,
+    resolved: Option<String>,
+}
+
+impl ChromCache {
+    pub fn resolve(&mut self, filter: &ChromFilter, chrom: &str) -> Option<&str> {
+        if self.raw.as_deref() != Some(chrom) {
+            self.resolved = filter.resolve(chrom).map(str::to_string);
+            self.raw = Some(chrom.to_string());
+        }
+        self.resolved.as_deref()
+    }
+}
+
+fn load_chrom_map(path: &Path) -> io::Result<FxHashMap<String, String>> {
+    let reader = open_input(path)?;
+    let mut rename: FxHashMap<String, String> = FxHashMap::default();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(from), Some(to)) => {
+                rename.insert(from.to_string(), to.to_string());
+            }
+            _ => warn!("Line {}: Chromosome map requires two columns", index + 1),
+        }
+    }
+
+    Ok(rename)
+}
+
+/// Read chromosome sizes from a two-column file, e.g. a FASTA index
+pub fn load_chrom_sizes(path: &Path) -> io::Result<FxHashMap<String, u32>> {
+    let reader = open_input(path)?;
+    let mut sizes: FxHashMap<String, u32> = FxHashMap::default();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let mut fields = line.split('\t');
+        match (fields.next(), fields.next().map(str::parse::<u32>)) {
+            (Some(chrom), Some(Ok(size))) => {
+                sizes.insert(chrom.to_string(), size);
+            }
+            _ => warn!("Line {}: Failed to parse chromosome size", index + 1),
+        }
+    }
+    Ok(sizes)
+}
+
+/// Match a chromosome name against a pattern where `*` matches any
+/// sequence of characters and `?` matches a single character
+fn pattern_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let name = name.as_bytes();
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // backtrack: let the last star consume one more character
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
@@ -0,0 +1,281 @@
+use std::{
+    io,
+    path::Path,
+    fs::File,
+    sync::Arc,
+};
+use arrow_array::{
+    cast::AsArray,
+    types::UInt32Type,
+    ArrayRef,
+    DictionaryArray,
+    RecordBatch,
+    StringArray,
+    UInt32Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use rustc_hash::FxHashMap;
+use crate::fragments::{Fragment, RecordSource};
+
+// rows held in memory before a record batch is written
+const BATCH_ROWS: usize = 1 << 20;
+
+/// Columns of a Parquet fragment file. Chromosomes and barcodes are
+/// dictionary-encoded, so each distinct string is stored once per row group.
+fn fragment_schema() -> SchemaRef {
+    let dictionary = DataType::Dictionary(Box::new(DataType::UInt32), Box::new(DataType::Utf8));
+    Arc::new(Schema::new(vec![
+        Field::new("chrom", dictionary.clone(), false),
+        Field::new("start", DataType::UInt32, false),
+        Field::new("end", DataType::UInt32, false),
+        Field::new("barcode", dictionary, false),
+        Field::new("count", DataType::UInt32, false),
+    ]))
+}
+
+/// Writes fragments to a Parquet file, starting a new row group for each chromosome
+pub struct ParquetFragmentWriter {
+    writer: ArrowWriter<File>,
+    schema: SchemaRef,
+    chrom: String,
+    starts: Vec<u32>,
+    ends: Vec<u32>,
+    counts: Vec<u32>,
+    barcode_keys: Vec<u32>,
+    barcode_values: Vec<String>,
+    barcode_index: FxHashMap<String, u32>,
+}
+
+impl ParquetFragmentWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let schema = fragment_schema();
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::default()))
+            .build();
+        let writer = ArrowWriter::try_new(File::create(path)?, schema.clone(), Some(properties))
+            .map_err(io::Error::other)?;
+        Ok(ParquetFragmentWriter {
+            writer,
+            schema,
+            chrom: String::new(),
+            starts: Vec::new(),
+            ends: Vec::new(),
+            counts: Vec::new(),
+            barcode_keys: Vec::new(),
+            barcode_values: Vec::new(),
+            barcode_index: FxHashMap::default(),
+        })
+    }
+
+    pub fn write(&mut self, fragment: &Fragment) -> io::Result<()> {
+        if fragment.chrom != self.chrom {
+            self.write_batch()?;
+            // end the row group so that each holds a single chromosome
+            self.writer.flush().map_err(io::Error::other)?;
+            self.chrom = fragment.chrom.to_string();
+        } else if self.starts.len() >= BATCH_ROWS {
+            self.write_batch()?;
+        }
+
+        let barcode = match self.barcode_index.get(fragment.barcode.as_ref()) {
+            Some(&key) => key,
+            None => {
+                let key = self.barcode_values.len() as u32;
+                self.barcode_values.push(fragment.barcode.to_string());
+                self.barcode_index.insert(fragment.barcode.to_string(), key);
+                key
+            }
+        };
+        self.starts.push(fragment.start);
+        self.ends.push(fragment.end);
+        self.counts.push(fragment.count);
+        self.barcode_keys.push(barcode);
+        Ok(())
+    }
+
+    fn write_batch(&mut self) -> io::Result<()> {
+        if self.starts.is_empty() {
+            return Ok(());
+        }
+        let rows = self.starts.len();
+        let chrom = DictionaryArray::<UInt32Type>::try_new(
+            UInt32Array::from(vec![0; rows]),
+            Arc::new(StringArray::from(vec![self.chrom.as_str()])),
+        ).map_err(io::Error::other)?;
+        let barcode = DictionaryArray::<UInt32Type>::try_new(
+            UInt32Array::from(std::mem::take(&mut self.barcode_keys)),
+            Arc::new(StringArray::from(std::mem::take(&mut self.barcode_values))),
+        ).map_err(io::Error::other)?;
+        self.barcode_index.clear();
+
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(chrom),
+            Arc::new(UInt32Array::from(std::mem::take(&mut self.starts))),
+            Arc::new(UInt32Array::from(std::mem::take(&mut self.ends))),
+            Arc::new(barcode),
+            Arc::new(UInt32Array::from(std::mem::take(&mut self.counts))),
+        ];
+        let batch = RecordBatch::try_new(self.schema.clone(), columns).map_err(io::Error::other)?;
+        self.writer.write(&batch).map_err(io::Error::other)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.write_batch()?;
+        self.writer.close().map_err(io::Error::other)?;
+        Ok(())
+    }
+}
+
+/// Reads fragments from a Parquet file written by `ParquetFragmentWriter`,
+/// one record batch at a time
+pub struct ParquetFragments {
+    reader: ParquetRecordBatchReader,
+    chrom_keys: UInt32Array,
+    chrom_values: StringArray,
+    starts: UInt32Array,
+    ends: UInt32Array,
+    barcode_keys: UInt32Array,
+    barcode_values: StringArray,
+    counts: UInt32Array,
+    row: usize,
+}
+
+impl ParquetFragments {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)
+            .map_err(io::Error::other)?;
+        if builder.schema().fields() != fragment_schema().fields() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} is not a fragtk Parquet fragment file", path),
+            ));
+        }
+        let reader = builder.with_batch_size(BATCH_ROWS).build().map_err(io::Error::other)?;
+        Ok(ParquetFragments {
+            reader,
+            chrom_keys: UInt32Array::from(Vec::<u32>::new()),
+            chrom_values: StringArray::from(Vec::<&str>::new()),
+            starts: UInt32Array::from(Vec::<u32>::new()),
+            ends: UInt32Array::from(Vec::<u32>::new()),
+            barcode_keys: UInt32Array::from(Vec::<u32>::new()),
+            barcode_values: StringArray::from(Vec::<&str>::new()),
+            counts: UInt32Array::from(Vec::<u32>::new()),
+            row: 0,
+        })
+    }
+}
+
+impl RecordSource for ParquetFragments {
+    /// Move to the next row, reading the next batch when needed
+    fn advance(&mut self) -> io::Result<bool> {
+        self.row += 1;
+        while self.row >= self.starts.len() {
+            let batch = match self.reader.next() {
+                Some(batch) => batch.map_err(io::Error::other)?,
+                None => return Ok(false),
+            };
+            let chrom = batch.column(0).as_dictionary::<UInt32Type>();
+            let barcode = batch.column(3).as_dictionary::<UInt32Type>();
+            self.chrom_keys = chrom.keys().clone();
+            self.chrom_values = chrom.values().as_string::<i32>().clone();
+            self.starts = batch.column(1).as_primitive::<UInt32Type>().clone();
+            self.ends = batch.column(2).as_primitive::<UInt32Type>().clone();
+            self.barcode_keys = barcode.keys().clone();
+            self.barcode_values = barcode.values().as_string::<i32>().clone();
+            self.counts = batch.column(4).as_primitive::<UInt32Type>().clone();
+            self.row = 0;
+        }
+        Ok(true)
+    }
+
+    fn chrom(&self) -> &str {
+        self.chrom_values.value(self.chrom_keys.value(self.row) as usize)
+    }
+
+    fn barcode(&self) -> &str {
+        self.barcode_values.value(self.barcode_keys.value(self.row) as usize)
+    }
+
+    fn start(&self) -> u32 {
+        self.starts.value(self.row)
+    }
+
+    fn end(&self) -> u32 {
+        self.ends.value(self.row)
+    }
+
+    fn count(&self) -> u32 {
+        self.counts.value(self.row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+    use crate::chroms::ChromFilter;
+    use crate::fragments::FragmentStream;
+    use crate::testing::TestDir;
+
+    #[test]
+    fn round_trip_with_a_row_group_per_chromosome() {
+        let dir = TestDir::new("parquet-round-trip");
+        let path = dir.join("fragments.parquet");
+        let input = [
+            ("chr1", 100, 200, "AAAC-1", 1),
+            ("chr1", 150, 300, "GGTA-1", 3),
+            ("chr1", 400, 450, "AAAC-1", 1),
+            ("chr2", 10, 90, "GGTA-1", 2),
+        ];
+        let mut writer = ParquetFragmentWriter::create(&path).unwrap();
+        for &(chrom, start, end, barcode, count) in input.iter() {
+            writer.write(&Fragment { chrom, start, end, barcode: Cow::Borrowed(barcode), count }).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let metadata = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap().metadata().clone();
+        let rows: Vec<i64> = metadata.row_groups().iter().map(|group| group.num_rows()).collect();
+        assert_eq!(rows, [3, 1]);
+
+        let chrom_filter = ChromFilter::default();
+        let mut stream = FragmentStream::open(&path, &chrom_filter, None).unwrap();
+        let mut output = Vec::new();
+        while let Some(fragment) = stream.next_fragment().unwrap() {
+            output.push((fragment.chrom.to_string(), fragment.start, fragment.end, fragment.barcode.into_owned(), fragment.count));
+        }
+        let expected: Vec<(String, u32, u32, String, u32)> = input.iter()
+            .map(|&(chrom, start, end, barcode, count)| (chrom.to_string(), start, end, barcode.to_string(), count))
+            .collect();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn other_parquet_files_are_rejected() {
+        let dir = TestDir::new("parquet-schema");
+        let path = dir.join("other.parquet");
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("chrom", DataType::Utf8, false),
+            Field::new("start", DataType::UInt32, false),
+        ]));
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(vec!["chr1"])),
+            Arc::new(UInt32Array::from(vec![100])),
+        ];
+        let mut writer = ArrowWriter::try_new(File::create(&path).unwrap(), schema.clone(), None).unwrap();
+        writer.write(&RecordBatch::try_new(schema, columns).unwrap()).unwrap();
+        writer.close().unwrap();
+
+        let chrom_filter = ChromFilter::default();
+        match FragmentStream::open(&path, &chrom_filter, None) {
+            Err(error) => {
+                assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+                assert!(error.to_string().contains("is not a fragtk Parquet fragment file"));
+            }
+            Ok(_) => panic!("expected a schema error"),
+        }
+    }
+}
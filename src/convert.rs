@@ -0,0 +1,116 @@
+use std::{
+    io,
+    path::Path,
+    error::Error,
+    io::Write,
+};
+use log::info;
+use crate::barcodes::BarcodeMap;
+use crate::chroms::ChromFilter;
+use crate::columnar::ParquetFragmentWriter;
+use crate::fragfile::FragFileWriter;
+use crate::fragments::FragmentStream;
+use crate::input::input_path;
+use crate::output::OutputCompression;
+
+pub fn convert(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = input_path(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+
+    let format = match matches.get_one::<String>("to").unwrap().as_str() {
+        "insertions" => Format::Insertions,
+        "bedpe" => Format::Bedpe,
+        "parquet" => Format::Parquet,
+        "frag" => Format::Frag,
+        _ => Format::Bed6,
+    };
+    let chrom_filter = ChromFilter::from_matches(matches)?;
+    let barcode_map = BarcodeMap::from_matches(matches)?;
+
+    let mut fragments = FragmentStream::open(&frag_file, &chrom_filter, barcode_map.as_ref())?;
+
+    if let Format::Parquet = format {
+        let output = match matches.get_one::<String>("output") {
+            Some(output) => Path::new(output),
+            None => return Err("--output is required for Parquet output".into()),
+        };
+        info!("Writing Parquet fragment file: {:?}", output);
+        let mut writer = ParquetFragmentWriter::create(output)?;
+        while let Some(fragment) = fragments.next_fragment()? {
+            writer.write(&fragment)?;
+        }
+        writer.finish()?;
+        return Ok(());
+    }
+
+    if let Format::Frag = format {
+        let output = match matches.get_one::<String>("output") {
+            Some(output) => Path::new(output),
+            None => return Err("--output is required for .frag output".into()),
+        };
+        info!("Writing .frag fragment file: {:?}", output);
+        let mut writer = FragFileWriter::create(output)?;
+        while let Some(fragment) = fragments.next_fragment()? {
+            writer.write(&fragment)?;
+        }
+        writer.finish()?;
+        return Ok(());
+    }
+
+    let compression = OutputCompression::from_matches(matches, *matches.get_one::<usize>("threads").unwrap())?;
+    let mut writer = compression.wrap(io::stdout())?;
+    convert_fragments(fragments, &mut writer, format)?;
+    writer.finish()?;
+
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    /// one 1 bp interval per Tn5 insertion, stranded by fragment end
+    Insertions,
+    /// the two insertion sites of each fragment as a BEDPE pair
+    Bedpe,
+    /// the whole fragment as a BED6 interval
+    Bed6,
+    /// columnar fragment file, see `columnar::ParquetFragmentWriter`
+    Parquet,
+    /// binary fragment file with a block index, see `fragfile::FragFileWriter`
+    Frag,
+}
+
+/// Write each fragment in `format`, with the barcode as the name and the
+/// read count as the score
+fn convert_fragments<W: Write>(mut fragments: FragmentStream, writer: &mut W, format: Format) -> io::Result<()> {
+    while let Some(fragment) = fragments.next_fragment()? {
+        let chrom = fragment.chrom;
+        let barcode = fragment.barcode.as_ref();
+        let count = fragment.count;
+        match format {
+            Format::Insertions => {
+                let [left, right] = fragment.insertions();
+                writeln!(writer, "{}\t{}\t{}\t{}\t{}\t+", chrom, left, left + 1, barcode, count)?;
+                writeln!(writer, "{}\t{}\t{}\t{}\t{}\t-", chrom, right, right + 1, barcode, count)?;
+            }
+            Format::Bedpe => {
+                let [left, right] = fragment.insertions();
+                writeln!(
+                    writer,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t+\t-",
+                    chrom, left, left + 1, chrom, right, right + 1, barcode, count
+                )?;
+            }
+            Format::Bed6 => {
+                writeln!(writer, "{}\t{}\t{}\t{}\t{}\t.", chrom, fragment.start, fragment.end, barcode, count)?;
+            }
+            Format::Parquet | Format::Frag => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Binary fragment formats are written by their own writer, not as text",
+                ));
+            }
+        }
+    }
+    Ok(())
+}
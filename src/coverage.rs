@@ -0,0 +1,341 @@
+use std::{
+    io,
+    fs,
+    path::Path,
+    error::Error,
+    io::Write,
+    collections::VecDeque,
+};
+use log::{info, warn};
+use rustc_hash::FxHashMap;
+use crate::barcodes::{load_groups, BarcodeMap, CellGroups};
+use crate::bias::{BiasTable, ChromSequence};
+use crate::bigwig::BigWigWriter;
+use crate::chroms::{load_chrom_sizes, ChromFilter};
+use crate::fasta::Fasta;
+use crate::fragments::FragmentStream;
+use crate::input::{input_path, is_stdin, stdin_unsupported};
+use crate::output::{OutputCompression, OutputWriter};
+
+// number of bins the streaming position advances before finished bins are written
+const FLUSH_BINS: u32 = 4096;
+
+pub fn coverage(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = input_path(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+
+    let group_file = Path::new(matches.get_one::<String>("groups").unwrap());
+    info!("Received group file: {:?}", group_file);
+
+    let output_path = Path::new(matches.get_one::<String>("outdir").unwrap());
+    info!("Received output directory: {:?}", output_path);
+    fs::create_dir_all(output_path)?;
+
+    let bin_size = *matches.get_one::<u32>("bin").unwrap();
+    if bin_size == 0 {
+        return Err("Bin size must be greater than zero".into());
+    }
+
+    let options = CoverageOptions {
+        bin_size,
+        normalize: matches.get_one::<String>("normalize").unwrap() == "cpm",
+        insertions: matches.get_one::<String>("mode").unwrap() == "insertions",
+        bigwig: matches.get_one::<String>("format").unwrap() == "bigwig",
+        compression: OutputCompression::from_matches(matches, *matches.get_one::<usize>("threads").unwrap())?,
+    };
+    info!(
+        "Bin size: {}, normalize: {}, insertions: {}, bigWig: {}",
+        options.bin_size, options.normalize, options.insertions, options.bigwig
+    );
+
+    let chrom_sizes = match matches.get_one::<String>("genome") {
+        Some(genome) => Some(load_chrom_sizes(Path::new(genome))?),
+        None => None,
+    };
+
+    let mut correction = match matches.get_one::<String>("bias") {
+        Some(bias_file) => {
+            if !options.insertions {
+                return Err("--bias can only be used with --mode insertions".into());
+            }
+            info!("Received bias table: {:?}", bias_file);
+            let fasta_file = matches.get_one::<String>("fasta").unwrap();
+            Some(BiasCorrection {
+                table: BiasTable::read(Path::new(bias_file))?,
+                fasta: Fasta::open(Path::new(fasta_file))?,
+                sequence: ChromSequence::default(),
+            })
+        }
+        None => None,
+    };
+
+    let groups = load_groups(group_file)?;
+    let file_names = groups.file_names()?;
+    let chrom_filter = ChromFilter::from_matches(matches)?;
+    let barcode_map = BarcodeMap::from_matches(matches)?;
+
+    let stream = || FragmentStream::open(&frag_file, &chrom_filter, barcode_map.as_ref());
+
+    // first pass to get group totals for normalization, and chromosome
+    // extents if no genome file was given
+    let mut scale = vec![1.0f32; groups.names.len()];
+    let mut observed_sizes: FxHashMap<String, u32> = FxHashMap::default();
+    if options.normalize || (options.bigwig && chrom_sizes.is_none()) {
+        if is_stdin(&frag_file) {
+            return Err(stdin_unsupported("coverage with normalization or without a genome file").into());
+        }
+        info!("Counting fragments per group");
+        let mut totals = vec![0u64; groups.names.len()];
+        let mut fragments = stream()?;
+        while let Some(fragment) = fragments.next_fragment()? {
+            if let Some(&group) = groups.cells.get(fragment.barcode.as_ref()) {
+                totals[group] += 1;
+                let size = observed_sizes.entry(fragment.chrom.to_string()).or_insert(0);
+                *size = (*size).max(fragment.end);
+            }
+        }
+        if options.normalize {
+            let per_fragment = if options.insertions { 2.0 } else { 1.0 };
+            for (group, total) in totals.iter().enumerate() {
+                if *total > 0 {
+                    scale[group] = (1e6 / (*total as f64 * per_fragment)) as f32;
+                }
+            }
+        }
+    }
+    let chrom_sizes = chrom_sizes.unwrap_or_else(|| {
+        if options.bigwig {
+            warn!("No genome file provided, using the largest fragment end as the chromosome size");
+        }
+        observed_sizes
+    });
+
+    let mut tracks: Vec<GroupTrack> = Vec::with_capacity(groups.names.len());
+    for (group, base_name) in file_names.iter().enumerate() {
+        let (file_name, writer) = if options.bigwig {
+            (format!("{}.bw", base_name), TrackWriter::BigWig(Box::new(BigWigWriter::new(&chrom_sizes, bin_size))))
+        } else {
+            let file_name = options.compression.file_name(&format!("{}.bedGraph", base_name));
+            let writer = options.compression.create(&output_path.join(&file_name))?;
+            (file_name, TrackWriter::BedGraph(Box::new(writer)))
+        };
+        tracks.push(GroupTrack {
+            path: output_path.join(file_name),
+            window: VecDeque::new(),
+            first_bin: 0,
+            run: None,
+            scale: scale[group],
+            writer,
+        });
+    }
+
+    let fragments = stream()?;
+    pileup(fragments, &groups, &mut tracks, &chrom_sizes, &options, correction.as_mut())?;
+
+    for track in tracks {
+        info!("Writing coverage track: {:?}", track.path);
+        track.writer.finish(&track.path)?;
+    }
+
+    Ok(())
+}
+
+struct CoverageOptions {
+    bin_size: u32,
+    normalize: bool,
+    insertions: bool,
+    bigwig: bool,
+    // bedGraph output only
+    compression: OutputCompression,
+}
+
+/// Tn5 bias table and the reference sequence needed to look up the k-mer
+/// at each insertion
+struct BiasCorrection {
+    table: BiasTable,
+    fasta: Fasta,
+    sequence: ChromSequence,
+}
+
+impl BiasCorrection {
+    fn weight(&mut self, chrom: &str, position: u32) -> io::Result<f32> {
+        if chrom != self.sequence.chrom {
+            self.sequence.load(&mut self.fasta, chrom)?;
+        }
+        Ok(self.table.weight(self.sequence.kmer_at(position, self.table.k)) as f32)
+    }
+}
+
+enum TrackWriter {
+    BigWig(Box<BigWigWriter>),
+    BedGraph(Box<OutputWriter>),
+}
+
+impl TrackWriter {
+    fn write(&mut self, chrom: &str, start: u32, end: u32, value: f32) -> io::Result<()> {
+        match self {
+            TrackWriter::BigWig(writer) => writer.add(chrom, start, end, value),
+            TrackWriter::BedGraph(writer) => writeln!(writer, "{}\t{}\t{}\t{}", chrom, start, end, value),
+        }
+    }
+
+    fn finish(self, path: &Path) -> io::Result<()> {
+        match self {
+            TrackWriter::BigWig(writer) => writer.finish(path),
+            TrackWriter::BedGraph(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Streaming coverage for one group. Bins from `first_bin` onwards are
+/// held in `window` until no later fragment can reach them.
+struct GroupTrack {
+    path: std::path::PathBuf,
+    window: VecDeque<f32>,
+    first_bin: u32,
+    // current run of equal-valued bins: (start bin, end bin, count)
+    run: Option<(u32, u32, f32)>,
+    scale: f32,
+    writer: TrackWriter,
+}
+
+impl GroupTrack {
+    fn add(&mut self, bin: u32, weight: f32) {
+        if self.window.is_empty() && self.run.is_none() && bin > self.first_bin {
+            self.first_bin = bin;
+        }
+        let offset = (bin - self.first_bin) as usize;
+        if offset >= self.window.len() {
+            self.window.resize(offset + 1, 0.0);
+        }
+        self.window[offset] += weight;
+    }
+
+    /// Write all bins before `bin_limit`, merging consecutive equal bins
+    fn drain_until(&mut self, chrom: &str, bin_limit: u32, bin_size: u32, chrom_size: u32) -> io::Result<()> {
+        while self.first_bin < bin_limit {
+            let count = self.window.pop_front().unwrap_or(0.0);
+            let bin = self.first_bin;
+            self.first_bin += 1;
+
+            match self.run {
+                Some((start, end, value)) if value == count && end == bin => {
+                    self.run = Some((start, bin + 1, value));
+                }
+                _ => {
+                    self.write_run(chrom, bin_size, chrom_size)?;
+                    if count > 0.0 {
+                        self.run = Some((bin, bin + 1, count));
+                    }
+                }
+            }
+            if self.window.is_empty() && self.run.is_none() {
+                // nothing pending, jump straight to the limit
+                self.first_bin = bin_limit;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_run(&mut self, chrom: &str, bin_size: u32, chrom_size: u32) -> io::Result<()> {
+        if let Some((start, end, count)) = self.run.take() {
+            let start = start.saturating_mul(bin_size);
+            let end = end.saturating_mul(bin_size).min(chrom_size);
+            if start < end {
+                self.writer.write(chrom, start, end, count * self.scale)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write all remaining bins for the chromosome and reset the window
+    fn finish_chrom(&mut self, chrom: &str, bin_size: u32, chrom_size: u32) -> io::Result<()> {
+        let last = self.first_bin + self.window.len() as u32;
+        self.drain_until(chrom, last, bin_size, chrom_size)?;
+        self.write_run(chrom, bin_size, chrom_size)?;
+        self.window.clear();
+        self.first_bin = 0;
+        Ok(())
+    }
+}
+
+fn pileup(
+    mut fragments: FragmentStream,
+    groups: &CellGroups,
+    tracks: &mut [GroupTrack],
+    chrom_sizes: &FxHashMap<String, u32>,
+    options: &CoverageOptions,
+    mut correction: Option<&mut BiasCorrection>,
+) -> io::Result<()> {
+    let bin_size = options.bin_size;
+
+    let mut current_chrom = String::new();
+    let mut current_size: u32 = u32::MAX;
+    let mut last_start: u32 = 0;
+    let mut flushed_bin: u32 = 0;
+
+    while let Some(fragment) = fragments.next_fragment()? {
+        let group = match groups.cells.get(fragment.barcode.as_ref()) {
+            Some(&group) => group,
+            None => continue,
+        };
+
+        if fragment.chrom != current_chrom {
+            for track in tracks.iter_mut() {
+                track.finish_chrom(&current_chrom, bin_size, current_size)?;
+            }
+            current_chrom = fragment.chrom.to_string();
+            current_size = match chrom_sizes.get(&current_chrom) {
+                Some(&size) => size,
+                None if options.bigwig => {
+                    warn!("Chromosome {} not found in genome file, skipping", current_chrom);
+                    0
+                }
+                None => u32::MAX,
+            };
+            last_start = 0;
+            flushed_bin = 0;
+        }
+        if current_size == 0 {
+            continue;
+        }
+        if fragment.start < last_start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Fragment file is not sorted by position",
+            ));
+        }
+        last_start = fragment.start;
+
+        // bins before the current fragment start are complete
+        let frontier = fragment.start / bin_size;
+        if frontier >= flushed_bin + FLUSH_BINS {
+            for track in tracks.iter_mut() {
+                track.drain_until(&current_chrom, frontier, bin_size, current_size)?;
+            }
+            flushed_bin = frontier;
+        }
+
+        let track = &mut tracks[group];
+        if options.insertions {
+            for position in fragment.insertions() {
+                let weight = match correction.as_deref_mut() {
+                    Some(correction) => correction.weight(fragment.chrom, position)?,
+                    None => 1.0,
+                };
+                track.add(position / bin_size, weight);
+            }
+        } else {
+            for bin in (fragment.start / bin_size)..=(fragment.end.max(fragment.start + 1) - 1) / bin_size {
+                track.add(bin, 1.0);
+            }
+        }
+    }
+
+    for track in tracks.iter_mut() {
+        track.finish_chrom(&current_chrom, bin_size, current_size)?;
+    }
+    Ok(())
+}
+
@@ -0,0 +1,233 @@
+use std::{
+    io,
+    path::Path,
+    error::Error,
+    io::Write,
+};
+use flate2::Compression;
+use log::{info, warn};
+use rustc_hash::FxHashMap;
+use crate::barcodes::BarcodeMap;
+use crate::bgzf::{BgzfWriter, BLOCK_SIZE};
+use crate::chroms::ChromFilter;
+use crate::fragments::FragmentStream;
+use crate::input::input_path;
+use crate::output::deflate_level;
+use crate::tabix::TabixBuilder;
+
+pub fn dedup(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = input_path(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+
+    let output = Path::new(matches.get_one::<String>("output").unwrap());
+    info!("Output file: {:?}", output);
+
+    let max_barcodes = matches.get_one::<usize>("max-barcodes").copied();
+    if max_barcodes == Some(0) {
+        return Err("--max-barcodes must be greater than zero".into());
+    }
+    let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let level = deflate_level(matches)?;
+    let chrom_filter = ChromFilter::from_matches(matches)?;
+    let barcode_map = BarcodeMap::from_matches(matches)?;
+
+    let options = CollapseOptions { max_barcodes, rename: None, level, num_threads };
+    let stats = collapse_fragments(&frag_file, output, &options, &chrom_filter, barcode_map.as_ref())?;
+    info!(
+        "Read {} fragments, collapsed {} duplicates, removed {} fragments shared by too many barcodes, wrote {}",
+        stats.input, stats.duplicates, stats.shared, stats.output
+    );
+
+    Ok(())
+}
+
+/// One fragment at the current start position, before duplicates are collapsed
+struct Record {
+    end: u32,
+    barcode: String,
+    count: u64,
+    // columns after the count, kept from the first record
+    rest: Option<String>,
+}
+
+/// How `collapse_fragments` treats barcodes and writes its output
+pub struct CollapseOptions<'a> {
+    /// remove fragments found in more than this many barcodes
+    pub max_barcodes: Option<usize>,
+    /// barcodes to rename after barcode correction, e.g. each multiplet
+    /// barcode to its multiplet name
+    pub rename: Option<&'a FxHashMap<String, String>>,
+    pub level: Compression,
+    pub num_threads: usize,
+}
+
+#[derive(Default)]
+pub struct DedupStats {
+    pub input: u64,
+    pub duplicates: u64,
+    pub shared: u64,
+    pub output: u64,
+}
+
+/// Write a bgzip-compressed, tabix-indexed copy of a position-sorted
+/// fragment file in which identical fragments (same position and barcode,
+/// after renaming) are collapsed into one, summing their counts
+pub fn collapse_fragments(
+    frag_file: &Path,
+    output: &Path,
+    options: &CollapseOptions,
+    chrom_filter: &ChromFilter,
+    barcode_map: Option<&BarcodeMap>,
+) -> io::Result<DedupStats> {
+    let CollapseOptions { max_barcodes, rename, level, num_threads } = *options;
+    let flush_size = BLOCK_SIZE * 16 * num_threads.max(1);
+    let mut writer = BgzfWriter::create(output, level, num_threads)?;
+    let mut index = TabixBuilder::default();
+    let mut stats = DedupStats::default();
+
+    let mut fragments = FragmentStream::open(frag_file, chrom_filter, barcode_map)?;
+    let mut line_out: Vec<u8> = Vec::with_capacity(1024);
+
+    // duplicates share a start position, so only fragments starting at the
+    // current position are held in memory
+    let mut current_chrom = String::new();
+    let mut current_start: u32 = 0;
+    let mut records: Vec<Record> = Vec::new();
+
+    let mut flush = |chrom: &str, start: u32, records: &mut Vec<Record>, stats: &mut DedupStats| -> io::Result<()> {
+        records.sort_by(|a, b| a.end.cmp(&b.end).then_with(|| a.barcode.cmp(&b.barcode)));
+        for same_end in records.chunk_by_mut(|a, b| a.end == b.end) {
+            // collapse identical fragments into the first, summing counts
+            let mut unique: Vec<&mut Record> = Vec::with_capacity(same_end.len());
+            for record in same_end.iter_mut() {
+                match unique.last_mut() {
+                    Some(last) if last.barcode == record.barcode => {
+                        last.count += record.count;
+                        stats.duplicates += 1;
+                    }
+                    _ => unique.push(record),
+                }
+            }
+            if max_barcodes.is_some_and(|max| unique.len() > max) {
+                stats.shared += unique.len() as u64;
+                continue;
+            }
+            for record in unique {
+                line_out.clear();
+                write!(line_out, "{}\t{}\t{}\t{}\t{}", chrom, start, record.end, record.barcode, record.count)?;
+                if let Some(rest) = &record.rest {
+                    write!(line_out, "\t{}", rest)?;
+                }
+                line_out.push(b'\n');
+
+                let ustart = writer.position();
+                writer.write_all(&line_out)?;
+                index.add(chrom, start, record.end, ustart, writer.position());
+                stats.output += 1;
+            }
+        }
+        records.clear();
+        if writer.buffered() >= flush_size {
+            writer.flush_blocks()?;
+        }
+        Ok(())
+    };
+
+    while let Some(fragment) = fragments.next_line()? {
+        stats.input += 1;
+        let (chrom, start) = (fragment.chrom, fragment.start);
+        let barcode = match rename.and_then(|rename| rename.get(fragment.barcode.as_ref())) {
+            Some(renamed) => renamed.clone(),
+            None => fragment.barcode.to_string(),
+        };
+
+        if chrom != current_chrom || start != current_start {
+            if chrom == current_chrom && start < current_start {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Fragment file is not sorted by position",
+                ));
+            }
+            flush(&current_chrom, current_start, &mut records, &mut stats)?;
+            if chrom != current_chrom {
+                current_chrom = chrom.to_string();
+            }
+            current_start = start;
+        }
+        records.push(Record {
+            end: fragment.end,
+            barcode,
+            count: fragment.count() as u64,
+            rest: fragment.extra().map(str::to_string),
+        });
+    }
+    flush(&current_chrom, current_start, &mut records, &mut stats)?;
+
+    let layout = writer.finish()?;
+    if index.is_unsorted() {
+        warn!("Fragments are not sorted, skipping index");
+        return Ok(stats);
+    }
+    let mut index_path = output.as_os_str().to_owned();
+    index_path.push(".tbi");
+    index.write(Path::new(&index_path), &layout)?;
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use crate::input::open_input;
+    use crate::testing::TestDir;
+
+    fn collapse(dir: &TestDir, fragments: &str, options: &CollapseOptions) -> io::Result<(String, DedupStats)> {
+        let input = dir.write("fragments.tsv", fragments);
+        let output = dir.join("collapsed.tsv.gz");
+        let stats = collapse_fragments(&input, &output, options, &ChromFilter::default(), None)?;
+        let mut text = String::new();
+        open_input(&output)?.read_to_string(&mut text)?;
+        Ok((text, stats))
+    }
+
+    fn options(max_barcodes: Option<usize>, rename: Option<&FxHashMap<String, String>>) -> CollapseOptions<'_> {
+        CollapseOptions { max_barcodes, rename, level: Compression::default(), num_threads: 1 }
+    }
+
+    #[test]
+    fn identical_fragments_are_collapsed() {
+        let dir = TestDir::new("dedup-collapse");
+        let fragments = "chr1\t100\t200\tB\t1\nchr1\t100\t200\tA\t2\nchr1\t100\t200\tA\t3\nchr1\t100\t150\tA\t1\n";
+        let (text, stats) = collapse(&dir, fragments, &options(None, None)).unwrap();
+        assert_eq!(text, "chr1\t100\t150\tA\t1\nchr1\t100\t200\tA\t5\nchr1\t100\t200\tB\t1\n");
+        assert_eq!((stats.input, stats.duplicates, stats.output), (4, 1, 3));
+    }
+
+    #[test]
+    fn renamed_barcodes_are_collapsed() {
+        let dir = TestDir::new("dedup-rename");
+        let rename: FxHashMap<String, String> = [("B".to_string(), "A".to_string())].into_iter().collect();
+        let fragments = "chr1\t100\t200\tA\t1\nchr1\t100\t200\tB\t2\nchr1\t300\t400\tB\t1\n";
+        let (text, _) = collapse(&dir, fragments, &options(None, Some(&rename))).unwrap();
+        assert_eq!(text, "chr1\t100\t200\tA\t3\nchr1\t300\t400\tA\t1\n");
+    }
+
+    #[test]
+    fn fragments_in_too_many_barcodes_are_removed() {
+        let dir = TestDir::new("dedup-shared");
+        let fragments = "chr1\t100\t200\tA\t1\nchr1\t100\t200\tB\t1\nchr1\t100\t200\tC\t1\nchr1\t300\t400\tA\t1\n";
+        let (text, stats) = collapse(&dir, fragments, &options(Some(2), None)).unwrap();
+        assert_eq!(text, "chr1\t300\t400\tA\t1\n");
+        assert_eq!(stats.shared, 3);
+    }
+
+    #[test]
+    fn unsorted_input_is_an_error() {
+        let dir = TestDir::new("dedup-unsorted");
+        let fragments = "chr1\t300\t400\tA\t1\nchr1\t100\t200\tA\t1\n";
+        let error = collapse(&dir, fragments, &options(None, None)).map(|(text, _)| text).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
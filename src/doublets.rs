@@ -0,0 +1,196 @@
+use std::{
+    io,
+    path::Path,
+    error::Error,
+    io::Write,
+};
+use log::info;
+use crate::barcodes::BarcodeMap;
+use crate::chroms::ChromFilter;
+use crate::f2m::load_cells;
+use crate::fragments::FragmentStream;
+use crate::input::input_path;
+use crate::output::OutputCompression;
+use crate::regions::{load_regions, overlaps, RegionIndex};
+use crate::stats::{benjamini_hochberg, poisson_upper_log10};
+
+// fragments from a single cell overlap at most twice at any position, one
+// from each copy of the genome
+const MIN_DEPTH: usize = 3;
+
+pub fn doublets(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = input_path(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+
+    let cell_file = Path::new(matches.get_one::<String>("cells").unwrap());
+    info!("Received cell file: {:?}", cell_file);
+
+    let output = Path::new(matches.get_one::<String>("output").unwrap());
+    let q_threshold = *matches.get_one::<f64>("q-value").unwrap();
+
+    let mut exclude: Vec<RegionIndex> = Vec::new();
+    if let Some(beds) = matches.get_many::<String>("exclude") {
+        for bed in beds {
+            info!("Removing fragments overlapping regions in {:?}", bed);
+            exclude.push(load_regions(Path::new(bed))?);
+        }
+    }
+
+    let chrom_filter = ChromFilter::from_matches(matches)?;
+    let compression = OutputCompression::from_matches(matches, 1)?;
+    let barcode_map = BarcodeMap::from_matches(matches)?;
+    let (barcodes, cells) = load_cells(cell_file)?;
+    let mut states: Vec<CellOverlaps> = (0..barcodes.len()).map(|_| CellOverlaps::default()).collect();
+
+    info!("Counting loci with more than {} overlapping fragments per cell", MIN_DEPTH - 1);
+    let mut fragments = FragmentStream::open(&frag_file, &chrom_filter, barcode_map.as_ref())?;
+    let mut current_chrom = String::new();
+    let mut last_start: u32 = 0;
+
+    while let Some(fragment) = fragments.next_fragment()? {
+        let cell = match cells.get(fragment.barcode.as_ref()) {
+            Some(&cell) => cell as usize,
+            None => continue,
+        };
+
+        if fragment.chrom != current_chrom {
+            current_chrom = fragment.chrom.to_string();
+            for state in states.iter_mut() {
+                state.reset();
+            }
+            last_start = 0;
+        }
+        if fragment.start < last_start {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Fragment file is not sorted by position",
+            )));
+        }
+        last_start = fragment.start;
+
+        if exclude.iter().any(|regions| overlaps(regions, fragment.chrom, fragment.start, fragment.end)) {
+            continue;
+        }
+        states[cell].add(fragment.start, fragment.end);
+    }
+
+    let loci: Vec<u64> = states.iter().map(|state| state.loci).collect();
+    let (lambda, p_values) = loci_p_values(&loci);
+    info!("Mean loci with more than {} overlapping fragments per cell: {:.3}", MIN_DEPTH - 1, lambda);
+    let q_values = benjamini_hochberg(&p_values);
+
+    info!("Writing doublet scores: {:?}", output);
+    let mut writer = compression.create(output)?;
+    writeln!(writer, "barcode\tfragments\tloci\tp_value\tq_value\tdoublet")?;
+    let mut doublet_count = 0;
+    for (index, barcode) in barcodes.iter().enumerate() {
+        let state = &states[index];
+        let doublet = q_values[index] < q_threshold;
+        if doublet {
+            doublet_count += 1;
+        }
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{:.6e}\t{:.6e}\t{}",
+            barcode, state.fragments, state.loci, p_values[index], q_values[index], doublet
+        )?;
+    }
+    writer.finish()?;
+    info!("Flagged {} of {} cells as doublets", doublet_count, states.len());
+
+    Ok(())
+}
+
+/// Poisson p-value of each cell's number of loci, with the mean across
+/// cells as the expected number, as in AMULET. Also returns the mean.
+fn loci_p_values(loci: &[u64]) -> (f64, Vec<f64>) {
+    let lambda = if loci.is_empty() {
+        0.0
+    } else {
+        loci.iter().map(|&count| count as f64).sum::<f64>() / loci.len() as f64
+    };
+    let p_values = loci.iter()
+        .map(|&count| 10f64.powf(-poisson_upper_log10(count, lambda)))
+        .collect();
+    (lambda, p_values)
+}
+
+/// Fragments currently open at the streaming position for one cell, and
+/// the number of distinct loci where at least `MIN_DEPTH` of them overlap
+#[derive(Default)]
+struct CellOverlaps {
+    ends: Vec<u32>,
+    // end of the last counted locus, so that a locus is counted once
+    locus_end: u32,
+    loci: u64,
+    fragments: u64,
+}
+
+impl CellOverlaps {
+    fn add(&mut self, start: u32, end: u32) {
+        self.fragments += 1;
+        self.ends.retain(|&open_end| open_end > start);
+        self.ends.push(end);
+        if self.ends.len() >= MIN_DEPTH {
+            if start >= self.locus_end {
+                self.loci += 1;
+            }
+            let overlap_end = self.ends.iter().copied().min().unwrap_or(end);
+            self.locus_end = self.locus_end.max(overlap_end);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ends.clear();
+        self.locus_end = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loci(fragments: &[(u32, u32)]) -> u64 {
+        let mut state = CellOverlaps::default();
+        for &(start, end) in fragments {
+            state.add(start, end);
+        }
+        state.loci
+    }
+
+    #[test]
+    fn loci_need_three_overlapping_fragments() {
+        assert_eq!(loci(&[(0, 100), (10, 110)]), 0);
+        assert_eq!(loci(&[(0, 100), (10, 110), (20, 120)]), 1);
+        // further fragments within the same overlap are the same locus
+        assert_eq!(loci(&[(0, 100), (10, 110), (20, 120), (30, 130), (90, 200)]), 1);
+        // a new locus starts after the end of the previous one
+        assert_eq!(loci(&[(0, 100), (10, 110), (20, 120), (150, 300), (160, 300), (170, 300)]), 2);
+        // fragments that ended before the start no longer overlap
+        assert_eq!(loci(&[(0, 10), (5, 15), (20, 30)]), 0);
+    }
+
+    #[test]
+    fn reset_forgets_open_fragments() {
+        let mut state = CellOverlaps::default();
+        state.add(1000, 1100);
+        state.add(1010, 1110);
+        state.reset();
+        state.add(0, 100);
+        assert_eq!(state.loci, 0);
+        assert_eq!(state.fragments, 3);
+    }
+
+    #[test]
+    fn p_values_fall_with_excess_loci() {
+        let (lambda, p_values) = loci_p_values(&[2, 2, 2, 10]);
+        assert_eq!(lambda, 4.0);
+        // no loci is always possible
+        assert_eq!(loci_p_values(&[0, 4]).1[0], 1.0);
+        assert!(p_values[0] > 0.5);
+        assert!(p_values[3] < 0.01);
+        assert!(p_values[3] < p_values[0]);
+        assert_eq!(loci_p_values(&[]), (0.0, Vec::new()));
+    }
+}
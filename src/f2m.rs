@@ -1,31 +1,34 @@
 use std::{
     io,
     fs,
-    path::Path,
+    path::{Path, PathBuf},
+    time::Duration,
     error::Error,
     fs::File,
-    io::BufReader,
     io::BufRead,
     io::Write,
+    hash::{Hash, Hasher},
 };
 use rust_lapper::{Interval, Lapper};
-use flate2::read::MultiGzDecoder;
-use flate2::Compression;
 use log::error;
 use log::info;
 use log::warn;
-use rustc_hash::FxHashMap;
-use gzp::{
-    deflate::Gzip,
-    ZWriter,
-    par::compress::{ParCompress, ParCompressBuilder},
-};
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+use crate::regions::{read_bed, RegionIndex};
+use crate::append::ExistingMatrix;
+use crate::checkpoint::{hash_file, hash_value, Checkpointer, RunFingerprint};
+use crate::chroms::ChromFilter;
+use crate::barcodes::BarcodeMap;
+use crate::background::{background_peaks, gc_content};
+use crate::fasta::Fasta;
+use crate::features::{BedInterval, FeatureRegistry};
+use crate::fragments::FragmentStream;
+use crate::input::{input_path, open_input};
+use crate::output::OutputCompression;
 
 pub fn f2m(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
 
-    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap())
-        .canonicalize()
-        .expect("Can't find path to input fragment file");
+    let frag_file = input_path(matches.get_one::<String>("fragments").unwrap());
     info!("Received fragment file: {:?}", frag_file);
 
     let bed_file = Path::new(matches.get_one::<String>("bed").unwrap())
@@ -44,6 +47,14 @@ pub fn f2m(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
     let group = matches.get_flag("group");
     info!("Grouping peaks: {:?}", group);
 
+    let binarize = matches.get_flag("binarize");
+    info!("Binarize counts: {:?}", binarize);
+
+    let weights = matches.get_flag("weights");
+    info!("Weighting intervals by score: {:?}", weights);
+
+    let once = matches.get_one::<String>("group-mode").is_some_and(|mode| mode == "once");
+
     let output_path = Path::new(output_directory);
 
     let num_threads = *matches.get_one::<usize>("threads").unwrap();
@@ -72,129 +83,300 @@ pub fn f2m(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
         }
     }
 
-    fcount(&frag_file, &bed_file, &cell_file, output_path, group, num_threads)?;
+    let fasta = matches.get_one::<String>("fasta").map(PathBuf::from);
+    if let Some(fasta) = &fasta {
+        info!("Received FASTA file: {:?}", fasta);
+    }
+
+    let options = MatrixOptions {
+        group,
+        once,
+        binarize,
+        weights,
+        chrom_filter: ChromFilter::from_matches(matches)?,
+        barcode_map: BarcodeMap::from_matches(matches)?,
+        fasta,
+        background_iterations: *matches.get_one::<usize>("background-peaks").unwrap(),
+        seed: *matches.get_one::<u64>("seed").unwrap(),
+        compression: OutputCompression::from_matches(matches, num_threads)?,
+        checkpoint_interval: matches.get_one::<u64>("checkpoint")
+            .map(|minutes| Duration::from_secs(minutes * 60)),
+        resume: matches.get_flag("resume"),
+        append: matches.get_one::<String>("append").map(PathBuf::from),
+        counting_options: counting_options_hash(matches)?,
+    };
+
+    fcount(&frag_file, &bed_file, &cell_file, output_path, &options)?;
     
     Ok(())
 }
 
+/// Settings controlling how fragments are counted
+struct MatrixOptions {
+    group: bool,
+    // count each group once per fragment
+    once: bool,
+    binarize: bool,
+    // weight intervals by the BED score column
+    weights: bool,
+    chrom_filter: ChromFilter,
+    barcode_map: Option<BarcodeMap>,
+    // genome sequence for GC content and background peaks
+    fasta: Option<PathBuf>,
+    background_iterations: usize,
+    seed: u64,
+    compression: OutputCompression,
+    // save partial counts this often, and whether to load the last checkpoint
+    checkpoint_interval: Option<Duration>,
+    resume: bool,
+    // existing output directory to add new cells to
+    append: Option<PathBuf>,
+    // hash of the options that change the counts, stored in checkpoints
+    counting_options: u64,
+}
+
+/// Hash the options that change which fragments are counted and how,
+/// including the contents of chromosome and barcode map files
+fn counting_options_hash(matches: &clap::ArgMatches) -> io::Result<u64> {
+    let mut hasher = FxHasher::default();
+    for flag in ["group", "binarize", "weights"] {
+        matches.get_flag(flag).hash(&mut hasher);
+    }
+    for name in ["group-mode", "keep-chroms", "drop-chroms"] {
+        let values: Vec<&String> = matches.get_many::<String>(name).map(Iterator::collect).unwrap_or_default();
+        values.hash(&mut hasher);
+    }
+    for name in ["chrom-map", "barcode-map", "atac-whitelist", "gex-whitelist"] {
+        match matches.get_one::<String>(name) {
+            Some(path) => {
+                true.hash(&mut hasher);
+                hash_file(Path::new(path), &mut hasher)?;
+            }
+            None => false.hash(&mut hasher),
+        }
+    }
+    Ok(hasher.finish())
+}
+
 fn fcount(
     frag_file: &Path,
     bed_file: &Path,
     cell_file: &Path,
     output: &Path,
-    group: bool,
-    num_threads: usize,
+    options: &MatrixOptions,
 ) -> io::Result<()> {
     info!(
         "Processing fragment file: {:?}, BED file: {:?}, Cell file: {:?}",
         frag_file, bed_file, cell_file
     );
+    let group = options.group;
+    let compression = &options.compression;
+
+    // read the existing matrix before its files can be overwritten
+    let existing = match &options.append {
+        Some(dir) => {
+            info!("Appending to existing matrix in {:?}", dir);
+            let existing = ExistingMatrix::open(dir)?;
+            existing.check_output(output, compression)?;
+            Some(existing)
+        }
+        None => None,
+    };
 
     // create BED intervals for overlaps with fragment coordinates
     // returns hashmap with each key being chromosome name
     // each value is intervals for that chromosome
-    // interval value gives the index of the feature
-    // also writes features to output directory to avoid second iteration of file
-    // write features
-    let feature_path = output.join("features.tsv.gz");
-    info!("Writing output feature file: {:?}", &feature_path);
-    let (total_peaks, mut peaks) = match peak_intervals(bed_file, group, &feature_path, num_threads) {
+    // interval value indexes interval_features, giving the feature and weight
+    let interval_features = IntervalFeatures::new(options.weights, options.once);
+    let (registry, mut peaks, interval_features) = match peak_intervals(bed_file, group, interval_features) {
         Ok(trees) => trees,
         Err(e) => {
             error!("Failed to read BED file: {}", e);
             return Err(e);
         }
     };
+    let total_peaks = registry.len();
+
+    // counts for each feature
+    let mut peak_cell_counts = if options.weights {
+        FeatureCounts::weighted(total_peaks)
+    } else {
+        FeatureCounts::new(total_peaks, options.binarize)
+    };
+
+    // when appending, features are only written with the merged matrix,
+    // once they are known to match the existing features
+    match &existing {
+        Some(existing) => existing.check_compatible(&registry, peak_cell_counts.field())?,
+        None => {
+            let feature_path = output.join(compression.file_name("features.tsv"));
+            info!("Writing output feature file: {:?}", &feature_path);
+            registry.write(&feature_path, compression)?;
+        }
+    }
 
     // create hashmap for cell barcodes
-    let cellreader = File::open(cell_file)
-        .map(BufReader::new)?;
-    
-    let mut cells: FxHashMap<String, u32> = FxHashMap::default();
-    for (index, line) in cellreader.lines().enumerate() {
-        let line = line?;
-        let index_u32 = index as u32;
-        cells.insert(line, index_u32);
+    // when appending, only cells not already in the matrix are counted
+    let (barcodes, cells) = match &existing {
+        Some(existing) => existing.new_cells(cell_file)?,
+        None => load_cells(cell_file)?,
+    };
+
+    let mut checkpoint = match options.checkpoint_interval {
+        Some(interval) => {
+            let mut features = FxHasher::default();
+            hash_file(bed_file, &mut features)?;
+            let mut barcodes: Vec<(&u32, &String)> = cells.iter().map(|(barcode, index)| (index, barcode)).collect();
+            barcodes.sort_unstable();
+            let fingerprint = RunFingerprint {
+                cells: hash_value(&barcodes),
+                features: features.finish(),
+                options: options.counting_options,
+            };
+            Some(Checkpointer::new(output, frag_file, interval, fingerprint)?)
+        }
+        None => None,
+    };
+    if options.resume {
+        if let Some(checkpoint) = checkpoint.as_mut() {
+            checkpoint.resume(&mut peak_cell_counts, cells.len())?;
+        }
     }
 
-    // vector of features
-    // each element is hashmap of cell: count
-    let mut peak_cell_counts: Vec<FxHashMap<u32, u32>> = vec![FxHashMap::<u32, u32>::default(); total_peaks];
+    count_fragments(
+        frag_file,
+        &mut peaks,
+        Some(&interval_features),
+        &mut peak_cell_counts,
+        &cells,
+        &options.chrom_filter,
+        options.barcode_map.as_ref(),
+        checkpoint.as_mut(),
+    )?;
+
+    match &existing {
+        Some(existing) => {
+            info!("Writing merged features, counts and cells to {:?}", output);
+            existing.write(output, &registry, &peak_cell_counts, &barcodes, compression)?;
+        }
+        None => {
+            // write count matrix
+            let counts_path = output.join(compression.file_name("matrix.mtx"));
+            info!("Writing output counts file: {:?}", &counts_path);
+            write_matrix_market(&counts_path, &peak_cell_counts, total_peaks, cells.len(), compression)
+                .expect("Failed to write matrix"); // features stored as rows
+
+            // write cells
+            let cell_path = output.join("barcodes.tsv");
+            info!("Writing output cells file: {:?}", &cell_path);
+            write_cells(&cell_path, &barcodes)
+                .expect("Failed to write cells");
+        }
+    }
 
-    // frag file reading
-    let frag_file = File::open(frag_file)?;
-    let mut reader = BufReader::with_capacity(1024 * 1024, MultiGzDecoder::new(frag_file));
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.finish()?;
+    }
+
+    if let Some(fasta_file) = &options.fasta {
+        let mut fasta = Fasta::open(fasta_file)?;
+        info!("Computing feature GC content");
+        let gc = gc_content(&mut fasta, &interval_features.feature_regions(&peaks), total_peaks)?;
+
+        let gc_path = output.join(compression.file_name("gc_content.tsv"));
+        info!("Writing output GC content file: {:?}", &gc_path);
+        let mut writer = compression.create(&gc_path)?;
+        for value in gc.iter() {
+            writeln!(writer, "{:.6}", value)?;
+        }
+        writer.finish()?;
+
+        info!("Selecting {} background peaks per feature", options.background_iterations);
+        let background = background_peaks(&gc, &peak_cell_counts.totals(), options.background_iterations, options.seed);
+        let background_path = output.join(compression.file_name("background_peaks.tsv"));
+        info!("Writing output background peaks file: {:?}", &background_path);
+        let mut writer = compression.create(&background_path)?;
+        let mut line = String::new();
+        for features in background.iter() {
+            line.clear();
+            for (index, feature) in features.iter().enumerate() {
+                if index > 0 {
+                    line.push('\t');
+                }
+                line.push_str(&(feature + 1).to_string()); // 1-based, as in the matrix
+            }
+            line.push('\n');
+            writer.write_all(line.as_bytes())?;
+        }
+        writer.finish()?;
+    }
 
-    let mut line_count: u64 = 0;
-    let update_interval = 1_000_000;
-    let mut line_str = String::new();
-    let mut startpos: u32;
-    let mut endpos: u32;
+    Ok(())
+}
+
+/// Add the fragment ends falling within each feature to `peak_cell_counts`,
+/// for cells in `cells`. Region values are feature indices, or index
+/// `intervals` if given. With a checkpoint, reading starts from its
+/// resume offset and the counts are saved periodically.
+#[allow(clippy::too_many_arguments)]
+pub fn count_fragments(
+    frag_file: &Path,
+    peaks: &mut RegionIndex,
+    intervals: Option<&IntervalFeatures>,
+    peak_cell_counts: &mut FeatureCounts,
+    cells: &FxHashMap<String, u32>,
+    chrom_filter: &ChromFilter,
+    barcode_map: Option<&BarcodeMap>,
+    mut checkpoint: Option<&mut Checkpointer>,
+) -> io::Result<()> {
+    let mut fragments = match &checkpoint {
+        Some(checkpoint) => FragmentStream::open_bgzf(frag_file, checkpoint.resume_offset, chrom_filter, barcode_map)?,
+        None => FragmentStream::open(frag_file, chrom_filter, barcode_map)?,
+    };
 
     let mut current_chrom = String::new();
     let mut current_lapper: Option<&mut Lapper<u32, usize>> = None;
     let mut cursor: usize = 0;
     let mut check_end: bool;
 
-    loop {
+    // fragment chromosomes without any BED regions
+    let mut missing_chroms: FxHashSet<String> = FxHashSet::default();
 
-        match reader.read_line(&mut line_str) {
-            Ok(0) => break,
-            Ok(_) => {},
-            Err(e) => {
-                error!("Error reading fragment file: {}", e);
-                return Err(e);
-            }
-        }
-        let line = &line_str[..line_str.len() - 1];
-
-        // Skip header lines that start with #
-        if line.starts_with('#') {
-            line_str.clear();
-            continue;
-        }
+    // features and weights overlapped by the current fragment
+    let mut hits: Vec<(usize, f32)> = Vec::new();
+    let resolve = |val: usize| intervals.map_or((val, 1.0), |intervals| intervals.resolve(val));
+    let once = intervals.is_some_and(|intervals| intervals.once);
 
-        line_count += 1;
-        if line_count % update_interval == 0 {
-            print!("\rProcessed {} M fragments", line_count / 1_000_000);
-            std::io::stdout().flush().expect("Can't flush output");
+    loop {
+        if let Some(checkpoint) = checkpoint.as_mut() {
+            if checkpoint.due() {
+                let offset = fragments.virtual_offset().ok_or_else(|| io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Checkpoints require a BGZF-compressed fragment file",
+                ))?;
+                checkpoint.save(offset, peak_cell_counts, cells.len())?;
+            }
         }
-
-        // Parse BED entry
-        let fields: Vec<&str> = line.split('\t').collect();
+        let fragment = match fragments.next_fragment()? {
+            Some(fragment) => fragment,
+            None => break,
+        };
 
         // Check if cell is to be included
-        let cell_barcode: &str = fields[3];
-        if let Some(&cell_index) = cells.get(cell_barcode) {
+        if let Some(&cell_index) = cells.get(fragment.barcode.as_ref()) {
             check_end = true;
 
-            // create intervals from fragment entry
-            let seqname: &str = fields[0];
-
-            if seqname != current_chrom {
-                current_chrom = seqname.to_string();
+            if fragment.chrom != current_chrom {
+                current_chrom = fragment.chrom.to_string();
                 current_lapper = peaks.get_mut(&current_chrom);
+                if current_lapper.is_none() {
+                    missing_chroms.insert(current_chrom.clone());
+                }
                 cursor = 0;
             }
 
-            // try to parse the coordinates, skip the line if parsing fails
-            startpos = match fields[1].trim().parse() {
-                Ok(num) => num,
-                Err(e) => {
-                    warn!("Failed to parse start position: {:?}. Error: {}", line_count, e);
-                    line_str.clear();
-                    continue;
-                }
-            };
-            
-            endpos = match fields[2].trim().parse() {
-                Ok(num) => num,
-                Err(e) => {
-                    warn!("Failed to parse end position: {:?}. Error: {}", line_count, e);
-                    line_str.clear();
-                    continue;
-                }
-            };
+            let startpos = fragment.start;
+            let endpos = fragment.end;
 
             if let Some(lapper) = &mut current_lapper {
                 // seems to be a problem with seek if lapper has one element
@@ -202,90 +384,209 @@ fn fcount(
                 if lapper.intervals.len() == 1 {
                     cursor = 0;
                 }
+                hits.clear();
                 for interval in lapper.seek(startpos, startpos + 1, &mut cursor) {
-                    let peak_index = interval.val;
                     let peak_end = interval.stop;
-                    *peak_cell_counts[peak_index].entry(cell_index).or_insert(0) += 1;
+                    hits.push(resolve(interval.val));
 
                     // Check if fragment end is behind peak end (if so, it overlaps and we don't need a full search)
                     if endpos < peak_end {
                         check_end = false;
-                        *peak_cell_counts[peak_index].entry(cell_index).or_insert(0) += 1;
+                        hits.push(resolve(interval.val));
                     }
                 }
                 if check_end {
                     for interval in lapper.seek(endpos, endpos + 1, &mut cursor) {
-                        let peak_index = interval.val;
-                        *peak_cell_counts[peak_index].entry(cell_index).or_insert(0) += 1;
+                        hits.push(resolve(interval.val));
                     }
                 }
+                if once {
+                    // keep the largest weight for each feature
+                    hits.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.total_cmp(&a.1)));
+                    hits.dedup_by_key(|hit| hit.0);
+                }
+                for &(peak_index, weight) in hits.iter() {
+                    peak_cell_counts.add(peak_index, cell_index, weight);
+                }
             }
         }
-        line_str.clear();
     }
-    eprintln!();
-    
-    // write count matrix
-    let counts_path = output.join("matrix.mtx.gz");
-    info!("Writing output counts file: {:?}", &counts_path);
-    write_matrix_market(&counts_path, &peak_cell_counts, total_peaks, cells.len(), num_threads)
-        .expect("Failed to write matrix"); // features stored as rows
-
-    // write cells
-    let cell_path = output.join("barcodes.tsv");
-    info!("Writing output cells file: {:?}", &cell_path);
-    write_cells(&cell_path, cell_file)
-        .expect("Failed to write cells");
+
+    if !missing_chroms.is_empty() {
+        let mut missing: Vec<String> = missing_chroms.into_iter().collect();
+        missing.sort();
+        warn!(
+            "Fragment chromosomes absent from BED file: {}. Check chromosome naming or use --chrom-map",
+            missing.join(", ")
+        );
+    }
 
     Ok(())
 }
 
-fn write_cells(
+/// Read cell barcodes, indexed by line
+/// Read cell barcodes, indexed in order of first appearance. Blank lines
+/// and repeated barcodes are skipped, so every barcode has one dense index.
+pub fn load_cells(cell_file: &Path) -> io::Result<(Vec<String>, FxHashMap<String, u32>)> {
+    let mut barcodes: Vec<String> = Vec::new();
+    let mut cells: FxHashMap<String, u32> = FxHashMap::default();
+    let mut duplicates: usize = 0;
+    for line in open_input(cell_file)?.lines() {
+        let line = line?;
+        let barcode = line.trim();
+        if barcode.is_empty() {
+            continue;
+        }
+        if cells.contains_key(barcode) {
+            duplicates += 1;
+            continue;
+        }
+        cells.insert(barcode.to_string(), barcodes.len() as u32);
+        barcodes.push(barcode.to_string());
+    }
+    if duplicates > 0 {
+        warn!("Skipped {} repeated barcodes in {:?}", duplicates, cell_file);
+    }
+    Ok((barcodes, cells))
+}
+
+/// Write cell barcodes in index order, one per line
+pub fn write_cells(
     outfile: &Path,
-    cells: &Path,
+    barcodes: &[String],
 ) -> io::Result<()> {
-    // Copy cell barcodes to output directory
-    match fs::copy(&cells, &outfile) {
-        Ok(bytes_copied) => info!("Successfully copied {} bytes.", bytes_copied),
-        Err(e) => eprintln!("Failed to copy file: {}", e),
+    let mut writer = io::BufWriter::new(File::create(outfile)?);
+    for barcode in barcodes.iter() {
+        writeln!(writer, "{}", barcode)?;
     }
-    Ok(())
+    writer.flush()
+}
+
+/// Cells overlapping each feature, with fragment counts, summed interval
+/// weights or, when binarized, only whether any fragment was seen
+pub enum FeatureCounts {
+    Counts(Vec<FxHashMap<u32, u32>>),
+    Binary(Vec<FxHashSet<u32>>),
+    Weighted(Vec<FxHashMap<u32, f32>>),
 }
 
-fn write_matrix_market(
+impl FeatureCounts {
+    pub fn new(total_features: usize, binarize: bool) -> Self {
+        if binarize {
+            FeatureCounts::Binary(vec![FxHashSet::default(); total_features])
+        } else {
+            FeatureCounts::Counts(vec![FxHashMap::default(); total_features])
+        }
+    }
+
+    pub fn weighted(total_features: usize) -> Self {
+        FeatureCounts::Weighted(vec![FxHashMap::default(); total_features])
+    }
+
+    /// Add one overlap of `cell` with `feature`. The weight is only used by
+    /// weighted counts.
+    fn add(&mut self, feature: usize, cell: u32, weight: f32) {
+        match self {
+            FeatureCounts::Counts(counts) => *counts[feature].entry(cell).or_insert(0) += 1,
+            FeatureCounts::Binary(cells) => {
+                cells[feature].insert(cell);
+            }
+            FeatureCounts::Weighted(counts) => *counts[feature].entry(cell).or_insert(0.0) += weight,
+        }
+    }
+
+    /// MatrixMarket field type for these counts
+    pub fn field(&self) -> &'static str {
+        match self {
+            FeatureCounts::Counts(_) => "integer",
+            FeatureCounts::Binary(_) => "pattern",
+            FeatureCounts::Weighted(_) => "real",
+        }
+    }
+
+    pub fn nonzero(&self) -> usize {
+        match self {
+            FeatureCounts::Counts(counts) => counts.iter().map(|map| map.len()).sum(),
+            FeatureCounts::Binary(cells) => cells.iter().map(|set| set.len()).sum(),
+            FeatureCounts::Weighted(counts) => counts.iter().map(|map| map.len()).sum(),
+        }
+    }
+
+    /// Total count in each feature, or number of cells when binarized
+    fn totals(&self) -> Vec<u64> {
+        match self {
+            FeatureCounts::Counts(counts) => counts.iter()
+                .map(|map| map.values().map(|&count| count as u64).sum())
+                .collect(),
+            FeatureCounts::Binary(cells) => cells.iter().map(|set| set.len() as u64).collect(),
+            FeatureCounts::Weighted(counts) => counts.iter()
+                .map(|map| map.values().map(|&weight| weight as f64).sum::<f64>().round() as u64)
+                .collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            FeatureCounts::Counts(counts) => counts.len(),
+            FeatureCounts::Binary(cells) => cells.len(),
+            FeatureCounts::Weighted(counts) => counts.len(),
+        }
+    }
+
+    /// Append the MatrixMarket entries for one feature, with 1-based indices
+    pub fn push_entries(&self, feature: usize, output: &mut String) {
+        match self {
+            FeatureCounts::Counts(counts) => {
+                for (key, value) in counts[feature].iter() {
+                    output.push_str(&format!("{} {} {}\n", feature + 1, key + 1, value));
+                }
+            }
+            FeatureCounts::Binary(cells) => {
+                for key in cells[feature].iter() {
+                    output.push_str(&format!("{} {}\n", feature + 1, key + 1));
+                }
+            }
+            FeatureCounts::Weighted(counts) => {
+                for (key, value) in counts[feature].iter() {
+                    output.push_str(&format!("{} {} {}\n", feature + 1, key + 1, value));
+                }
+            }
+        }
+    }
+}
+
+/// MatrixMarket header lines and size line. Binary matrices use the
+/// pattern field and store only the coordinates of nonzero entries.
+pub fn matrix_market_header(field: &str, nrow: usize, ncol: usize, nonzero: usize) -> String {
+    let mut header = format!("%%MatrixMarket matrix coordinate {} general\n", field);
+    header.push_str("%%metadata json: {{\"software_version\": \"fragtk-1.1.0\"}}\n");
+    header.push_str(&format!("{} {} {}\n", nrow, ncol, nonzero));
+    header
+}
+
+pub fn write_matrix_market(
     outfile: &Path,
-    peak_cell_counts: &[FxHashMap<u32, u32>],
+    peak_cell_counts: &FeatureCounts,
     nrow: usize,
     ncol: usize,
-    num_threads: usize,
+    compression: &OutputCompression,
 ) -> io::Result<()> {
 
     // get nonzero value count
-    let nonzero: usize = peak_cell_counts.iter().map(|map| map.len()).sum();
+    let nonzero: usize = peak_cell_counts.nonzero();
 
     // create output file
-    let writer = File::create(outfile)?;
-    let mut encoder: ParCompress<Gzip> = ParCompressBuilder::new()
-        .compression_level(Compression::default())  // Set compression level
-        .num_threads(num_threads)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))? 
-        .from_writer(writer);
+    let mut encoder = compression.create(outfile)?;
 
     // Create a string buffer to collect all lines
     let mut output = String::new();
 
     // Write the header for the Matrix Market format
-    output.push_str("%%MatrixMarket matrix coordinate integer general\n");
-    output.push_str("%%metadata json: {{\"software_version\": \"fragtk-1.1.0\"}}\n");
-    output.push_str(&format!("{} {} {}\n", nrow, ncol, nonzero));
-    encoder.write_all(output.as_bytes())?;
-    output.clear();
+    encoder.write_all(matrix_market_header(peak_cell_counts.field(), nrow, ncol, nonzero).as_bytes())?;
 
     // Collect each peak-cell-count entry into the string buffer
-    for (index, hashmap) in peak_cell_counts.iter().enumerate() {
-        for (key, value) in hashmap.iter() {
-            output.push_str(&format!("{} {} {}\n", index + 1, key + 1, value)); // +1 to convert 0-based to 1-based indices
-        }
+    for index in 0..peak_cell_counts.len() {
+        peak_cell_counts.push_entries(index, &mut output);
         // write chunk, clear string
         if index % 5000 == 0 {
             encoder.write_all(output.as_bytes())?;
@@ -298,116 +599,165 @@ fn write_matrix_market(
         encoder.write_all(output.as_bytes())?;
     }
 
-    encoder.finish().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    encoder.finish()?;
 
     Ok(())
 }
 
+/// Feature index and weight of each BED interval. The values of the
+/// region index returned by `peak_intervals` index these intervals.
+pub struct IntervalFeatures {
+    features: Vec<usize>,
+    // scores from BED column 5, when weighting intervals
+    weights: Option<Vec<f32>>,
+    // count each feature once per fragment, however many of its intervals overlap
+    once: bool,
+}
+
+impl IntervalFeatures {
+    fn new(weighted: bool, once: bool) -> Self {
+        IntervalFeatures {
+            features: Vec::new(),
+            weights: if weighted { Some(Vec::new()) } else { None },
+            once,
+        }
+    }
+
+    /// Add an interval, returning its index
+    fn push(&mut self, feature: usize, weight: f32) -> usize {
+        self.features.push(feature);
+        if let Some(weights) = &mut self.weights {
+            weights.push(weight);
+        }
+        self.features.len() - 1
+    }
+
+    /// Copy of `regions` with interval values replaced by feature indices
+    fn feature_regions(&self, regions: &RegionIndex) -> RegionIndex {
+        regions.iter()
+            .map(|(chrom, lapper)| {
+                let intervals = lapper.intervals.iter()
+                    .map(|interval| Interval { start: interval.start, stop: interval.stop, val: self.features[interval.val] })
+                    .collect();
+                (chrom.clone(), Lapper::new(intervals))
+            })
+            .collect()
+    }
+
+    fn resolve(&self, interval: usize) -> (usize, f32) {
+        let weight = self.weights.as_ref().map_or(1.0, |weights| weights[interval]);
+        (self.features[interval], weight)
+    }
+}
+
+/// Read BED intervals into a region index and feature registry. With
+/// `group`, intervals are combined into one feature per fourth-column value.
 fn peak_intervals(
     bed_file: &Path,
     group: bool,
-    outfile: &Path,
-    num_threads: usize,
-) -> io::Result<(usize, FxHashMap<String, Lapper<u32, usize>>)> {
-
-    // feature file
-    let writer = File::create(outfile)?;
-    let mut writer: ParCompress<Gzip> = ParCompressBuilder::new()
-        .compression_level(Compression::default())
-        .num_threads(num_threads)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
-        .from_writer(writer);
-    
-    // bed file reader
-    let file = File::open(bed_file)?;
-    let reader = BufReader::new(file);
-    
-    // hashmap of peak intervals for each chromosome
-    let mut chromosome_trees: FxHashMap<String, Vec<Interval<u32, usize>>> = FxHashMap::default();
-    
-    // Store peak group name and corresponding index
-    let mut peak_group_index: FxHashMap<String, usize> = FxHashMap::default();
-    
-    // track total number of peaks
-    let mut total_peaks: usize = 0;
+    mut interval_features: IntervalFeatures,
+) -> io::Result<(FeatureRegistry, RegionIndex, IntervalFeatures)> {
 
-    // index for peak groups
-    let mut current_index: usize = 0;
+    // features with dense indices, skipping headers and malformed lines
+    let mut registry = FeatureRegistry::default();
 
-    // skipped lines
-    let mut skipped_lines: usize = 0;
-
-    for (index, line) in reader.lines().enumerate() {
-
-        match line {
-            Ok(line) => {
-                if line.starts_with('#') {
-                    skipped_lines += 1;
-                    continue;
-                }
-                let fields: Vec<&str> = line.split('\t').collect();
-                if fields.len() >= 3 {
-                    let chromosome = fields[0].to_string();
-                    let start: u32 = match fields[1].parse() {
-                        Ok(num) => num,
-                        Err(_) => {
-                            error!("Line {}: Failed to parse start position", index + 1);
-                            continue;
-                        }
-                    };
-                    let end: u32 = match fields[2].parse() {
-                        Ok(num) => num,
-                        Err(_) => {
-                            error!("Line {}: Failed to parse end position", index + 1);
-                            continue;
-                        }
-                    };
-
-                    let intervals = chromosome_trees.entry(chromosome.clone()).or_insert_with(Vec::new);
-
-                    if group && (fields.len() >= 4) {
-                        let peakgroup: String = match fields[3].parse() {
-                            Ok(num) => num,
-                            Err(_) => {
-                                error!("Line {}: Failed to parse group information", index + 1);
-                                continue;
-                            }
-                        };
-
-                        let group_index = peak_group_index.entry(peakgroup.clone()).or_insert_with(|| {
-                            writeln!(writer, "{}", peakgroup).expect("Failed to write");
-                            let idx: usize = current_index - skipped_lines;
-                            current_index += 1;
-                            idx
-                        });
-
-                        intervals.push(Interval { start, stop: end, val: *group_index });
-                    } else {
-                        intervals.push(Interval { start, stop: end, val: index - skipped_lines});
-                        writeln!(writer, "{}-{}-{}", chromosome, start, end)?;
-                    }
-                    total_peaks += 1;
-                } else {
-                    error!("Line {}: Less than three fields", index + 1);
+    let lapper_map = read_bed(bed_file, |record| {
+        let weight: f32 = match (&interval_features.weights, record.fields.get(4)) {
+            (None, _) => 1.0,
+            (Some(_), Some(score)) => match score.parse() {
+                Ok(num) => num,
+                Err(_) => {
+                    error!("Line {}: Failed to parse score", record.line);
+                    return None;
                 }
             },
-            Err(e) => {
-                error!("Error reading line {}: {}", index + 1, e);
-                break;
+            (Some(_), None) => {
+                error!("Line {}: Missing score column", record.line);
+                return None;
             }
+        };
+        let peakgroup = record.fields.get(3).filter(|name| !name.is_empty()).copied();
+        if group && peakgroup.is_none() {
+            error!("Line {}: Missing group in fourth column", record.line);
+            return None;
         }
+
+        let interval = BedInterval {
+            chrom: record.chrom.to_string(),
+            start: record.start,
+            end: record.end,
+            line: record.line,
+        };
+        let feature = match peakgroup {
+            Some(peakgroup) if group => registry.add_to_group(interval, peakgroup),
+            _ => registry.add_interval(interval, peakgroup),
+        };
+        Some(interval_features.push(feature, weight))
+    })?;
+
+    Ok((registry, lapper_map, interval_features))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read `bed` with `peak_intervals`, returning the registry and the
+    /// feature index of each interval, by chromosome and start
+    fn read_bed(name: &str, bed: &str, group: bool) -> (FeatureRegistry, Vec<(String, u32, usize)>) {
+        let dir = crate::testing::TestDir::new(name);
+        let path = dir.write("peaks.bed", bed);
+        let (registry, regions, intervals) = peak_intervals(&path, group, IntervalFeatures::new(false, false)).unwrap();
+
+        let mut features: Vec<(String, u32, usize)> = regions.iter()
+            .flat_map(|(chrom, lapper)| {
+                lapper.intervals.iter().map(|interval| (chrom.clone(), interval.start, intervals.resolve(interval.val).0))
+            })
+            .collect();
+        features.sort();
+        (registry, features)
     }
 
-    let lapper_map = chromosome_trees.into_iter()
-        .map(|(chr, intervals)| (chr, Lapper::new(intervals)))
-        .collect();
+    #[test]
+    fn headers_do_not_take_an_index() {
+        let bed = "#comment\ntrack name=peaks\nbrowser position chr1:1-100\nchr1\t100\t200\nchr2\t50\t150\n";
+        let (registry, features) = read_bed("headers", bed, false);
+        assert_eq!(registry.len(), 2);
+        assert_eq!(features, [("chr1".to_string(), 100, 0), ("chr2".to_string(), 50, 1)]);
+    }
 
-    if group {
-        total_peaks = current_index;
+    #[test]
+    fn malformed_lines_do_not_take_an_index() {
+        let bed = "chr1\t100\t200\nchr1\tx\t300\nchr1\t400\nchr1\t500\t600\n";
+        let (registry, features) = read_bed("malformed", bed, false);
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.names().collect::<Vec<&str>>(), ["chr1-100-200", "chr1-500-600"]);
+        assert_eq!(features, [("chr1".to_string(), 100, 0), ("chr1".to_string(), 500, 1)]);
     }
 
-    // Finalize the compression, converting GzpError to io::Error
-    writer.finish().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    #[test]
+    fn groups_after_headers_are_indexed_by_first_appearance() {
+        let bed = "#comment\n#comment\nchr1\t100\t200\tB\nchr1\t300\t400\nchr1\t500\t600\tA\nchr2\t0\t50\tB\n";
+        let (registry, features) = read_bed("groups", bed, true);
+        assert_eq!(registry.names().collect::<Vec<&str>>(), ["B", "A"]);
+        assert_eq!(
+            features,
+            [("chr1".to_string(), 100, 0), ("chr1".to_string(), 500, 1), ("chr2".to_string(), 0, 0)]
+        );
+    }
 
-    Ok((total_peaks, lapper_map))
-}
\ No newline at end of file
+    #[test]
+    fn cells_skip_blank_lines_and_duplicates() {
+        let dir = crate::testing::TestDir::new("cells");
+        let cell_file = dir.write("cells.txt", "A\n\nB\nA\n  C \n\n");
+        let (barcodes, cells) = load_cells(&cell_file).unwrap();
+        assert_eq!(barcodes, ["A", "B", "C"]);
+        for (index, barcode) in barcodes.iter().enumerate() {
+            assert_eq!(cells[barcode], index as u32);
+        }
+
+        let barcodes_path = dir.join("barcodes.tsv");
+        write_cells(&barcodes_path, &barcodes).unwrap();
+        assert_eq!(fs::read_to_string(&barcodes_path).unwrap(), "A\nB\nC\n");
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    io,
+    path::Path,
+    fs::File,
+    io::BufReader,
+    io::BufRead,
+    io::Read,
+    io::Seek,
+    io::SeekFrom,
+};
+use log::info;
+use rustc_hash::FxHashMap;
+
+/// Location of one sequence in the FASTA file, as in a samtools .fai index
+struct FaiRecord {
+    length: u64,
+    offset: u64,
+    line_bases: u64,
+    line_width: u64,
+}
+
+/// Random access to sequences in an uncompressed FASTA file. The .fai
+/// index next to the file is used if present, otherwise the file is
+/// scanned once to build the same index in memory.
+pub struct Fasta {
+    file: File,
+    index: FxHashMap<String, FaiRecord>,
+    scratch: Vec<u8>,
+}
+
+impl Fasta {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut magic = [0u8; 2];
+        let read = file.read(&mut magic)?;
+        if read == 2 && magic == [0x1f, 0x8b] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Compressed FASTA files are not supported, please decompress the genome first",
+            ));
+        }
+
+        let mut fai_path = path.as_os_str().to_owned();
+        fai_path.push(".fai");
+        let fai_path = Path::new(&fai_path);
+        let index = if fai_path.exists() {
+            info!("Reading FASTA index: {:?}", fai_path);
+            read_fai(fai_path)?
+        } else {
+            info!("No FASTA index found, indexing {:?}", path);
+            scan_index(path)?
+        };
+
+        Ok(Fasta { file, index, scratch: Vec::new() })
+    }
+
+    pub fn contains(&self, chrom: &str) -> bool {
+        self.index.contains_key(chrom)
+    }
+
+    /// Read the uppercase sequence of `chrom` from `start` to `end` (0-based,
+    /// half-open) into `buffer`, truncated at the end of the chromosome.
+    /// Returns false if the chromosome is not in the FASTA file.
+    pub fn fetch(&mut self, chrom: &str, start: u64, end: u64, buffer: &mut Vec<u8>) -> io::Result<bool> {
+        buffer.clear();
+        let record = match self.index.get(chrom) {
+            Some(record) => record,
+            None => return Ok(false),
+        };
+        let end = end.min(record.length);
+        if start >= end {
+            return Ok(true);
+        }
+
+        let byte_offset = |position: u64| {
+            record.offset + position / record.line_bases * record.line_width + position % record.line_bases
+        };
+        let first = byte_offset(start);
+        let last = byte_offset(end - 1);
+
+        self.scratch.resize((last - first + 1) as usize, 0);
+        self.file.seek(SeekFrom::Start(first))?;
+        self.file.read_exact(&mut self.scratch)?;
+        buffer.extend(
+            self.scratch.iter()
+                .filter(|&&base| base != b'\n' && base != b'\r')
+                .map(|base| base.to_ascii_uppercase())
+        );
+        Ok(true)
+    }
+}
+
+fn read_fai(path: &Path) -> io::Result<FxHashMap<String, FaiRecord>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut index: FxHashMap<String, FaiRecord> = FxHashMap::default();
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        let parsed: Vec<u64> = fields.iter().skip(1).take(4).filter_map(|field| field.parse().ok()).collect();
+        if fields.len() < 5 || parsed.len() < 4 || parsed[2] == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("FASTA index line {}: expected name, length, offset, line bases and line width", line_number + 1),
+            ));
+        }
+        index.insert(fields[0].to_string(), FaiRecord {
+            length: parsed[0],
+            offset: parsed[1],
+            line_bases: parsed[2],
+            line_width: parsed[3],
+        });
+    }
+    Ok(index)
+}
+
+/// Build a .fai-style index by reading through the FASTA file. As with
+/// samtools faidx, all sequence lines of a record except the last must
+/// have the same length.
+fn scan_index(path: &Path) -> io::Result<FxHashMap<String, FaiRecord>> {
+    let mut reader = BufReader::with_capacity(1024 * 1024, File::open(path)?);
+    let mut index: FxHashMap<String, FaiRecord> = FxHashMap::default();
+    let mut line: Vec<u8> = Vec::new();
+    let mut position: u64 = 0;
+    let mut current: Option<(String, FaiRecord)> = None;
+
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)? as u64;
+        if read == 0 {
+            break;
+        }
+        position += read;
+
+        if line.starts_with(b">") {
+            if let Some((name, record)) = current.take() {
+                index.insert(name, record);
+            }
+            let header = String::from_utf8_lossy(&line[1..]);
+            let name = header.split_whitespace().next().unwrap_or_default().to_string();
+            current = Some((name, FaiRecord { length: 0, offset: position, line_bases: 0, line_width: 0 }));
+            continue;
+        }
+
+        if let Some((_, record)) = current.as_mut() {
+            let bases = line.iter().filter(|&&base| base != b'\n' && base != b'\r').count() as u64;
+            if record.line_bases == 0 {
+                record.line_bases = bases;
+                record.line_width = read;
+            }
+            record.length += bases;
+        }
+    }
+    if let Some((name, record)) = current.take() {
+        index.insert(name, record);
+    }
+
+    // records without sequence can never be fetched
+    index.retain(|_, record| record.line_bases > 0);
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    const FASTA: &str = ">chr1 first\nACGTa\ncgtAC\nGT\n>empty\n>chr2\r\nTTTT\r\nGG\r\n";
+
+    fn fetch(fasta: &mut Fasta, chrom: &str, start: u64, end: u64) -> Option<String> {
+        let mut buffer = Vec::new();
+        fasta.fetch(chrom, start, end, &mut buffer).unwrap()
+            .then(|| String::from_utf8(buffer).unwrap())
+    }
+
+    #[test]
+    fn scanned_index_fetches_across_lines() {
+        let dir = TestDir::new("fasta-scan");
+        let path = dir.write("genome.fa", FASTA);
+        let mut fasta = Fasta::open(&path).unwrap();
+        assert!(fasta.contains("chr1"));
+        assert!(!fasta.contains("empty"));
+
+        assert_eq!(fetch(&mut fasta, "chr1", 3, 7).as_deref(), Some("TACG"));
+        assert_eq!(fetch(&mut fasta, "chr1", 8, 100).as_deref(), Some("ACGT"));
+        assert_eq!(fetch(&mut fasta, "chr1", 12, 20).as_deref(), Some(""));
+        assert_eq!(fetch(&mut fasta, "chr2", 2, 6).as_deref(), Some("TTGG"));
+        assert_eq!(fetch(&mut fasta, "chr3", 0, 10), None);
+    }
+
+    #[test]
+    fn fai_index_is_used_when_present() {
+        let dir = TestDir::new("fasta-fai");
+        let path = dir.write("genome.fa", FASTA);
+        // the same chr1 record, under a name only the index has
+        dir.write("genome.fa.fai", "indexed\t12\t12\t5\t6\n");
+        let mut fasta = Fasta::open(&path).unwrap();
+        assert!(!fasta.contains("chr1"));
+        assert_eq!(fetch(&mut fasta, "indexed", 4, 6).as_deref(), Some("AC"));
+
+        dir.write("genome.fa.fai", "chr1\t12\t12\t0\t1\n");
+        assert_eq!(Fasta::open(&path).map(|_| ()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    io,
+    path::Path,
+    io::Write,
+};
+use log::warn;
+use rustc_hash::FxHashMap;
+use crate::output::OutputCompression;
+
+/// One BED interval belonging to a feature
+pub struct BedInterval {
+    pub chrom: String,
+    pub start: u32,
+    pub end: u32,
+    /// 1-based line number in the BED file
+    pub line: usize,
+}
+
+/// A matrix row: a single BED interval, or all intervals of a group
+pub struct Feature {
+    pub name: String,
+    /// group from the fourth BED column, if present
+    pub group: Option<String>,
+    pub intervals: Vec<BedInterval>,
+}
+
+/// Features of a `matrix` run, with dense indices in the order they are
+/// first seen in the BED file. Header, comment and malformed lines never
+/// take an index, so the indices match the rows of features.tsv.
+#[derive(Default)]
+pub struct FeatureRegistry {
+    features: Vec<Feature>,
+    // feature index of each group name
+    groups: FxHashMap<String, usize>,
+    // feature index of each single-interval feature, to report duplicates
+    intervals: FxHashMap<(String, u32, u32), usize>,
+}
+
+impl FeatureRegistry {
+    /// Add an interval as a feature of its own, returning the feature index
+    pub fn add_interval(&mut self, interval: BedInterval, group: Option<&str>) -> usize {
+        let key = (interval.chrom.clone(), interval.start, interval.end);
+        if let Some(&first) = self.intervals.get(&key) {
+            warn!(
+                "Line {}: same interval as line {}, counted as a separate feature",
+                interval.line, self.features[first].intervals[0].line
+            );
+        } else {
+            self.intervals.insert(key, self.features.len());
+        }
+        self.features.push(Feature {
+            name: format!("{}-{}-{}", interval.chrom, interval.start, interval.end),
+            group: group.map(str::to_string),
+            intervals: vec![interval],
+        });
+        self.features.len() - 1
+    }
+
+    /// Add an interval to the feature for `group`, creating it if this is
+    /// the first interval of the group, and return the feature index
+    pub fn add_to_group(&mut self, interval: BedInterval, group: &str) -> usize {
+        let index = match self.groups.get(group) {
+            Some(&index) => index,
+            None => {
+                self.features.push(Feature {
+                    name: group.to_string(),
+                    group: Some(group.to_string()),
+                    intervals: Vec::new(),
+                });
+                self.groups.insert(group.to_string(), self.features.len() - 1);
+                self.features.len() - 1
+            }
+        };
+        self.features[index].intervals.push(interval);
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.features.len()
+    }
+
+    /// Feature names, in index order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.features.iter().map(|feature| feature.name.as_str())
+    }
+
+    /// Write one line per feature: name, comma-separated chrom:start-end
+    /// coordinates of its intervals, and group, or `.` without a group
+    pub fn write(&self, outfile: &Path, compression: &OutputCompression) -> io::Result<()> {
+        let mut writer = compression.create(outfile)?;
+        let mut line = String::new();
+        for feature in self.features.iter() {
+            line.clear();
+            line.push_str(&feature.name);
+            line.push('\t');
+            for (index, interval) in feature.intervals.iter().enumerate() {
+                if index > 0 {
+                    line.push(',');
+                }
+                line.push_str(&format!("{}:{}-{}", interval.chrom, interval.start, interval.end));
+            }
+            line.push('\t');
+            line.push_str(feature.group.as_deref().unwrap_or("."));
+            line.push('\n');
+            writer.write_all(line.as_bytes())?;
+        }
+        writer.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn interval(chrom: &str, start: u32, end: u32, line: usize) -> BedInterval {
+        BedInterval { chrom: chrom.to_string(), start, end, line }
+    }
+
+    fn uncompressed() -> OutputCompression {
+        let matches = clap::Command::new("test")
+            .args(crate::compression_args("none"))
+            .get_matches_from(["test"]);
+        OutputCompression::from_matches(&matches, 1).unwrap()
+    }
+
+    #[test]
+    fn groups_are_indexed_by_first_appearance() {
+        let mut registry = FeatureRegistry::default();
+        assert_eq!(registry.add_to_group(interval("chr1", 0, 10, 1), "B"), 0);
+        assert_eq!(registry.add_to_group(interval("chr1", 20, 30, 2), "A"), 1);
+        assert_eq!(registry.add_to_group(interval("chr2", 0, 10, 3), "B"), 0);
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.names().collect::<Vec<&str>>(), ["B", "A"]);
+    }
+
+    #[test]
+    fn duplicate_intervals_are_separate_features() {
+        let mut registry = FeatureRegistry::default();
+        assert_eq!(registry.add_interval(interval("chr1", 0, 10, 1), None), 0);
+        assert_eq!(registry.add_interval(interval("chr1", 0, 10, 2), None), 1);
+        assert_eq!(registry.len(), 2);
+    }
+
+    #[test]
+    fn writes_name_coordinates_and_group() {
+        let mut registry = FeatureRegistry::default();
+        registry.add_interval(interval("chr1", 100, 200, 1), None);
+        registry.add_interval(interval("chr1", 300, 400, 2), Some("enhancer"));
+        let mut grouped = FeatureRegistry::default();
+        grouped.add_to_group(interval("chr1", 100, 200, 1), "set1");
+        grouped.add_to_group(interval("chr2", 5, 15, 2), "set1");
+
+        let dir = crate::testing::TestDir::new("features");
+        let path = dir.join("features.tsv");
+        registry.write(&path, &uncompressed()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "chr1-100-200\tchr1:100-200\t.\nchr1-300-400\tchr1:300-400\tenhancer\n"
+        );
+        grouped.write(&path, &uncompressed()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "set1\tchr1:100-200,chr2:5-15\tset1\n");
+    }
+}
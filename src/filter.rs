@@ -1,23 +1,81 @@
 use std::fs::File;
 use std::io::{BufRead, BufReader, Write};
 use std::path::Path;
-use flate2::read::MultiGzDecoder;
 use rustc_hash::FxHashSet;
+use log::info;
+use crate::regions::{load_regions, overlaps, RegionIndex};
+use crate::chroms::ChromFilter;
+use crate::barcodes::BarcodeMap;
+use crate::fragments::{detect_format, FragmentFormat, FragmentStream};
+use crate::output::OutputCompression;
 
 pub fn run(matches: &clap::ArgMatches) -> std::io::Result<()> {
     // Get file paths from command-line arguments
-    let cells_file = matches.get_one::<String>("cells").unwrap();
     let fragments_file = matches.get_one::<String>("fragments").unwrap();
 
     // Load the cell barcodes into a FxHashSet for fast lookups
-    let cell_barcodes = load_cells(cells_file)?;
+    let cell_barcodes = match matches.get_one::<String>("cells") {
+        Some(cells_file) => Some(load_cells(cells_file)?),
+        None => None,
+    };
 
-    // Filter the fragment file based on the cell barcodes
-    filter_fragments(fragments_file, &cell_barcodes)?;
+    // Load regions to keep and regions to exclude
+    let keep_regions = match matches.get_one::<String>("regions") {
+        Some(bed) => {
+            info!("Keeping fragments overlapping regions in {:?}", bed);
+            Some(load_regions(Path::new(bed))?)
+        }
+        None => None,
+    };
+    let exclude_regions = match matches.get_one::<String>("exclude") {
+        Some(bed) => {
+            info!("Removing fragments overlapping regions in {:?}", bed);
+            Some(load_regions(Path::new(bed))?)
+        }
+        None => None,
+    };
+
+    let filters = FragmentFilters {
+        chroms: ChromFilter::from_matches(matches)?,
+        cells: cell_barcodes,
+        keep: keep_regions,
+        exclude: exclude_regions,
+        barcodes: BarcodeMap::from_matches(matches)?,
+    };
+
+    let compression = OutputCompression::from_matches(matches, *matches.get_one::<usize>("threads").unwrap())?;
+
+    // Filter the fragment file based on the cell barcodes and regions
+    filter_fragments(fragments_file, &filters, &compression)?;
 
     Ok(())
 }
 
+/// Criteria a fragment must pass to be written to the output
+struct FragmentFilters {
+    chroms: ChromFilter,
+    cells: Option<FxHashSet<String>>,
+    keep: Option<RegionIndex>,
+    exclude: Option<RegionIndex>,
+    barcodes: Option<BarcodeMap>,
+}
+
+impl FragmentFilters {
+    fn passes_regions(&self, chrom: &str, start: u32, end: u32) -> bool {
+        if let Some(keep) = &self.keep {
+            if !overlaps(keep, chrom, start, end) {
+                return false;
+            }
+        }
+        if let Some(exclude) = &self.exclude {
+            if overlaps(exclude, chrom, start, end) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
 fn load_cells<P: AsRef<Path>>(path: P) -> std::io::Result<FxHashSet<String>> {
     let file = File::open(path)?;
     let reader = BufReader::new(file);
@@ -33,50 +91,57 @@ fn load_cells<P: AsRef<Path>>(path: P) -> std::io::Result<FxHashSet<String>> {
 
 fn filter_fragments<P: AsRef<Path>>(
     fragments_path: P,
-    cell_barcodes: &FxHashSet<String>,
+    filters: &FragmentFilters,
+    compression: &OutputCompression,
 ) -> std::io::Result<()> {
-    let fragments_file = File::open(fragments_path)?;
-    let mut fragments_reader = BufReader::with_capacity(1024 * 1024, MultiGzDecoder::new(fragments_file));
-
-    let stdout = std::io::stdout();
-    let mut output_writer = stdout.lock();
-
-    let mut line_count: u64 = 0;
-    let mut buffer = String::with_capacity(1024);
-
-    loop {
-        buffer.clear();
-        match fragments_reader.read_line(&mut buffer) {
-            Ok(0) => break, // End of file
-            Ok(_) => {
-                // Remove trailing newline
-                if buffer.ends_with('\n') {
-                    buffer.pop();
-                }
-                if buffer.ends_with('\r') {
-                    buffer.pop();
-                }
-
-                // Skip comment lines
-                if buffer.starts_with('#') {
-                    continue;
-                }
-
-                if let Some(barcode) = buffer.split('\t').nth(3) {
-                    if cell_barcodes.contains(barcode) {
-                        writeln!(output_writer, "{}", buffer)?;
-                    }
-                }
-
-                line_count += 1;
-                if line_count % 1_000_000 == 0 {
-                    eprint!("\rProcessed {} M lines", line_count / 1_000_000);
-                    std::io::stderr().flush().expect("Can't flush stderr");
-                }
+    if detect_format(fragments_path.as_ref())? != FragmentFormat::Text {
+        return filter_binary(fragments_path.as_ref(), filters, compression);
+    }
+
+    let mut fragments = FragmentStream::open(fragments_path.as_ref(), &filters.chroms, filters.barcodes.as_ref())?;
+
+    let mut output_writer = compression.wrap(std::io::stdout())?;
+
+    while let Some(fragment) = fragments.next_line()? {
+        if let Some(cells) = &filters.cells {
+            if !cells.contains(fragment.barcode.as_ref()) {
+                continue;
             }
-            Err(e) => return Err(e),
         }
+        if !filters.passes_regions(fragment.chrom, fragment.start, fragment.end) {
+            continue;
+        }
+        fragment.write(&mut output_writer)?;
     }
 
-    Ok(())
-}
\ No newline at end of file
+    output_writer.finish()
+}
+
+/// Filter a Parquet or `.frag` fragment file, writing the five standard
+/// fragment columns. Blocks of a `.frag` file outside the regions to keep
+/// are skipped using its block index.
+fn filter_binary(fragments_path: &Path, filters: &FragmentFilters, compression: &OutputCompression) -> std::io::Result<()> {
+    let mut fragments = FragmentStream::open(fragments_path, &filters.chroms, filters.barcodes.as_ref())?;
+    if let Some(keep) = &filters.keep {
+        fragments.restrict_to(keep);
+    }
+
+    let mut output_writer = compression.wrap(std::io::stdout())?;
+
+    while let Some(fragment) = fragments.next_fragment()? {
+        if let Some(cells) = &filters.cells {
+            if !cells.contains(fragment.barcode.as_ref()) {
+                continue;
+            }
+        }
+        if !filters.passes_regions(fragment.chrom, fragment.start, fragment.end) {
+            continue;
+        }
+        writeln!(
+            output_writer,
+            "{}\t{}\t{}\t{}\t{}",
+            fragment.chrom, fragment.start, fragment.end, fragment.barcode, fragment.count
+        )?;
+    }
+    output_writer.finish()
+}
@@ -0,0 +1,262 @@
+use std::{
+    io,
+    path::Path,
+    error::Error,
+    io::BufRead,
+    io::Write,
+};
+use log::{info, warn};
+use rust_lapper::{Interval, Lapper};
+use rustc_hash::FxHashMap;
+use crate::barcodes::{load_groups, BarcodeMap};
+use crate::bias::{kmer_code, BiasTable};
+use crate::chroms::ChromFilter;
+use crate::fasta::Fasta;
+use crate::fragments::FragmentStream;
+use crate::input::{input_path, open_input};
+use crate::output::OutputCompression;
+use crate::motifs::encode;
+
+// length of the sequence context used for the Tn5 bias model when no bias
+// table is given, centered on the insertion
+const DEFAULT_KMER: usize = 6;
+
+pub fn footprint(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = input_path(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+
+    let site_file = Path::new(matches.get_one::<String>("sites").unwrap());
+    info!("Received site file: {:?}", site_file);
+
+    let group_file = Path::new(matches.get_one::<String>("groups").unwrap());
+    info!("Received group file: {:?}", group_file);
+
+    let fasta_file = Path::new(matches.get_one::<String>("fasta").unwrap());
+    info!("Received FASTA file: {:?}", fasta_file);
+
+    let output = Path::new(matches.get_one::<String>("output").unwrap());
+    let flank = *matches.get_one::<u32>("flank").unwrap();
+    let width = 2 * flank as usize + 1;
+
+    let bias_table = match matches.get_one::<String>("bias") {
+        Some(bias_file) => {
+            info!("Received bias table: {:?}", bias_file);
+            Some(BiasTable::read(Path::new(bias_file))?)
+        }
+        None => None,
+    };
+    let k = bias_table.as_ref().map_or(DEFAULT_KMER, |table| table.k);
+
+    let groups = load_groups(group_file)?;
+    let chrom_filter = ChromFilter::from_matches(matches)?;
+    let compression = OutputCompression::from_matches(matches, 1)?;
+    let barcode_map = BarcodeMap::from_matches(matches)?;
+
+    let sites = load_sites(site_file, flank, k)?;
+    if sites.minus.is_empty() {
+        return Err("No usable sites found in site file".into());
+    }
+    info!("Loaded {} sites", sites.minus.len());
+
+    let mut fasta = Fasta::open(fasta_file)?;
+    info!("Counting sequence context around sites");
+    let positional = positional_kmers(&mut fasta, &sites, width, k)?;
+
+    info!("Counting insertions around sites");
+    let mut fragments = FragmentStream::open(&frag_file, &chrom_filter, barcode_map.as_ref())?;
+    let mut profiles: Vec<Vec<u64>> = vec![vec![0; width]; groups.names.len()];
+    let mut observed_kmers = vec![0u64; 1 << (2 * k)];
+
+    let mut current_chrom = String::new();
+    let mut current_lapper: Option<&Lapper<u32, usize>> = None;
+    let mut sequences: FxHashMap<usize, Vec<u8>> = FxHashMap::default();
+
+    while let Some(fragment) = fragments.next_fragment()? {
+        let group = match groups.cells.get(fragment.barcode.as_ref()) {
+            Some(&group) => group,
+            None => continue,
+        };
+
+        if fragment.chrom != current_chrom {
+            current_chrom = fragment.chrom.to_string();
+            current_lapper = sites.windows.get(&current_chrom);
+            sequences.clear();
+            if let Some(lapper) = current_lapper {
+                for interval in lapper.intervals.iter() {
+                    sequences.insert(interval.val, padded_sequence(&mut fasta, &current_chrom, interval, k)?);
+                }
+            }
+        }
+        let lapper = match current_lapper {
+            Some(lapper) => lapper,
+            None => continue,
+        };
+
+        for position in fragment.insertions() {
+            for interval in lapper.find(position, position + 1) {
+                let offset = (position - interval.start) as usize;
+                let oriented = if sites.minus[interval.val] { width - 1 - offset } else { offset };
+                profiles[group][oriented] += 1;
+                if let Some(kmer) = kmer_code(&sequences[&interval.val], offset, k) {
+                    observed_kmers[kmer] += 1;
+                }
+            }
+        }
+    }
+
+    // without a bias table, estimate the Tn5 preference for each k-mer from
+    // its frequency at insertion sites relative to its frequency around the sites
+    let bias_table = bias_table.unwrap_or_else(|| {
+        let background_kmers: Vec<u64> = (0..observed_kmers.len())
+            .map(|kmer| positional.iter().map(|counts| counts[kmer]).sum())
+            .collect();
+        BiasTable::from_counts(k, &observed_kmers, &background_kmers)
+    });
+    let expected: Vec<f64> = positional.iter()
+        .map(|counts| counts.iter().zip(bias_table.bias.iter()).map(|(&count, &bias)| count as f64 * bias).sum())
+        .collect();
+    let expected_total: f64 = expected.iter().sum();
+
+    info!("Writing footprint profiles: {:?}", output);
+    let mut writer = compression.create(output)?;
+    writeln!(writer, "group\tposition\tinsertions\texpected")?;
+    for (name, profile) in groups.names.iter().zip(profiles.iter()) {
+        // expected insertions, scaled to the group's total around the sites
+        let group_total: u64 = profile.iter().sum();
+        let scale = if expected_total > 0.0 { group_total as f64 / expected_total } else { 0.0 };
+        for (offset, &count) in profile.iter().enumerate() {
+            let position = offset as i64 - flank as i64;
+            writeln!(writer, "{}\t{}\t{}\t{:.4}", name, position, count, expected[offset] * scale)?;
+        }
+    }
+    writer.finish()?;
+
+    Ok(())
+}
+
+/// Windows of `flank` bases either side of each site center, keyed by
+/// chromosome, with the interval value indexing `minus`
+struct Sites {
+    windows: FxHashMap<String, Lapper<u32, usize>>,
+    minus: Vec<bool>,
+}
+
+/// Read sites from a BED file. The site center is the midpoint of the
+/// region and a sixth column of `-` marks a site on the minus strand.
+fn load_sites(path: &Path, flank: u32, k: usize) -> io::Result<Sites> {
+    let pad = (k / 2) as u32;
+    let mut intervals: FxHashMap<String, Vec<Interval<u32, usize>>> = FxHashMap::default();
+    let mut minus: Vec<bool> = Vec::new();
+    let mut too_close = 0;
+
+    for (index, line) in open_input(path)?.lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let (start, end) = match (fields.get(1).map(|s| s.parse::<u32>()), fields.get(2).map(|s| s.parse::<u32>())) {
+            (Some(Ok(start)), Some(Ok(end))) => (start, end),
+            _ => {
+                warn!("Line {}: Failed to parse site coordinates", index + 1);
+                continue;
+            }
+        };
+        let center = start + (end.saturating_sub(start)) / 2;
+        if center < flank + pad {
+            too_close += 1;
+            continue;
+        }
+        intervals
+            .entry(fields[0].to_string())
+            .or_default()
+            .push(Interval { start: center - flank, stop: center + flank + 1, val: minus.len() });
+        minus.push(fields.get(5) == Some(&"-"));
+    }
+    if too_close > 0 {
+        warn!("Skipped {} sites within {} bp of the chromosome start", too_close, flank + pad);
+    }
+
+    Ok(Sites {
+        windows: intervals.into_iter()
+            .map(|(chrom, intervals)| (chrom, Lapper::new(intervals)))
+            .collect(),
+        minus,
+    })
+}
+
+/// Encoded sequence of a window with `k / 2` extra bases on each side, so
+/// that the k-mer centered on window offset `i` starts at index `i`
+fn padded_sequence(fasta: &mut Fasta, chrom: &str, window: &Interval<u32, usize>, k: usize) -> io::Result<Vec<u8>> {
+    let pad = (k / 2) as u64;
+    let mut sequence: Vec<u8> = Vec::new();
+    fasta.fetch(chrom, window.start as u64 - pad, window.stop as u64 + pad, &mut sequence)?;
+    encode(&mut sequence);
+    Ok(sequence)
+}
+
+/// Number of sites with each k-mer at each oriented position of the window
+fn positional_kmers(fasta: &mut Fasta, sites: &Sites, width: usize, k: usize) -> io::Result<Vec<Vec<u64>>> {
+    let mut positional: Vec<Vec<u64>> = vec![vec![0; 1 << (2 * k)]; width];
+    for (chrom, lapper) in sites.windows.iter() {
+        if !fasta.contains(chrom) {
+            warn!("Chromosome {} not found in FASTA file, its sites will not contribute to the bias expectation", chrom);
+            continue;
+        }
+        for interval in lapper.intervals.iter() {
+            let sequence = padded_sequence(fasta, chrom, interval, k)?;
+            for offset in 0..width {
+                if let Some(kmer) = kmer_code(&sequence, offset, k) {
+                    let oriented = if sites.minus[interval.val] { width - 1 - offset } else { offset };
+                    positional[oriented][kmer] += 1;
+                }
+            }
+        }
+    }
+    Ok(positional)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    #[test]
+    fn sites_are_centered_windows_with_strand() {
+        let dir = TestDir::new("footprint-sites");
+        let bed = "#header\nchr1\t9\t11\t.\t0\t+\nchr1\t0\t2\nchr1\tx\t5\nchr2\t20\t31\t.\t0\t-\n";
+        let sites = load_sites(&dir.write("sites.bed", bed), 2, 2).unwrap();
+        // the site centered at 1 is too close to the chromosome start
+        assert_eq!(sites.minus, [false, true]);
+        let window = |chrom: &str| {
+            let interval = &sites.windows[chrom].intervals[0];
+            (interval.start, interval.stop, interval.val)
+        };
+        assert_eq!(window("chr1"), (8, 13, 0));
+        assert_eq!(window("chr2"), (23, 28, 1));
+    }
+
+    #[test]
+    fn kmers_are_counted_at_oriented_positions() {
+        let dir = TestDir::new("footprint-kmers");
+        let mut fasta = Fasta::open(&dir.write("genome.fa", ">chr1\nACGTACGTACGTACGTACGT\n")).unwrap();
+        let sites = load_sites(&dir.write("sites.bed", "chr1\t9\t11\t.\t0\t+\nchr1\t9\t11\t.\t0\t-\n"), 2, 2).unwrap();
+
+        // the window 8-13 with one base of padding reads TACGTAC, so the
+        // centered 2-mers at offsets 0-4 are TA, AC, CG, GT, TA
+        let code = |kmer: &str| {
+            let mut kmer = kmer.as_bytes().to_vec();
+            encode(&mut kmer);
+            kmer_code(&kmer, 0, 2).unwrap()
+        };
+        let positional = positional_kmers(&mut fasta, &sites, 5, 2).unwrap();
+        let counted = |offset: usize| -> Vec<(usize, u64)> {
+            positional[offset].iter().enumerate().filter(|(_, &count)| count > 0).map(|(kmer, &count)| (kmer, count)).collect()
+        };
+        assert_eq!(counted(0), [(code("TA"), 2)]);
+        // the minus strand site is read from the other end of the window
+        assert_eq!(counted(1), [(code("AC"), 1), (code("GT"), 1)]);
+        assert_eq!(counted(2), [(code("CG"), 2)]);
+    }
+}
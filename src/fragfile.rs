@@ -0,0 +1,464 @@
+//! Compact binary fragment format (`.frag`).
+//!
+//! The file starts with `MAGIC` and is followed by deflate-compressed
+//! blocks, each holding up to `BLOCK_RECORDS` fragments from a single
+//! chromosome. Within a block every record is four varints: the
+//! zigzag-encoded change in start from the previous record, the fragment
+//! length, the barcode number and the read count. The footer holds the
+//! chromosome and barcode string tables and the block index, and is found
+//! through its offset, stored as a little-endian u64 followed by `MAGIC`
+//! at the end of the file.
+
+use std::{
+    io,
+    path::Path,
+    fs::File,
+    io::BufWriter,
+    io::Read,
+    io::Seek,
+    io::SeekFrom,
+    io::Write,
+};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use rustc_hash::FxHashMap;
+use crate::fragments::{Fragment, RecordSource};
+
+pub const MAGIC: &[u8; 4] = b"FRG1";
+const BLOCK_RECORDS: u32 = 1 << 16;
+
+/// Location and extent of one compressed block
+struct BlockInfo {
+    chrom: u32,
+    min_start: u32,
+    max_end: u32,
+    offset: u64,
+    compressed_len: u64,
+    records: u32,
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> io::Result<u64> {
+    let mut value: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*position).ok_or_else(|| invalid("truncated varint"))?;
+        *position += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift >= 64 {
+            return Err(invalid("varint is too long"));
+        }
+    }
+}
+
+fn write_string(buffer: &mut Vec<u8>, value: &str) {
+    write_varint(buffer, value.len() as u64);
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+fn read_string(data: &[u8], position: &mut usize) -> io::Result<String> {
+    let length = read_varint(data, position)? as usize;
+    let bytes = data.get(*position..*position + length).ok_or_else(|| invalid("truncated string"))?;
+    *position += length;
+    String::from_utf8(bytes.to_vec()).map_err(|_| invalid("string is not valid UTF-8"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid .frag file: {}", message))
+}
+
+/// Writes fragments to a `.frag` file
+pub struct FragFileWriter {
+    file: BufWriter<File>,
+    offset: u64,
+    chroms: Vec<String>,
+    barcodes: Vec<String>,
+    barcode_index: FxHashMap<String, u32>,
+    blocks: Vec<BlockInfo>,
+    block: Vec<u8>,
+    block_records: u32,
+    block_min_start: u32,
+    block_max_end: u32,
+    last_start: u32,
+}
+
+impl FragFileWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        Ok(FragFileWriter {
+            file,
+            offset: MAGIC.len() as u64,
+            chroms: Vec::new(),
+            barcodes: Vec::new(),
+            barcode_index: FxHashMap::default(),
+            blocks: Vec::new(),
+            block: Vec::new(),
+            block_records: 0,
+            block_min_start: u32::MAX,
+            block_max_end: 0,
+            last_start: 0,
+        })
+    }
+
+    pub fn write(&mut self, fragment: &Fragment) -> io::Result<()> {
+        if self.chroms.last().map(String::as_str) != Some(fragment.chrom) {
+            self.flush_block()?;
+            self.chroms.push(fragment.chrom.to_string());
+        } else if self.block_records >= BLOCK_RECORDS {
+            self.flush_block()?;
+        }
+
+        let barcode = match self.barcode_index.get(fragment.barcode.as_ref()) {
+            Some(&barcode) => barcode,
+            None => {
+                let barcode = self.barcodes.len() as u32;
+                self.barcodes.push(fragment.barcode.to_string());
+                self.barcode_index.insert(fragment.barcode.to_string(), barcode);
+                barcode
+            }
+        };
+
+        // zigzag encoding keeps unsorted input representable
+        let delta = fragment.start as i64 - self.last_start as i64;
+        write_varint(&mut self.block, ((delta << 1) ^ (delta >> 63)) as u64);
+        write_varint(&mut self.block, fragment.end.saturating_sub(fragment.start) as u64);
+        write_varint(&mut self.block, barcode as u64);
+        write_varint(&mut self.block, fragment.count as u64);
+
+        self.last_start = fragment.start;
+        self.block_min_start = self.block_min_start.min(fragment.start);
+        self.block_max_end = self.block_max_end.max(fragment.end);
+        self.block_records += 1;
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        if self.block_records == 0 {
+            return Ok(());
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.block)?;
+        let compressed = encoder.finish()?;
+        self.file.write_all(&compressed)?;
+
+        self.blocks.push(BlockInfo {
+            chrom: (self.chroms.len() - 1) as u32,
+            min_start: self.block_min_start,
+            max_end: self.block_max_end,
+            offset: self.offset,
+            compressed_len: compressed.len() as u64,
+            records: self.block_records,
+        });
+        self.offset += compressed.len() as u64;
+        self.block.clear();
+        self.block_records = 0;
+        self.block_min_start = u32::MAX;
+        self.block_max_end = 0;
+        self.last_start = 0;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush_block()?;
+
+        let mut footer: Vec<u8> = Vec::new();
+        write_varint(&mut footer, self.chroms.len() as u64);
+        for chrom in self.chroms.iter() {
+            write_string(&mut footer, chrom);
+        }
+        write_varint(&mut footer, self.barcodes.len() as u64);
+        for barcode in self.barcodes.iter() {
+            write_string(&mut footer, barcode);
+        }
+        write_varint(&mut footer, self.blocks.len() as u64);
+        for block in self.blocks.iter() {
+            write_varint(&mut footer, block.chrom as u64);
+            write_varint(&mut footer, block.min_start as u64);
+            write_varint(&mut footer, block.max_end as u64);
+            write_varint(&mut footer, block.offset);
+            write_varint(&mut footer, block.compressed_len);
+            write_varint(&mut footer, block.records as u64);
+        }
+
+        self.file.write_all(&footer)?;
+        self.file.write_all(&self.offset.to_le_bytes())?;
+        self.file.write_all(MAGIC)?;
+        self.file.flush()
+    }
+}
+
+struct FragRecord {
+    start: u32,
+    end: u32,
+    barcode: u32,
+    count: u32,
+}
+
+/// Reads fragments from a `.frag` file block by block, optionally only
+/// the blocks that overlap a set of regions
+pub struct FragFileReader {
+    file: File,
+    chroms: Vec<String>,
+    barcodes: Vec<String>,
+    blocks: Vec<BlockInfo>,
+    // blocks to read, in file order
+    selected: Vec<usize>,
+    next_block: usize,
+    chrom: u32,
+    records: Vec<FragRecord>,
+    row: usize,
+    compressed: Vec<u8>,
+    decompressed: Vec<u8>,
+}
+
+impl FragFileReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("missing header"));
+        }
+
+        let mut trailer = [0u8; 12];
+        file.seek(SeekFrom::End(-12))?;
+        file.read_exact(&mut trailer)?;
+        if &trailer[8..] != MAGIC {
+            return Err(invalid("missing footer, the file may be truncated"));
+        }
+        let footer_offset = u64::from_le_bytes(trailer[..8].try_into().expect("eight bytes"));
+        let file_len = file.seek(SeekFrom::End(0))?;
+        let mut footer = vec![0u8; (file_len - 12).saturating_sub(footer_offset) as usize];
+        file.seek(SeekFrom::Start(footer_offset))?;
+        file.read_exact(&mut footer)?;
+
+        let mut position = 0;
+        let chrom_count = read_varint(&footer, &mut position)? as usize;
+        let chroms = (0..chrom_count)
+            .map(|_| read_string(&footer, &mut position))
+            .collect::<io::Result<Vec<String>>>()?;
+        let barcode_count = read_varint(&footer, &mut position)? as usize;
+        let barcodes = (0..barcode_count)
+            .map(|_| read_string(&footer, &mut position))
+            .collect::<io::Result<Vec<String>>>()?;
+        let block_count = read_varint(&footer, &mut position)? as usize;
+        let mut blocks: Vec<BlockInfo> = Vec::with_capacity(block_count);
+        for _ in 0..block_count {
+            let block = BlockInfo {
+                chrom: read_varint(&footer, &mut position)? as u32,
+                min_start: read_varint(&footer, &mut position)? as u32,
+                max_end: read_varint(&footer, &mut position)? as u32,
+                offset: read_varint(&footer, &mut position)?,
+                compressed_len: read_varint(&footer, &mut position)?,
+                records: read_varint(&footer, &mut position)? as u32,
+            };
+            if block.chrom as usize >= chroms.len() {
+                return Err(invalid("block refers to an unknown chromosome"));
+            }
+            blocks.push(block);
+        }
+
+        Ok(FragFileReader {
+            file,
+            chroms,
+            barcodes,
+            selected: (0..blocks.len()).collect(),
+            blocks,
+            next_block: 0,
+            chrom: 0,
+            records: Vec::new(),
+            row: 0,
+            compressed: Vec::new(),
+            decompressed: Vec::new(),
+        })
+    }
+
+    fn read_block(&mut self, index: usize) -> io::Result<()> {
+        let block = &self.blocks[index];
+        self.compressed.resize(block.compressed_len as usize, 0);
+        self.file.seek(SeekFrom::Start(block.offset))?;
+        self.file.read_exact(&mut self.compressed)?;
+        self.decompressed.clear();
+        DeflateDecoder::new(self.compressed.as_slice()).read_to_end(&mut self.decompressed)?;
+
+        self.records.clear();
+        let data = &self.decompressed;
+        let mut position = 0;
+        let mut start: i64 = 0;
+        for _ in 0..block.records {
+            let zigzag = read_varint(data, &mut position)?;
+            start = start.checked_add(((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64))
+                .ok_or_else(|| invalid("record start is out of range"))?;
+            let length = read_varint(data, &mut position)?;
+            let barcode = read_varint(data, &mut position)? as u32;
+            let count = read_varint(data, &mut position)? as u32;
+            if barcode as usize >= self.barcodes.len() {
+                return Err(invalid("record refers to an unknown barcode"));
+            }
+            let record_start = u32::try_from(start).map_err(|_| invalid("record start is out of range"))?;
+            let end = u32::try_from(length).ok()
+                .and_then(|length| record_start.checked_add(length))
+                .ok_or_else(|| invalid("record end is out of range"))?;
+            self.records.push(FragRecord { start: record_start, end, barcode, count });
+        }
+        self.chrom = block.chrom;
+        Ok(())
+    }
+}
+
+impl RecordSource for FragFileReader {
+    fn advance(&mut self) -> io::Result<bool> {
+        self.row += 1;
+        while self.row >= self.records.len() {
+            let index = match self.selected.get(self.next_block) {
+                Some(&index) => index,
+                None => return Ok(false),
+            };
+            self.next_block += 1;
+            self.read_block(index)?;
+            self.row = 0;
+        }
+        Ok(true)
+    }
+
+    fn restrict(&mut self, keep: &dyn Fn(&str, u32, u32) -> bool) {
+        self.selected = self.blocks.iter()
+            .enumerate()
+            .filter(|(_, block)| keep(&self.chroms[block.chrom as usize], block.min_start, block.max_end.max(block.min_start.saturating_add(1))))
+            .map(|(index, _)| index)
+            .collect();
+        self.next_block = 0;
+        self.records.clear();
+        self.row = 0;
+    }
+
+    fn chrom(&self) -> &str {
+        &self.chroms[self.chrom as usize]
+    }
+
+    fn barcode(&self) -> &str {
+        &self.barcodes[self.records[self.row].barcode as usize]
+    }
+
+    fn start(&self) -> u32 {
+        self.records[self.row].start
+    }
+
+    fn end(&self) -> u32 {
+        self.records[self.row].end
+    }
+
+    fn count(&self) -> u32 {
+        self.records[self.row].count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+    use crate::chroms::ChromFilter;
+    use crate::fragments::FragmentStream;
+    use crate::regions::RegionIndex;
+    use crate::testing::TestDir;
+    use rust_lapper::{Interval, Lapper};
+
+    fn fragment<'a>(chrom: &'a str, start: u32, end: u32, barcode: &str, count: u32) -> Fragment<'a> {
+        Fragment { chrom, start, end, barcode: Cow::Owned(barcode.to_string()), count }
+    }
+
+    fn read_all(path: &Path, regions: Option<&RegionIndex>) -> Vec<(String, u32, u32, String, u32)> {
+        let chrom_filter = ChromFilter::default();
+        let mut stream = FragmentStream::open(path, &chrom_filter, None).unwrap();
+        if let Some(regions) = regions {
+            stream.restrict_to(regions);
+        }
+        let mut fragments = Vec::new();
+        while let Some(fragment) = stream.next_fragment().unwrap() {
+            fragments.push((fragment.chrom.to_string(), fragment.start, fragment.end, fragment.barcode.into_owned(), fragment.count));
+        }
+        fragments
+    }
+
+    #[test]
+    fn round_trip_through_fragment_stream() {
+        let dir = TestDir::new("fragfile-round-trip");
+        let path = dir.join("fragments.frag");
+        // the second chr1 fragment starts before the first, which needs a negative delta
+        let input = [
+            fragment("chr1", 500, 700, "AAAC-1", 2),
+            fragment("chr1", 100, 4_000_000_000, "GGTA-1", 1),
+            fragment("chr2", 0, 50, "AAAC-1", 7),
+        ];
+        let mut writer = FragFileWriter::create(&path).unwrap();
+        for fragment in input.iter() {
+            writer.write(fragment).unwrap();
+        }
+        writer.finish().unwrap();
+
+        assert_eq!(
+            read_all(&path, None),
+            [
+                ("chr1".to_string(), 500, 700, "AAAC-1".to_string(), 2),
+                ("chr1".to_string(), 100, 4_000_000_000, "GGTA-1".to_string(), 1),
+                ("chr2".to_string(), 0, 50, "AAAC-1".to_string(), 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn restrict_skips_blocks_outside_regions() {
+        let dir = TestDir::new("fragfile-restrict");
+        let path = dir.join("fragments.frag");
+        let mut writer = FragFileWriter::create(&path).unwrap();
+        // two blocks on chr1, then one on each of chr2 and chr3
+        for index in 0..BLOCK_RECORDS + 10 {
+            writer.write(&fragment("chr1", index * 10, index * 10 + 100, "A", 1)).unwrap();
+        }
+        writer.write(&fragment("chr2", 100, 200, "B", 1)).unwrap();
+        writer.write(&fragment("chr3", 100, 200, "C", 1)).unwrap();
+        writer.finish().unwrap();
+
+        // a region past the end of the first chr1 block, and one on chr3
+        let second_block = BLOCK_RECORDS * 10;
+        let mut regions = RegionIndex::default();
+        regions.insert("chr1".to_string(), Lapper::new(vec![Interval { start: second_block + 140, stop: second_block + 141, val: 0 }]));
+        regions.insert("chr3".to_string(), Lapper::new(vec![Interval { start: 0, stop: 1000, val: 1 }]));
+
+        let fragments = read_all(&path, Some(&regions));
+        assert_eq!(fragments.len(), 11);
+        assert!(fragments[..10].iter().all(|fragment| fragment.0 == "chr1" && fragment.1 >= second_block));
+        assert_eq!(fragments[10].0, "chr3");
+    }
+
+    #[test]
+    fn out_of_range_records_are_errors() {
+        let dir = TestDir::new("fragfile-range");
+        let path = dir.join("fragments.frag");
+        let mut writer = FragFileWriter::create(&path).unwrap();
+        writer.write(&fragment("chr1", 100, 200, "A", 1)).unwrap();
+        // a start delta that takes the position below zero
+        write_varint(&mut writer.block, 401);
+        write_varint(&mut writer.block, 10);
+        write_varint(&mut writer.block, 0);
+        write_varint(&mut writer.block, 1);
+        writer.block_records += 1;
+        writer.finish().unwrap();
+
+        let mut reader = FragFileReader::open(&path).unwrap();
+        assert_eq!(reader.advance().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
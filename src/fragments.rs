@@ -0,0 +1,375 @@
+use std::{
+    io,
+    path::Path,
+    fs::File,
+    io::BufRead,
+    io::Read,
+    io::Write,
+    borrow::Cow,
+};
+use log::warn;
+use crate::barcodes::BarcodeMap;
+use crate::bgzf::BgzfReader;
+use crate::chroms::{ChromCache, ChromFilter};
+use crate::columnar::ParquetFragments;
+use crate::fragfile::{self, FragFileReader};
+use crate::input::{is_stdin, open_input};
+use crate::regions::{overlaps, RegionIndex};
+
+/// A single fragment with chromosome and barcode renaming applied.
+/// Coordinates are 0-based and half-open, as in the fragment file.
+pub struct Fragment<'a> {
+    pub chrom: &'a str,
+    pub start: u32,
+    pub end: u32,
+    pub barcode: Cow<'a, str>,
+    /// Read pairs supporting the fragment, 1 if the file has no count column
+    pub count: u32,
+}
+
+impl Fragment<'_> {
+    /// 0-based positions of the two Tn5 insertion sites
+    pub fn insertions(&self) -> [u32; 2] {
+        [self.start, self.end.saturating_sub(1).max(self.start)]
+    }
+}
+
+/// A line of a text fragment file with chromosome and barcode renaming
+/// applied, for commands that write fragments out with all their columns
+pub struct FragmentLine<'a> {
+    /// the line as read, without the line ending
+    pub line: &'a str,
+    pub chrom: &'a str,
+    pub start: u32,
+    pub end: u32,
+    pub barcode: Cow<'a, str>,
+    // chromosome, start, end and barcode as read
+    fields: [&'a str; 4],
+    // columns after the barcode, starting with the count
+    rest: Option<&'a str>,
+}
+
+impl<'a> FragmentLine<'a> {
+    /// Read pairs supporting the fragment, 1 if the line has no count column
+    pub fn count(&self) -> u32 {
+        self.rest
+            .and_then(|rest| rest.split('\t').next())
+            .and_then(|count| count.parse::<u32>().ok())
+            .unwrap_or(1)
+    }
+
+    /// Columns after the count, if any
+    pub fn extra(&self) -> Option<&'a str> {
+        self.rest.and_then(|rest| rest.split_once('\t')).map(|(_, extra)| extra)
+    }
+
+    /// Write the line, replacing the chromosome and barcode if they were renamed
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.chrom == self.fields[0] && self.barcode == self.fields[3] {
+            writeln!(writer, "{}", self.line)
+        } else {
+            write!(writer, "{}\t{}\t{}\t{}", self.chrom, self.fields[1], self.fields[2], self.barcode)?;
+            match self.rest {
+                Some(rest) => writeln!(writer, "\t{}", rest),
+                None => writeln!(writer),
+            }
+        }
+    }
+}
+
+/// Storage format of a fragment file
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FragmentFormat {
+    /// tab-separated text, optionally gzip or bgzip compressed
+    Text,
+    /// written by `fragtk convert --to parquet`
+    Parquet,
+    /// binary `.frag` file written by `fragtk convert --to frag`
+    Frag,
+}
+
+/// Identify the fragment file format from its leading magic bytes
+pub fn detect_format(path: &Path) -> io::Result<FragmentFormat> {
+    if is_stdin(path) {
+        return Ok(FragmentFormat::Text);
+    }
+    let mut magic = [0u8; 4];
+    let mut file = File::open(path)?;
+    let read = file.read(&mut magic)?;
+    Ok(match &magic[..read] {
+        b"PAR1" => FragmentFormat::Parquet,
+        magic if magic == fragfile::MAGIC => FragmentFormat::Frag,
+        _ => FragmentFormat::Text,
+    })
+}
+
+/// Row-by-row access to a binary fragment file. `advance` must return
+/// true before the accessors are called.
+pub trait RecordSource {
+    /// Move to the next record. Returns false at the end of the file.
+    fn advance(&mut self) -> io::Result<bool>;
+
+    /// Skip parts of the file for which `keep(chrom, start, end)` is false,
+    /// for formats with an index. Records that fail `keep` may still be
+    /// returned.
+    fn restrict(&mut self, _keep: &dyn Fn(&str, u32, u32) -> bool) {}
+
+    fn chrom(&self) -> &str;
+    fn barcode(&self) -> &str;
+    fn start(&self) -> u32;
+    fn end(&self) -> u32;
+    fn count(&self) -> u32;
+}
+
+/// Streams parsed fragments from a fragment file, or stdin if the path is
+/// `-`, skipping header lines, excluded chromosomes and lines that fail to
+/// parse. Parquet and `.frag` fragment files written by `fragtk convert`
+/// are read transparently.
+pub struct FragmentStream<'a> {
+    reader: Box<dyn BufRead>,
+    records: Option<Box<dyn RecordSource>>,
+    // used instead of `reader` when the position in the file is needed
+    bgzf: Option<BgzfReader>,
+    buffer: String,
+    chrom_cache: ChromCache,
+    chrom_filter: &'a ChromFilter,
+    barcode_map: Option<&'a BarcodeMap>,
+    line_count: u64,
+}
+
+impl<'a> FragmentStream<'a> {
+    pub fn open(
+        frag_file: &Path,
+        chrom_filter: &'a ChromFilter,
+        barcode_map: Option<&'a BarcodeMap>,
+    ) -> io::Result<Self> {
+        let records: Option<Box<dyn RecordSource>> = match detect_format(frag_file)? {
+            FragmentFormat::Parquet => Some(Box::new(ParquetFragments::open(frag_file)?)),
+            FragmentFormat::Frag => Some(Box::new(FragFileReader::open(frag_file)?)),
+            FragmentFormat::Text => None,
+        };
+        if records.is_some() {
+            return Ok(FragmentStream {
+                reader: Box::new(io::empty()),
+                records,
+                bgzf: None,
+                buffer: String::new(),
+                chrom_cache: ChromCache::default(),
+                chrom_filter,
+                barcode_map,
+                line_count: 0,
+            });
+        }
+        Ok(FragmentStream {
+            reader: open_input(frag_file)?,
+            records: None,
+            bgzf: None,
+            buffer: String::with_capacity(1024),
+            chrom_cache: ChromCache::default(),
+            chrom_filter,
+            barcode_map,
+            line_count: 0,
+        })
+    }
+
+    /// Stream a bgzip-compressed fragment file from `virtual_offset`,
+    /// keeping track of the position so that reading can be resumed
+    pub fn open_bgzf(
+        frag_file: &Path,
+        virtual_offset: u64,
+        chrom_filter: &'a ChromFilter,
+        barcode_map: Option<&'a BarcodeMap>,
+    ) -> io::Result<Self> {
+        Ok(FragmentStream {
+            reader: Box::new(io::empty()),
+            records: None,
+            bgzf: Some(BgzfReader::open(frag_file, virtual_offset)?),
+            buffer: String::with_capacity(1024),
+            chrom_cache: ChromCache::default(),
+            chrom_filter,
+            barcode_map,
+            line_count: 0,
+        })
+    }
+
+    /// BGZF virtual offset of the next unread fragment, for streams opened
+    /// with `open_bgzf`
+    pub fn virtual_offset(&self) -> Option<u64> {
+        self.bgzf.as_ref().map(BgzfReader::virtual_offset)
+    }
+
+    /// Skip blocks of an indexed binary fragment file that can't overlap
+    /// `regions`. Has no effect on other formats, so callers must still
+    /// check each fragment.
+    pub fn restrict_to(&mut self, regions: &RegionIndex) {
+        let chrom_filter = self.chrom_filter;
+        if let Some(records) = self.records.as_mut() {
+            records.restrict(&|chrom, start, end| {
+                chrom_filter.resolve(chrom).is_some_and(|chrom| overlaps(regions, chrom, start, end))
+            });
+        }
+    }
+
+    pub fn next_fragment(&mut self) -> io::Result<Option<Fragment<'_>>> {
+        if self.records.is_some() {
+            return self.next_record_fragment();
+        }
+        Ok(self.next_line()?.map(|line| {
+            let count = line.count();
+            Fragment { chrom: line.chrom, start: line.start, end: line.end, barcode: line.barcode, count }
+        }))
+    }
+
+    /// Read the next usable line of a text fragment file, keeping the line
+    /// so that it can be written out with its extra columns. Binary fragment
+    /// files have no lines to keep, so they are an error here.
+    pub fn next_line(&mut self) -> io::Result<Option<FragmentLine<'_>>> {
+        if self.records.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Expected a text fragment file, Parquet and .frag files can be converted with fragtk convert",
+            ));
+        }
+
+        // find the next usable line, recording the length of its fields so
+        // the result can borrow from the buffer once the loop is done
+        let (lengths, start, end) = loop {
+            if !self.read_line()? {
+                return Ok(None);
+            }
+            let line = self.buffer.trim_end_matches(['\n', '\r']);
+            let mut fields = line.splitn(5, '\t');
+            let (chrom, start_str, end_str, barcode) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(chrom), Some(start), Some(end), Some(barcode)) => (chrom, start, end, barcode),
+                _ => continue,
+            };
+            let (start, end) = match (start_str.parse::<u32>(), end_str.parse::<u32>()) {
+                (Ok(start), Ok(end)) => (start, end),
+                _ => {
+                    warn!("Failed to parse fragment coordinates: {:?}", self.line_count);
+                    continue;
+                }
+            };
+            if self.chrom_cache.resolve(self.chrom_filter, chrom).is_none() {
+                continue;
+            }
+            break ([chrom.len(), start_str.len(), end_str.len(), barcode.len()], start, end);
+        };
+
+        let line = self.buffer.trim_end_matches(['\n', '\r']);
+        let mut fields = [""; 4];
+        let mut offset = 0;
+        for (field, length) in fields.iter_mut().zip(lengths) {
+            *field = &line[offset..offset + length];
+            offset += length + 1;
+        }
+        let chrom = self.chrom_cache
+            .resolve(self.chrom_filter, fields[0])
+            .expect("checked above");
+        let barcode = match self.barcode_map {
+            Some(barcode_map) => barcode_map.translate(fields[3]),
+            None => Cow::Borrowed(fields[3]),
+        };
+
+        Ok(Some(FragmentLine { line, chrom, start, end, barcode, fields, rest: line.get(offset..) }))
+    }
+
+    /// Read the next line that is not a header into `buffer`. Returns false
+    /// at the end of the file.
+    fn read_line(&mut self) -> io::Result<bool> {
+        loop {
+            self.buffer.clear();
+            let read = match self.bgzf.as_mut() {
+                Some(bgzf) => bgzf.read_line(&mut self.buffer)?,
+                None => self.reader.read_line(&mut self.buffer)?,
+            };
+            if read == 0 {
+                finish_progress(self.line_count);
+                return Ok(false);
+            }
+            if !self.buffer.starts_with('#') {
+                count_progress(&mut self.line_count);
+                return Ok(true);
+            }
+        }
+    }
+
+    fn next_record_fragment(&mut self) -> io::Result<Option<Fragment<'_>>> {
+        let records = self.records.as_mut().expect("only called for binary input");
+        loop {
+            if !records.advance()? {
+                finish_progress(self.line_count);
+                return Ok(None);
+            }
+            count_progress(&mut self.line_count);
+            if self.chrom_cache.resolve(self.chrom_filter, records.chrom()).is_some() {
+                break;
+            }
+        }
+        let chrom = self.chrom_cache
+            .resolve(self.chrom_filter, records.chrom())
+            .expect("checked above");
+        let barcode = match self.barcode_map {
+            Some(barcode_map) => barcode_map.translate(records.barcode()),
+            None => Cow::Borrowed(records.barcode()),
+        };
+        Ok(Some(Fragment {
+            chrom,
+            start: records.start(),
+            end: records.end(),
+            barcode,
+            count: records.count(),
+        }))
+    }
+}
+
+/// Count a fragment, reporting progress every million
+fn count_progress(line_count: &mut u64) {
+    *line_count += 1;
+    if *line_count % 1_000_000 == 0 {
+        eprint!("\rProcessed {} M fragments", *line_count / 1_000_000);
+        std::io::stderr().flush().expect("Can't flush stderr");
+    }
+}
+
+fn finish_progress(line_count: u64) {
+    if line_count >= 1_000_000 {
+        eprintln!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    #[test]
+    fn lines_keep_their_columns_and_rename_chromosomes() {
+        let dir = TestDir::new("fragment-lines");
+        let chrom_map = dir.write("chroms.txt", "1\tchr1\n");
+        let fragments = dir.write(
+            "fragments.tsv",
+            "#header\n1\t10\t20\tA\t3\textra\tcolumns\nchr2\tx\t20\tB\t1\nchr2\t5\t15\tB\nchr2\t5\n",
+        );
+        let matches = clap::Command::new("test")
+            .args(crate::chrom_args())
+            .get_matches_from(["test", "--chrom-map", chrom_map.to_str().unwrap(), "--drop-chroms", "chr3"]);
+        let chrom_filter = ChromFilter::from_matches(&matches).unwrap();
+        let mut stream = FragmentStream::open(&fragments, &chrom_filter, None).unwrap();
+
+        let mut output: Vec<u8> = Vec::new();
+        let line = stream.next_line().unwrap().unwrap();
+        assert_eq!((line.chrom, line.start, line.end, line.barcode.as_ref()), ("chr1", 10, 20, "A"));
+        assert_eq!(line.count(), 3);
+        assert_eq!(line.extra(), Some("extra\tcolumns"));
+        line.write(&mut output).unwrap();
+
+        // the line with unparseable coordinates is skipped
+        let line = stream.next_line().unwrap().unwrap();
+        assert_eq!((line.chrom, line.start, line.count(), line.extra()), ("chr2", 5, 1, None));
+        line.write(&mut output).unwrap();
+        assert!(stream.next_line().unwrap().is_none());
+
+        assert_eq!(String::from_utf8(output).unwrap(), "chr1\t10\t20\tA\t3\textra\tcolumns\nchr2\t5\t15\tB\n");
+    }
+}
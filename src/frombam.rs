@@ -0,0 +1,321 @@
+use std::{
+    io,
+    path::Path,
+    error::Error,
+    io::Write,
+    borrow::Cow,
+};
+use flate2::Compression;
+use log::{info, warn};
+use rustc_hash::FxHashMap;
+use crate::bam::{
+    BamReader, BamRecord, FLAG_MATE_UNMAPPED, FLAG_PAIRED, FLAG_PROPER_PAIR,
+    FLAG_QC_FAIL, FLAG_SECONDARY, FLAG_SUPPLEMENTARY, FLAG_UNMAPPED,
+};
+use crate::barcodes::BarcodeMap;
+use crate::bgzf::{BgzfWriter, BLOCK_SIZE};
+use crate::chroms::{ChromCache, ChromFilter};
+use crate::output::deflate_level;
+use crate::tabix::TabixBuilder;
+
+pub fn frombam(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let bam_file = Path::new(matches.get_one::<String>("bam").unwrap());
+    info!("Received BAM file: {:?}", bam_file);
+
+    let output = Path::new(matches.get_one::<String>("output").unwrap());
+    info!("Output file: {:?}", output);
+
+    let tag = matches.get_one::<String>("barcode-tag").unwrap();
+    let tag: [u8; 2] = tag.as_bytes().try_into().map_err(|_| "Barcode tag must be two characters")?;
+
+    let options = PairOptions {
+        tag,
+        min_mapq: *matches.get_one::<u8>("min-mapq").unwrap(),
+        proper_pair: !matches.get_flag("allow-improper"),
+        max_length: *matches.get_one::<u32>("max-length").unwrap(),
+        shift_plus: *matches.get_one::<i64>("shift-plus").unwrap(),
+        shift_minus: *matches.get_one::<i64>("shift-minus").unwrap(),
+    };
+    let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let level = deflate_level(matches)?;
+    let chrom_filter = ChromFilter::from_matches(matches)?;
+    let barcode_map = BarcodeMap::from_matches(matches)?;
+
+    bam_to_fragments(bam_file, output, &options, &chrom_filter, barcode_map.as_ref(), level, num_threads)?;
+
+    Ok(())
+}
+
+/// Pair the reads of a BAM file into Tn5-shifted fragments and write them,
+/// sorted and with duplicate read pairs collapsed, to a bgzip-compressed
+/// and tabix-indexed fragment file
+fn bam_to_fragments(
+    bam_file: &Path,
+    output: &Path,
+    options: &PairOptions,
+    chrom_filter: &ChromFilter,
+    barcode_map: Option<&BarcodeMap>,
+    level: Compression,
+    num_threads: usize,
+) -> io::Result<()> {
+    let mut bam = BamReader::open(bam_file)?;
+    if bam.coordinate_sorted {
+        info!("BAM file is coordinate-sorted, writing fragments one chromosome at a time");
+    } else {
+        info!("BAM file is not coordinate-sorted, holding all fragments in memory");
+    }
+
+    // chromosome names after renaming, None for excluded chromosomes
+    let mut chrom_cache = ChromCache::default();
+    let chroms: Vec<Option<String>> = bam.references.iter()
+        .map(|name| chrom_cache.resolve(chrom_filter, name).map(str::to_string))
+        .collect();
+
+    let mut output_writer = FragmentWriter::create(output, level, num_threads)?;
+    let mut barcodes = BarcodeIndex::default();
+    let mut fragments: Vec<Vec<RawFragment>> = vec![Vec::new(); chroms.len()];
+    // first mate of each pair, waiting for the second: name -> (5' end, barcode)
+    let mut pending: FxHashMap<Vec<u8>, (i64, u32)> = FxHashMap::default();
+
+    let mut record = BamRecord::default();
+    let mut current_ref: i32 = -1;
+    let mut read_count: u64 = 0;
+    let mut pair_count: u64 = 0;
+
+    while bam.next_record(&mut record)? {
+        read_count += 1;
+        if read_count % 1_000_000 == 0 {
+            eprint!("\rProcessed {} M reads", read_count / 1_000_000);
+            std::io::stderr().flush().expect("Can't flush stderr");
+        }
+
+        if bam.coordinate_sorted && record.ref_id != current_ref {
+            if current_ref >= 0 {
+                let finished = current_ref as usize;
+                output_writer.write_chrom(&chroms[finished], &mut fragments[finished], &barcodes)?;
+                // mates of these reads were filtered out or lie on other chromosomes
+                pending.clear();
+            }
+            current_ref = record.ref_id;
+        }
+
+        if !options.passes(&record) {
+            continue;
+        }
+        let ref_id = record.ref_id as usize;
+        if chroms.get(ref_id).is_none_or(|chrom| chrom.is_none()) {
+            continue;
+        }
+        let barcode = match record.string_tag(&options.tag) {
+            Some(barcode) => String::from_utf8_lossy(barcode),
+            None => continue,
+        };
+        let barcode = match barcode_map {
+            Some(barcode_map) => Cow::Owned(barcode_map.translate(&barcode).into_owned()),
+            None => barcode,
+        };
+
+        let five_prime = record.five_prime();
+        let (mate_five_prime, mate_barcode) = match pending.remove(&record.name) {
+            Some(mate) => mate,
+            None => {
+                let barcode = barcodes.intern(&barcode);
+                pending.insert(record.name.clone(), (five_prime, barcode));
+                continue;
+            }
+        };
+        if barcodes.names[mate_barcode as usize] != barcode {
+            continue;
+        }
+
+        let start = five_prime.min(mate_five_prime) + options.shift_plus;
+        let end = five_prime.max(mate_five_prime) - options.shift_minus;
+        if start < 0 || end <= start || end - start > options.max_length as i64 {
+            continue;
+        }
+        fragments[ref_id].push(RawFragment { start: start as u32, end: end as u32, barcode: mate_barcode });
+        pair_count += 1;
+    }
+    eprintln!();
+
+    // remaining chromosomes, in header order
+    for (ref_id, chrom) in chroms.iter().enumerate() {
+        output_writer.write_chrom(chrom, &mut fragments[ref_id], &barcodes)?;
+    }
+    if !pending.is_empty() {
+        warn!("{} reads passed the filters without a mate", pending.len());
+    }
+    info!(
+        "Read {} alignments, found {} read pairs, wrote {} unique fragments",
+        read_count, pair_count, output_writer.written
+    );
+    output_writer.finish(output)
+}
+
+struct PairOptions {
+    // tag holding the cell barcode
+    tag: [u8; 2],
+    min_mapq: u8,
+    proper_pair: bool,
+    max_length: u32,
+    shift_plus: i64,
+    shift_minus: i64,
+}
+
+impl PairOptions {
+    fn passes(&self, record: &BamRecord) -> bool {
+        let excluded = FLAG_UNMAPPED | FLAG_MATE_UNMAPPED | FLAG_SECONDARY | FLAG_QC_FAIL | FLAG_SUPPLEMENTARY;
+        record.flag & FLAG_PAIRED != 0
+            && record.flag & excluded == 0
+            && (!self.proper_pair || record.flag & FLAG_PROPER_PAIR != 0)
+            && record.mapq >= self.min_mapq
+            && record.ref_id >= 0
+            && record.ref_id == record.next_ref_id
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct RawFragment {
+    start: u32,
+    end: u32,
+    barcode: u32,
+}
+
+/// Barcodes numbered in order of first appearance, so that fragments can
+/// be held in memory without a copy of the barcode string
+#[derive(Default)]
+struct BarcodeIndex {
+    names: Vec<String>,
+    index: FxHashMap<String, u32>,
+}
+
+impl BarcodeIndex {
+    fn intern(&mut self, barcode: &str) -> u32 {
+        if let Some(&index) = self.index.get(barcode) {
+            return index;
+        }
+        let index = self.names.len() as u32;
+        self.names.push(barcode.to_string());
+        self.index.insert(barcode.to_string(), index);
+        index
+    }
+}
+
+/// Sorted, bgzip-compressed and tabix-indexed fragment output
+struct FragmentWriter {
+    writer: BgzfWriter,
+    index: TabixBuilder,
+    flush_size: usize,
+    written: u64,
+}
+
+impl FragmentWriter {
+    fn create(path: &Path, level: Compression, num_threads: usize) -> io::Result<Self> {
+        Ok(FragmentWriter {
+            writer: BgzfWriter::create(path, level, num_threads)?,
+            index: TabixBuilder::default(),
+            flush_size: BLOCK_SIZE * 16 * num_threads.max(1),
+            written: 0,
+        })
+    }
+
+    /// Sort the fragments of one chromosome and write each unique fragment
+    /// once, with the number of read pairs supporting it
+    fn write_chrom(&mut self, chrom: &Option<String>, fragments: &mut Vec<RawFragment>, barcodes: &BarcodeIndex) -> io::Result<()> {
+        let chrom = match chrom {
+            Some(chrom) if !fragments.is_empty() => chrom,
+            _ => {
+                fragments.clear();
+                return Ok(());
+            }
+        };
+        fragments.sort_unstable();
+
+        let mut line: Vec<u8> = Vec::with_capacity(128);
+        for duplicates in fragments.chunk_by(|a, b| a == b) {
+            let fragment = &duplicates[0];
+            line.clear();
+            writeln!(
+                line,
+                "{}\t{}\t{}\t{}\t{}",
+                chrom, fragment.start, fragment.end, barcodes.names[fragment.barcode as usize], duplicates.len()
+            )?;
+            let ustart = self.writer.position();
+            self.writer.write_all(&line)?;
+            self.index.add(chrom, fragment.start, fragment.end, ustart, self.writer.position());
+            self.written += 1;
+
+            if self.writer.buffered() >= self.flush_size {
+                self.writer.flush_blocks()?;
+            }
+        }
+        *fragments = Vec::new();
+        Ok(())
+    }
+
+    fn finish(self, path: &Path) -> io::Result<()> {
+        let layout = self.writer.finish()?;
+        if self.index.is_unsorted() {
+            warn!("Fragments are not sorted after chromosome renaming, skipping index");
+            return Ok(());
+        }
+        let mut index_path = path.as_os_str().to_owned();
+        index_path.push(".tbi");
+        self.index.write(Path::new(&index_path), &layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use crate::bam::{write_test_bam, TestRead, FLAG_REVERSE};
+    use crate::input::open_input;
+    use crate::testing::TestDir;
+
+    const PAIR: u16 = FLAG_PAIRED | FLAG_PROPER_PAIR;
+
+    fn read(name: &'static str, pos: i32, reverse: bool, barcode: &'static str) -> TestRead<'static> {
+        let flag = if reverse { PAIR | FLAG_REVERSE } else { PAIR };
+        TestRead { name, ref_id: 0, pos, flag, mapq: 30, cigar: &[(50, 0)], barcode: Some(barcode) }
+    }
+
+    #[test]
+    fn pairs_mates_into_shifted_fragments() {
+        let dir = TestDir::new("frombam");
+        let bam = dir.join("reads.bam");
+        let reads = [
+            read("dup1", 100, false, "A"),
+            read("dup2", 100, false, "A"),
+            read("other", 120, false, "A"),
+            read("mixed", 130, false, "A"),
+            read("single", 140, false, "B"),
+            read("dup1", 250, true, "A"),
+            read("other", 260, true, "B"),
+            read("mixed", 260, true, "B"),
+            read("dup2", 250, true, "A"),
+            read("other", 400, true, "B"),
+        ];
+        let records: Vec<Vec<u8>> = reads.iter().map(TestRead::encode).collect();
+        write_test_bam(&bam, "", &[("chr1", 10_000)], &records);
+
+        let options = PairOptions {
+            tag: *b"CB",
+            min_mapq: 10,
+            proper_pair: true,
+            max_length: 1000,
+            shift_plus: 4,
+            shift_minus: 5,
+        };
+        let output = dir.join("fragments.tsv.gz");
+        bam_to_fragments(&bam, &output, &options, &ChromFilter::default(), None, Compression::default(), 1).unwrap();
+
+        let mut text = String::new();
+        open_input(&output).unwrap().read_to_string(&mut text).unwrap();
+        // the 5' end of a reverse read is its alignment end, so the pair
+        // spans 100-300, shifted by +4 and -5; mates with different
+        // barcodes are not paired
+        assert_eq!(text, "chr1\t104\t295\tA\t2\n");
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    fs::File,
+    io::BufReader,
+    io::BufRead,
+    io::Read,
+};
+use flate2::read::MultiGzDecoder;
+
+/// File name that reads from standard input
+pub const STDIN: &str = "-";
+
+/// Decompressed, buffered fragment input
+pub type FragmentReader = Box<dyn BufRead + Send>;
+
+/// Compression of an input stream, identified from its leading bytes
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    None,
+    /// gzip, including bgzip, which is a series of gzip members
+    Gzip,
+    Zstd,
+}
+
+fn sniff(header: &[u8]) -> Compression {
+    if header.starts_with(&[0x1f, 0x8b]) {
+        Compression::Gzip
+    } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Compression::Zstd
+    } else {
+        Compression::None
+    }
+}
+
+pub fn is_stdin(path: &Path) -> bool {
+    path.as_os_str() == STDIN
+}
+
+/// Resolve an input path given on the command line, leaving `-` for
+/// standard input as it is
+pub fn input_path(value: &str) -> PathBuf {
+    if value == STDIN {
+        return PathBuf::from(STDIN);
+    }
+    Path::new(value)
+        .canonicalize()
+        .expect("Can't find path to input fragment file")
+}
+
+/// Error for subcommands that read the fragment file more than once
+pub fn stdin_unsupported(command: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} reads the fragment file more than once and can't read from stdin", command),
+    )
+}
+
+/// Open a plain text, gzip, bgzip or zstd compressed file, or standard
+/// input if the path is `-`. The compression is detected from the
+/// content rather than the file name.
+pub fn open_input(path: &Path) -> io::Result<FragmentReader> {
+    let source: Box<dyn Read + Send> = if is_stdin(path) {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    let mut reader = BufReader::with_capacity(1024 * 1024, source);
+    let compression = sniff(reader.fill_buf()?);
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(BufReader::with_capacity(1024 * 1024, MultiGzDecoder::new(reader))),
+        Compression::Zstd => Box::new(BufReader::with_capacity(1024 * 1024, zstd::stream::read::Decoder::with_buffer(reader)?)),
+    })
+}
@@ -6,14 +6,155 @@ static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: std::alloc::System = std::alloc::System;
 
-use clap::{Command, Arg, ArgAction};
+use clap::{Command, Arg, ArgAction, ArgGroup};
 use std::error::Error;
 
 mod f2m;
 mod cellselect;
 mod filter;
+mod regions;
+mod chroms;
+mod barcodes;
+mod bgzf;
+mod tabix;
+mod split;
+mod fragments;
+mod bigwig;
+mod coverage;
+mod stats;
+mod peaks;
+mod callpeaks;
+mod peakmerge;
+mod fasta;
+mod background;
+mod motifs;
+mod motifmatrix;
+mod footprint;
+mod bias;
+mod doublets;
+mod multiplets;
+mod dedup;
+mod bam;
+mod frombam;
+mod convert;
+mod columnar;
+mod checkpoint;
+mod append;
+mod fragfile;
+mod input;
+mod output;
+mod features;
+#[cfg(test)]
+mod testing;
 
 
+/// Chromosome renaming and selection options shared by all subcommands
+fn chrom_args() -> Vec<Arg> {
+    vec![
+        Arg::new("chrom-map")
+            .long("chrom-map")
+            .value_name("FILE")
+            .help("Two-column file mapping fragment chromosome names to new names")
+            .long_help(
+                "Two-column, whitespace-separated file mapping chromosome names in the fragment \
+                file to new names (for example 1 -> chr1). Chromosomes are renamed before \
+                any other processing."
+            )
+            .required(false),
+        Arg::new("keep-chroms")
+            .long("keep-chroms")
+            .value_name("PATTERNS")
+            .help("Comma-separated chromosome patterns to keep (supports * and ? wildcards)")
+            .value_delimiter(',')
+            .action(ArgAction::Append)
+            .required(false),
+        Arg::new("drop-chroms")
+            .long("drop-chroms")
+            .value_name("PATTERNS")
+            .help("Comma-separated chromosome patterns to remove, for example chrM,*_random,chrUn_*")
+            .value_delimiter(',')
+            .action(ArgAction::Append)
+            .required(false),
+    ]
+}
+
+/// Barcode translation options for subcommands that match barcodes to a cell list
+fn barcode_args() -> Vec<Arg> {
+    vec![
+        Arg::new("barcode-map")
+            .long("barcode-map")
+            .value_name("FILE")
+            .help("Two-column, tab-separated file mapping fragment barcodes to new names")
+            .long_help(
+                "Two-column, tab-separated file mapping fragment barcodes to new names, \
+                for example to add a sample prefix. Barcodes not present in the file are \
+                unchanged. The cell list should contain the new barcode names."
+            )
+            .required(false),
+        Arg::new("atac-whitelist")
+            .long("atac-whitelist")
+            .value_name("FILE")
+            .help("10x multiome ATAC barcode whitelist, used together with --gex-whitelist")
+            .requires("gex-whitelist")
+            .required(false),
+        Arg::new("gex-whitelist")
+            .long("gex-whitelist")
+            .value_name("FILE")
+            .help("10x multiome GEX barcode whitelist")
+            .long_help(
+                "10x multiome GEX barcode whitelist. ATAC barcodes are translated to the GEX \
+                barcode on the same line, keeping any suffix such as -1. Applied before --barcode-map."
+            )
+            .requires("atac-whitelist")
+            .required(false),
+    ]
+}
+
+/// Output compression options for subcommands that write text files
+fn compression_args(default: &'static str) -> Vec<Arg> {
+    vec![
+        Arg::new("compression")
+            .long("compression")
+            .value_name("FORMAT")
+            .help("Output compression")
+            .long_help(
+                "Output compression. bgzf output can be indexed with tabix, and zstd is \
+                multithreaded and fast to decompress. Files written to an output directory end in .gz \
+                for gzip and bgzf and .zst for zstd, and files named with --output or --outfile are \
+                written to that name."
+            )
+            .value_parser(["gzip", "bgzf", "zstd", "none"])
+            .default_value(default),
+        level_arg(),
+    ]
+}
+
+/// Compression level, shared by subcommands that write bgzip-compressed fragment files
+fn level_arg() -> Arg {
+    Arg::new("level")
+        .long("level")
+        .help("Compression level, 0-9 for gzip and bgzf or 1-22 for zstd")
+        .long_help(
+            "Compression level, 0-9 for gzip and bgzf or 1-22 for zstd. \
+            Defaults to 6 for gzip and bgzf and 3 for zstd."
+        )
+        .value_parser(clap::value_parser!(u32))
+        .required(false)
+}
+
+/// Chromosome sizes file, with `long_help` describing how the sizes are used
+fn genome_arg(long_help: &'static str) -> Arg {
+    Arg::new("genome")
+        .long("genome")
+        .value_name("FILE")
+        .help("Chromosome sizes file (chromosome and length, tab-separated), such as a FASTA index")
+        .long_help(format!(
+            "Chromosome sizes file (chromosome and length, tab-separated), such as a FASTA index. {}",
+            long_help
+        ))
+        .required(false)
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
 
     let matches = Command::new("fragtk")
@@ -51,7 +192,8 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .long("outdir")
                         .help("Output directory name")
                         .long_help("Output directory name. Directory will be created if it does not exist. \
-                               The output directory will contain matrix.mtx.gz, features.tsv, barcodes.tsv")
+                               The output directory will contain matrix.mtx, features.tsv and barcodes.tsv, \
+                               with the --compression extension added to matrix.mtx and features.tsv (e.g. matrix.mtx.gz)")
                         .required(true),
                 )
                 .arg(
@@ -69,6 +211,108 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .help("Group peaks by variable in fourth BED column")
                         .action(ArgAction::SetTrue),
                 )
+                .arg(
+                    Arg::new("group-mode")
+                        .long("group-mode")
+                        .help("How fragments overlapping several intervals of a group are counted")
+                        .long_help(
+                            "How fragments overlapping several intervals of the same group are counted. \
+                            sum adds one count for each overlapping interval. once counts each group at most \
+                            once per fragment, using the largest weight of its overlapping intervals with --weights."
+                        )
+                        .value_parser(["sum", "once"])
+                        .default_value("sum")
+                        .requires("group"),
+                )
+                .arg(
+                    Arg::new("weights")
+                        .long("weights")
+                        .help("Weight each interval by the score in the fifth BED column")
+                        .long_help(
+                            "Weight each interval by the score in the fifth BED column. Each overlap adds the \
+                            interval's score instead of one, and the matrix is written in MatrixMarket real format. \
+                            Lines without a numeric score are skipped."
+                        )
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("binarize"),
+                )
+                .arg(
+                    Arg::new("binarize")
+                        .long("binarize")
+                        .help("Record only whether each cell has a fragment in each feature")
+                        .long_help(
+                            "Record only whether each cell has a fragment in each feature. \
+                            The matrix is written in MatrixMarket pattern format, without a value column."
+                        )
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("fasta")
+                        .long("fasta")
+                        .value_name("FASTA")
+                        .help("Genome FASTA file used to compute feature GC content and background peaks")
+                        .long_help(
+                            "Uncompressed genome FASTA file. If given, the output directory will also contain \
+                            gc_content.tsv.gz, with the GC content of each feature, and background_peaks.tsv.gz, \
+                            with chromVAR-style background features matched on GC content and accessibility. \
+                            A .fai index next to the FASTA file is used if present."
+                        )
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("background-peaks")
+                        .long("background-peaks")
+                        .value_name("N")
+                        .help("Number of background peaks to sample for each feature")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("50")
+                        .requires("fasta"),
+                )
+                .arg(
+                    Arg::new("seed")
+                        .long("seed")
+                        .help("Random seed for background peak selection")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("1")
+                        .requires("fasta"),
+                )
+                .arg(
+                    Arg::new("checkpoint")
+                        .long("checkpoint")
+                        .value_name("MINUTES")
+                        .help("Save partial counts to the output directory every MINUTES minutes")
+                        .long_help(
+                            "Save partial counts and the position in the fragment file to checkpoint.bin \
+                            in the output directory every MINUTES minutes, so that an interrupted run can be \
+                            continued with --resume. Requires a bgzip-compressed fragment file."
+                        )
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("resume")
+                        .long("resume")
+                        .help("Continue from the last checkpoint in the output directory")
+                        .action(ArgAction::SetTrue)
+                        .requires("checkpoint"),
+                )
+                .arg(
+                    Arg::new("append")
+                        .long("append")
+                        .value_name("DIR")
+                        .help("Add new cells to the matrix in an existing output directory")
+                        .long_help(
+                            "Add the cells in --cells that are not already in the matrix in DIR, a previous \
+                            matrix output directory. Only the new cells are counted, and their columns are added \
+                            after the existing ones. The BED file, --group and --binarize must match the \
+                            existing matrix. The output directory may be DIR itself."
+                        )
+                        .required(false)
+                        .conflicts_with("fasta"),
+                )
+                .args(chrom_args())
+                .args(barcode_args())
+                .args(compression_args("gzip"))
         )
         .subcommand(
             Command::new("count")
@@ -105,13 +349,200 @@ fn main() -> Result<(), Box<dyn Error>> {
                     )
                     .default_value("200"),
             )
+            .args(chrom_args())
+            .args(compression_args("none"))
         )
         .subcommand(
             Command::new("filter")
                 .about(
-                    "Subset a fragment file to include only specified cell barcodes. \
-                    Output is uncompressed data written to stdout"
+                    "Subset a fragment file to include only specified cell barcodes or regions. \
+                    Output is written to stdout, uncompressed unless --compression is given"
+                )
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("cells")
+                        .short('c')
+                        .long("cells")
+                        .help("File containing cell barcodes to include")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("regions")
+                        .long("regions")
+                        .value_name("BED")
+                        .help("Keep only fragments overlapping regions in this BED file")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("exclude")
+                        .long("exclude")
+                        .value_name("BED")
+                        .help("Remove fragments overlapping regions in this BED file")
+                        .long_help(
+                            "Remove fragments overlapping regions in this BED file, \
+                            for example the ENCODE blacklist regions."
+                        )
+                        .required(false),
+                )
+                .group(
+                    ArgGroup::new("criteria")
+                        .args(["cells", "regions", "exclude", "chrom-map", "keep-chroms", "drop-chroms", "barcode-map", "atac-whitelist", "gex-whitelist"])
+                        .multiple(true)
+                        .required(true),
+                )
+                .args(chrom_args())
+                .args(barcode_args())
+                .arg(
+                    Arg::new("threads")
+                        .short('t')
+                        .long("threads")
+                        .help("Number of compression threads to use")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("4")
+                        .required(false),
+                )
+                .args(compression_args("none"))
+        )
+        .subcommand(
+            Command::new("split")
+                .about("Split a fragment file into one bgzip-compressed, indexed file per cell group")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("groups")
+                        .short('g')
+                        .long("groups")
+                        .value_name("FILE")
+                        .help("Two-column, tab-separated file of cell barcode and group name")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("outdir")
+                        .short('o')
+                        .long("outdir")
+                        .help("Output directory name")
+                        .long_help("Output directory name. Directory will be created if it does not exist. \
+                               The output directory will contain <group>.fragments.tsv.gz and its tabix index for each group")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("threads")
+                        .short('t')
+                        .long("threads")
+                        .help("Number of compression threads to use")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("4")
+                        .required(false),
+                )
+                .args(chrom_args())
+                .args(barcode_args())
+                .arg(level_arg())
+        )
+        .subcommand(
+            Command::new("coverage")
+                .about("Create pseudobulk coverage tracks for each cell group")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the fragment file")
+                        .required(true),
                 )
+                .arg(
+                    Arg::new("groups")
+                        .short('g')
+                        .long("groups")
+                        .value_name("FILE")
+                        .help("Two-column, tab-separated file of cell barcode and group name")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("outdir")
+                        .short('o')
+                        .long("outdir")
+                        .help("Output directory name")
+                        .long_help("Output directory name. Directory will be created if it does not exist. \
+                               The output directory will contain one <group>.bw or <group>.bedGraph.gz file per group")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("bin")
+                        .long("bin")
+                        .value_name("BP")
+                        .help("Bin size in base pairs")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("normalize")
+                        .long("normalize")
+                        .help("Normalization method")
+                        .long_help(
+                            "Normalization method. cpm scales each group to counts per million \
+                            insertions (or fragments with --mode fragments) in the group."
+                        )
+                        .value_parser(["cpm", "none"])
+                        .default_value("cpm"),
+                )
+                .arg(
+                    Arg::new("mode")
+                        .long("mode")
+                        .help("Count Tn5 insertion sites or whole fragments")
+                        .value_parser(["insertions", "fragments"])
+                        .default_value("insertions"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .help("Output track format")
+                        .value_parser(["bigwig", "bedgraph"])
+                        .default_value("bigwig"),
+                )
+                .arg(genome_arg(
+                    "If not provided, the largest fragment end on each chromosome is used."
+                ))
+                .arg(
+                    Arg::new("bias")
+                        .long("bias")
+                        .value_name("FILE")
+                        .help("Tn5 bias table from fragtk bias, used to weight each insertion by its inverse bias")
+                        .requires("fasta")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("fasta")
+                        .long("fasta")
+                        .value_name("FASTA")
+                        .help("Uncompressed genome FASTA file, required with --bias")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("threads")
+                        .short('t')
+                        .long("threads")
+                        .help("Number of compression threads to use")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("4")
+                        .required(false),
+                )
+                .args(chrom_args())
+                .args(barcode_args())
+                .args(compression_args("gzip"))
+        )
+        .subcommand(
+            Command::new("callpeaks")
+                .about("Call peaks for each cell group from Tn5 insertion sites")
                 .arg(
                     Arg::new("fragments")
                         .short('f')
@@ -119,6 +550,97 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .help("Path to the fragment file")
                         .required(true),
                 )
+                .arg(
+                    Arg::new("groups")
+                        .short('g')
+                        .long("groups")
+                        .value_name("FILE")
+                        .help("Two-column, tab-separated file of cell barcode and group name")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("outdir")
+                        .short('o')
+                        .long("outdir")
+                        .help("Output directory name")
+                        .long_help("Output directory name. Directory will be created if it does not exist. \
+                               The output directory will contain <group>_peaks.narrowPeak for each group \
+                               and peaks.bed, a merged set of fixed-width, non-overlapping peaks")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("genome-size")
+                        .long("genome-size")
+                        .value_name("SIZE")
+                        .help("Effective genome size, or hs (2.7e9) or mm (1.87e9)")
+                        .default_value("hs"),
+                )
+                .arg(
+                    Arg::new("pvalue")
+                        .short('p')
+                        .long("pvalue")
+                        .help("P-value cutoff for peak detection")
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("0.01"),
+                )
+                .arg(
+                    Arg::new("extsize")
+                        .long("extsize")
+                        .value_name("BP")
+                        .help("Width of the window centered on each insertion site")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("150"),
+                )
+                .arg(
+                    Arg::new("llocal")
+                        .long("llocal")
+                        .value_name("BP")
+                        .help("Window size used to estimate the local background")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("10000"),
+                )
+                .arg(
+                    Arg::new("width")
+                        .long("width")
+                        .value_name("BP")
+                        .help("Width of the summit-centered peaks in the merged peak set")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("501"),
+                )
+                .arg(genome_arg(
+                    "Merged peaks are trimmed to the end of their chromosome, and peaks with a summit beyond \
+                    the end are dropped."
+                ))
+                .arg(
+                    Arg::new("threads")
+                        .short('t')
+                        .long("threads")
+                        .help("Number of threads to use")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("4")
+                        .required(false),
+                )
+                .args(chrom_args())
+                .args(barcode_args())
+                .args(compression_args("none"))
+        )
+        .subcommand(
+            Command::new("motifmatrix")
+                .about("Create a motif x cell matrix by summing counts over peaks containing each motif")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("bed")
+                        .short('b')
+                        .long("bed")
+                        .help("BED file containing peaks to scan for motifs")
+                        .required(true),
+                )
                 .arg(
                     Arg::new("cells")
                         .short('c')
@@ -126,6 +648,476 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .help("File containing cell barcodes to include")
                         .required(true),
                 )
+                .arg(
+                    Arg::new("motifs")
+                        .short('m')
+                        .long("motifs")
+                        .value_name("MEME")
+                        .help("Motif position frequency matrices in MEME format, e.g. from JASPAR")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("fasta")
+                        .long("fasta")
+                        .value_name("FASTA")
+                        .help("Uncompressed genome FASTA file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("outdir")
+                        .short('o')
+                        .long("outdir")
+                        .help("Output directory name")
+                        .long_help("Output directory name. Directory will be created if it does not exist. \
+                               The output directory will contain matrix.mtx, features.tsv and barcodes.tsv, \
+                               with the --compression extension added to matrix.mtx and features.tsv (e.g. matrix.mtx.gz)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("threshold")
+                        .long("threshold")
+                        .help("Minimum motif match score, relative to the range of possible scores")
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("0.85"),
+                )
+                .arg(
+                    Arg::new("threads")
+                        .short('t')
+                        .long("threads")
+                        .help("Number of threads to use")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("4")
+                        .required(false),
+                )
+                .args(chrom_args())
+                .args(barcode_args())
+                .args(compression_args("gzip"))
+        )
+        .subcommand(
+            Command::new("footprint")
+                .about("Aggregate Tn5 insertion profiles around motif sites for each cell group")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("sites")
+                        .short('s')
+                        .long("sites")
+                        .value_name("BED")
+                        .help("BED file of motif sites, with strand in the sixth column")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("groups")
+                        .short('g')
+                        .long("groups")
+                        .value_name("FILE")
+                        .help("Two-column, tab-separated file of cell barcode and group name")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("fasta")
+                        .long("fasta")
+                        .value_name("FASTA")
+                        .help("Uncompressed genome FASTA file, used to estimate the Tn5 sequence bias")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .help("Output file name")
+                        .long_help("Output file name. A tab-separated table of group, position relative to the \
+                               site center, observed insertions and bias-expected insertions")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("flank")
+                        .long("flank")
+                        .value_name("BP")
+                        .help("Number of bases either side of the site center to include")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("250"),
+                )
+                .arg(
+                    Arg::new("bias")
+                        .long("bias")
+                        .value_name("FILE")
+                        .help("Tn5 bias table from fragtk bias, used instead of estimating the bias around the sites")
+                        .required(false),
+                )
+                .args(chrom_args())
+                .args(barcode_args())
+                .args(compression_args("none"))
+        )
+        .subcommand(
+            Command::new("bias")
+                .about("Estimate Tn5 insertion sequence bias from a fragment file")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("fasta")
+                        .long("fasta")
+                        .value_name("FASTA")
+                        .help("Uncompressed genome FASTA file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .help("Output file name")
+                        .long_help("Output file name. A tab-separated table of k-mer, observed count at insertion \
+                               sites, expected count in the genome and bias")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("kmer")
+                        .short('k')
+                        .long("kmer")
+                        .help("Length of the k-mer centered on each insertion site (even, at most 10)")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("6"),
+                )
+                .args(chrom_args())
+                .args(barcode_args())
+                .args(compression_args("none"))
+        )
+        .subcommand(
+            Command::new("doublets")
+                .about("Detect doublets from loci with more than two overlapping fragments in a cell")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the position-sorted fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("cells")
+                        .short('c')
+                        .long("cells")
+                        .help("File containing cell barcodes to score")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .help("Output file name")
+                        .long_help("Output file name. A tab-separated table of barcode, fragment count, number of loci \
+                               with more than two overlapping fragments, p-value, q-value and doublet call")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("exclude")
+                        .long("exclude")
+                        .value_name("BED")
+                        .help("Ignore fragments overlapping regions in this BED file, such as repeats or blacklist regions")
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("q-value")
+                        .long("q-value")
+                        .help("Benjamini-Hochberg q-value below which cells are called doublets")
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("0.01"),
+                )
+                .args(chrom_args())
+                .args(barcode_args())
+                .args(compression_args("none"))
+        )
+        .subcommand(
+            Command::new("multiplets")
+                .about("Find barcodes that share identical fragments, such as multiple barcodes on one bead")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the position-sorted fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("outdir")
+                        .short('o')
+                        .long("outdir")
+                        .help("Output directory name")
+                        .long_help("Output directory name. Directory will be created if it does not exist. \
+                               The output directory will contain pairs.tsv, multiplets.tsv and, with --rewrite, \
+                               fragments.tsv.gz. The .tsv file names follow --compression")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("cells")
+                        .short('c')
+                        .long("cells")
+                        .help("File containing cell barcodes to test. By default, barcodes with at least --min-fragments are tested")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("min-fragments")
+                        .long("min-fragments")
+                        .help("Minimum number of fragments for a barcode to be tested, if no cell file is given")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("200"),
+                )
+                .arg(
+                    Arg::new("max-barcodes")
+                        .long("max-barcodes")
+                        .help("Ignore fragments found in more than this number of barcodes")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("6"),
+                )
+                .arg(
+                    Arg::new("min-shared")
+                        .long("min-shared")
+                        .help("Minimum number of identical fragments for a barcode pair to be linked")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("5"),
+                )
+                .arg(
+                    Arg::new("q-value")
+                        .long("q-value")
+                        .help("Benjamini-Hochberg q-value below which a barcode pair is linked")
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("0.01"),
+                )
+                .arg(
+                    Arg::new("rewrite")
+                        .long("rewrite")
+                        .help("Write a fragment file with each multiplet merged into its barcode with the most fragments")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("threads")
+                        .short('t')
+                        .long("threads")
+                        .help("Number of compression threads to use")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("4")
+                        .required(false),
+                )
+                .args(chrom_args())
+                .args(barcode_args())
+                .args(compression_args("none"))
+        )
+        .subcommand(
+            Command::new("dedup")
+                .about("Collapse duplicate fragments, summing their read counts")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the position-sorted fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .help("Output file name")
+                        .long_help("Output file name. The output is bgzip-compressed and indexed with tabix \
+                               if the input is sorted")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("max-barcodes")
+                        .long("max-barcodes")
+                        .help("Remove fragments found in more than this number of barcodes")
+                        .long_help(
+                            "Remove fragments found in more than this number of barcodes. Identical \
+                            fragments in many barcodes suggest ambient DNA or index hopping."
+                        )
+                        .value_parser(clap::value_parser!(usize))
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("threads")
+                        .short('t')
+                        .long("threads")
+                        .help("Number of compression threads to use")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("4")
+                        .required(false),
+                )
+                .args(chrom_args())
+                .args(barcode_args())
+                .arg(level_arg())
+        )
+        .subcommand(
+            Command::new("frombam")
+                .visible_alias("fromBAM")
+                .about("Create a fragment file from a paired-end BAM file with cell barcode tags")
+                .arg(
+                    Arg::new("bam")
+                        .short('b')
+                        .long("bam")
+                        .help("Path to the coordinate- or name-sorted BAM file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .help("Output file name")
+                        .long_help("Output file name. The output is sorted, bgzip-compressed and indexed with tabix")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("barcode-tag")
+                        .long("barcode-tag")
+                        .help("Alignment tag holding the cell barcode")
+                        .default_value("CB"),
+                )
+                .arg(
+                    Arg::new("min-mapq")
+                        .long("min-mapq")
+                        .help("Minimum mapping quality of both reads")
+                        .value_parser(clap::value_parser!(u8))
+                        .default_value("30"),
+                )
+                .arg(
+                    Arg::new("allow-improper")
+                        .long("allow-improper")
+                        .help("Keep read pairs that are not flagged as properly paired")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("max-length")
+                        .long("max-length")
+                        .help("Maximum fragment length")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("5000"),
+                )
+                .arg(
+                    Arg::new("shift-plus")
+                        .long("shift-plus")
+                        .help("Shift applied to the fragment start to give the Tn5 insertion site")
+                        .value_parser(clap::value_parser!(i64))
+                        .allow_hyphen_values(true)
+                        .default_value("4"),
+                )
+                .arg(
+                    Arg::new("shift-minus")
+                        .long("shift-minus")
+                        .help("Shift subtracted from the fragment end to give the Tn5 insertion site")
+                        .value_parser(clap::value_parser!(i64))
+                        .allow_hyphen_values(true)
+                        .default_value("5"),
+                )
+                .arg(
+                    Arg::new("threads")
+                        .short('t')
+                        .long("threads")
+                        .help("Number of compression threads to use")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("4")
+                        .required(false),
+                )
+                .args(chrom_args())
+                .args(barcode_args())
+                .arg(level_arg())
+        )
+        .subcommand(
+            Command::new("convert")
+                .about(
+                    "Convert a fragment file to BED, BEDPE or Tn5 insertion site BED. \
+                    Output is written to stdout, uncompressed unless --compression is given"
+                )
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .help("Output format")
+                        .long_help(
+                            "Output format. insertions writes one 1 bp interval per Tn5 insertion site, \
+                            bedpe writes the two insertion sites of each fragment as a pair, and bed6 writes \
+                            each fragment as an interval. The barcode is used as the name and the read count as the score. \
+                            parquet writes a columnar fragment file and frag a compact binary fragment file with a \
+                            block index, both of which matrix, count and filter read directly."
+                        )
+                        .value_parser(["insertions", "bedpe", "bed6", "parquet", "frag"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .help("Output file name, required for --to parquet and --to frag")
+                        .required_if_eq_any([("to", "parquet"), ("to", "frag")]),
+                )
+                .args(chrom_args())
+                .args(barcode_args())
+                .arg(
+                    Arg::new("threads")
+                        .short('t')
+                        .long("threads")
+                        .help("Number of compression threads to use")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("4")
+                        .required(false),
+                )
+                .args(compression_args("none"))
+        )
+        .subcommand(
+            Command::new("peaks")
+                .about("Operations on peak sets")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("merge")
+                        .about(
+                            "Merge narrowPeak or BED files into a non-overlapping set of \
+                            fixed-width peaks by iterative overlap removal"
+                        )
+                        .arg(
+                            Arg::new("input")
+                                .short('i')
+                                .long("input")
+                                .value_name("FILE")
+                                .help("Peak files in narrowPeak or BED format")
+                                .num_args(1..)
+                                .action(ArgAction::Append)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .short('o')
+                                .long("output")
+                                .value_name("BED")
+                                .help("Output BED file")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("width")
+                                .long("width")
+                                .value_name("BP")
+                                .help("Width of the summit-centered peaks")
+                                .value_parser(clap::value_parser!(u32))
+                                .default_value("501"),
+                        )
+                        .arg(genome_arg(
+                            "Merged peaks are trimmed to the end of their chromosome, and peaks with a summit beyond \
+                            the end are dropped."
+                        ))
+                        .args(chrom_args())
+                        .args(compression_args("none"))
+                )
         )
         .get_matches();
 
@@ -135,6 +1127,22 @@ fn main() -> Result<(), Box<dyn Error>> {
         Some(("matrix", sub_matches)) => f2m::f2m(sub_matches)?,
         Some(("count", sub_matches)) => cellselect::cellselect(sub_matches)?,
         Some(("filter", sub_matches)) => filter::run(sub_matches)?,
+        Some(("split", sub_matches)) => split::split(sub_matches)?,
+        Some(("coverage", sub_matches)) => coverage::coverage(sub_matches)?,
+        Some(("callpeaks", sub_matches)) => callpeaks::callpeaks(sub_matches)?,
+        Some(("motifmatrix", sub_matches)) => motifmatrix::motifmatrix(sub_matches)?,
+        Some(("footprint", sub_matches)) => footprint::footprint(sub_matches)?,
+        Some(("bias", sub_matches)) => bias::bias(sub_matches)?,
+        Some(("doublets", sub_matches)) => doublets::doublets(sub_matches)?,
+        Some(("multiplets", sub_matches)) => multiplets::multiplets(sub_matches)?,
+        Some(("dedup", sub_matches)) => dedup::dedup(sub_matches)?,
+        Some(("frombam", sub_matches)) => frombam::frombam(sub_matches)?,
+        Some(("convert", sub_matches)) => convert::convert(sub_matches)?,
+        Some(("peaks", sub_matches)) => {
+            if let Some(("merge", merge_matches)) = sub_matches.subcommand() {
+                peakmerge::merge(merge_matches)?
+            }
+        }
         _ => {
 
         }
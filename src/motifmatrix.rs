@@ -0,0 +1,166 @@
+use std::{
+    io,
+    fs,
+    path::Path,
+    error::Error,
+    io::Write,
+    thread,
+};
+use log::{info, warn};
+use rustc_hash::FxHashMap;
+use crate::barcodes::BarcodeMap;
+use crate::chroms::ChromFilter;
+use crate::f2m::{count_fragments, load_cells, write_cells, write_matrix_market, FeatureCounts};
+use crate::fasta::Fasta;
+use crate::input::input_path;
+use crate::motifs::{encode, read_meme, MotifScanner};
+use crate::output::OutputCompression;
+use crate::regions::{load_regions, RegionIndex};
+
+// number of peak sequences held in memory for scanning at once
+const SCAN_BATCH: usize = 10_000;
+
+pub fn motifmatrix(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = input_path(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+
+    let bed_file = Path::new(matches.get_one::<String>("bed").unwrap());
+    info!("Received BED file: {:?}", bed_file);
+
+    let cell_file = Path::new(matches.get_one::<String>("cells").unwrap());
+    info!("Received cell file: {:?}", cell_file);
+
+    let motif_file = Path::new(matches.get_one::<String>("motifs").unwrap());
+    info!("Received motif file: {:?}", motif_file);
+
+    let fasta_file = Path::new(matches.get_one::<String>("fasta").unwrap());
+    info!("Received FASTA file: {:?}", fasta_file);
+
+    let output_path = Path::new(matches.get_one::<String>("outdir").unwrap());
+    info!("Received output directory: {:?}", output_path);
+    fs::create_dir_all(output_path)?;
+
+    let threshold = *matches.get_one::<f64>("threshold").unwrap();
+    if !(0.0..=1.0).contains(&threshold) {
+        return Err("Motif score threshold must be between 0 and 1".into());
+    }
+    let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let chrom_filter = ChromFilter::from_matches(matches)?;
+    let barcode_map = BarcodeMap::from_matches(matches)?;
+    let compression = OutputCompression::from_matches(matches, num_threads)?;
+
+    let motifs = read_meme(motif_file)?;
+    if motifs.is_empty() {
+        return Err("No motifs found in motif file".into());
+    }
+    info!("Read {} motifs", motifs.len());
+    let scanners: Vec<MotifScanner> = motifs.iter()
+        .map(|motif| MotifScanner::new(motif, threshold))
+        .collect();
+
+    let mut peaks = load_regions(bed_file)?;
+    let total_peaks: usize = peaks.values().map(|lapper| lapper.intervals.len()).sum();
+
+    info!("Scanning {} peaks for motif matches", total_peaks);
+    let mut fasta = Fasta::open(fasta_file)?;
+    let peak_motifs = scan_peaks(&mut fasta, &peaks, total_peaks, &scanners, num_threads)?;
+    let with_match = peak_motifs.iter().filter(|motifs| !motifs.is_empty()).count();
+    info!("{} of {} peaks contain at least one motif match", with_match, total_peaks);
+
+    let (barcodes, cells) = load_cells(cell_file)?;
+    let mut counts = FeatureCounts::new(total_peaks, false);
+    count_fragments(&frag_file, &mut peaks, None, &mut counts, &cells, &chrom_filter, barcode_map.as_ref(), None)?;
+    let peak_counts = match counts {
+        FeatureCounts::Counts(peak_counts) => peak_counts,
+        FeatureCounts::Binary(_) | FeatureCounts::Weighted(_) => {
+            return Err("Motif counts must be integer counts, not binarized or weighted".into());
+        }
+    };
+
+    // sum the counts of all peaks containing each motif
+    let mut motif_counts: Vec<FxHashMap<u32, u32>> = vec![FxHashMap::default(); motifs.len()];
+    for (peak, peak_motifs) in peak_motifs.iter().enumerate() {
+        for &motif in peak_motifs {
+            for (&cell, &count) in peak_counts[peak].iter() {
+                *motif_counts[motif as usize].entry(cell).or_insert(0) += count;
+            }
+        }
+    }
+
+    let feature_path = output_path.join(compression.file_name("features.tsv"));
+    info!("Writing output feature file: {:?}", &feature_path);
+    let mut writer = compression.create(&feature_path)?;
+    for motif in motifs.iter() {
+        writeln!(writer, "{}", motif.name)?;
+    }
+    writer.finish()?;
+
+    let counts_path = output_path.join(compression.file_name("matrix.mtx"));
+    info!("Writing output counts file: {:?}", &counts_path);
+    write_matrix_market(&counts_path, &FeatureCounts::Counts(motif_counts), motifs.len(), cells.len(), &compression)?;
+
+    let cell_path = output_path.join("barcodes.tsv");
+    info!("Writing output cells file: {:?}", &cell_path);
+    write_cells(&cell_path, &barcodes)?;
+
+    Ok(())
+}
+
+/// Find the motifs matching each peak. Sequences are read in batches and
+/// each batch is scanned in parallel.
+fn scan_peaks(
+    fasta: &mut Fasta,
+    peaks: &RegionIndex,
+    total_peaks: usize,
+    scanners: &[MotifScanner],
+    num_threads: usize,
+) -> io::Result<Vec<Vec<u32>>> {
+    let mut peak_motifs: Vec<Vec<u32>> = vec![Vec::new(); total_peaks];
+
+    let mut intervals: Vec<(&str, u32, u32, usize)> = Vec::with_capacity(total_peaks);
+    for (chrom, lapper) in peaks.iter() {
+        if !fasta.contains(chrom) {
+            warn!("Chromosome {} not found in FASTA file, its peaks will have no motif matches", chrom);
+            continue;
+        }
+        intervals.extend(lapper.intervals.iter().map(|interval| (chrom.as_str(), interval.start, interval.stop, interval.val)));
+    }
+
+    let mut sequences: Vec<(usize, Vec<u8>)> = Vec::with_capacity(SCAN_BATCH);
+    for batch in intervals.chunks(SCAN_BATCH) {
+        sequences.clear();
+        for &(chrom, start, end, peak) in batch {
+            let mut sequence: Vec<u8> = Vec::with_capacity((end - start) as usize);
+            fasta.fetch(chrom, start as u64, end as u64, &mut sequence)?;
+            encode(&mut sequence);
+            sequences.push((peak, sequence));
+        }
+
+        let per_thread = sequences.len().div_ceil(num_threads.max(1)).max(1);
+        let results: Vec<Vec<(usize, Vec<u32>)>> = thread::scope(|scope| {
+            let handles: Vec<_> = sequences.chunks(per_thread)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk.iter()
+                            .map(|(peak, sequence)| {
+                                let matched: Vec<u32> = scanners.iter()
+                                    .enumerate()
+                                    .filter(|(_, scanner)| scanner.matches(sequence))
+                                    .map(|(motif, _)| motif as u32)
+                                    .collect();
+                                (*peak, matched)
+                            })
+                            .collect()
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().expect("motif scanning thread panicked")).collect()
+        });
+        for (peak, matched) in results.into_iter().flatten() {
+            peak_motifs[peak] = matched;
+        }
+    }
+
+    Ok(peak_motifs)
+}
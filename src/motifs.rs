@@ -0,0 +1,268 @@
+use std::{
+    io,
+    path::Path,
+    io::BufRead,
+};
+use crate::input::open_input;
+
+// added to each letter probability before taking log odds
+const PSEUDOCOUNT: f64 = 0.001;
+
+/// A position weight matrix of log2 odds scores, one row per position
+/// with columns in A, C, G, T order
+pub struct Motif {
+    pub name: String,
+    weights: Vec<[f64; 4]>,
+}
+
+impl Motif {
+    fn from_probabilities(name: String, probabilities: &[[f64; 4]], background: &[f64; 4]) -> Self {
+        let weights = probabilities.iter()
+            .map(|row| {
+                let total: f64 = row.iter().map(|p| p + PSEUDOCOUNT).sum();
+                let mut weights = [0.0; 4];
+                for base in 0..4 {
+                    weights[base] = ((row[base] + PSEUDOCOUNT) / total / background[base]).log2();
+                }
+                weights
+            })
+            .collect();
+        Motif { name, weights }
+    }
+
+    /// Matrix for the reverse complement strand
+    fn reverse_complement(&self) -> Vec<[f64; 4]> {
+        self.weights.iter()
+            .rev()
+            .map(|row| [row[3], row[2], row[1], row[0]])
+            .collect()
+    }
+}
+
+/// Read motifs from a MEME format file, such as those distributed by JASPAR.
+/// Motif names combine the identifier and alternate name, e.g. MA0004.1_Arnt.
+pub fn read_meme(path: &Path) -> io::Result<Vec<Motif>> {
+    let reader = open_input(path)?;
+    let invalid = |line: usize, message: &str| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{:?} line {}: {}", path, line, message))
+    };
+
+    let mut background = [0.25; 4];
+    let mut motifs: Vec<Motif> = Vec::new();
+    let mut name: Option<String> = None;
+    let mut rows: Vec<[f64; 4]> = Vec::new();
+    // rows remaining in the current letter-probability matrix
+    let mut remaining: usize = 0;
+    let mut background_next = false;
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let trimmed = line.trim();
+
+        if remaining > 0 {
+            if trimmed.is_empty() {
+                continue;
+            }
+            let values: Vec<f64> = trimmed.split_whitespace().filter_map(|value| value.parse().ok()).collect();
+            if values.len() != 4 {
+                return Err(invalid(index + 1, "expected four letter probabilities"));
+            }
+            rows.push([values[0], values[1], values[2], values[3]]);
+            remaining -= 1;
+            if remaining == 0 {
+                let motif_name = name.take().unwrap_or_default();
+                motifs.push(Motif::from_probabilities(motif_name, &rows, &background));
+                rows.clear();
+            }
+            continue;
+        }
+
+        if background_next && !trimmed.is_empty() {
+            // e.g. "A 0.25 C 0.25 G 0.25 T 0.25"
+            let fields: Vec<&str> = trimmed.split_whitespace().collect();
+            for pair in fields.chunks(2) {
+                if let [letter, value] = pair {
+                    let base = match *letter {
+                        "A" => 0,
+                        "C" => 1,
+                        "G" => 2,
+                        "T" => 3,
+                        _ => continue,
+                    };
+                    background[base] = value.parse().map_err(|_| invalid(index + 1, "invalid background frequency"))?;
+                }
+            }
+            background_next = false;
+        } else if trimmed.starts_with("Background letter frequencies") {
+            background_next = true;
+        } else if let Some(rest) = trimmed.strip_prefix("MOTIF") {
+            let fields: Vec<&str> = rest.split_whitespace().collect();
+            name = match fields.as_slice() {
+                [] => return Err(invalid(index + 1, "motif without a name")),
+                [id] => Some(id.to_string()),
+                [id, alt, ..] => Some(format!("{}_{}", id, alt)),
+            };
+        } else if trimmed.starts_with("letter-probability matrix") {
+            if name.is_none() {
+                return Err(invalid(index + 1, "matrix without a MOTIF line"));
+            }
+            remaining = trimmed.split_whitespace()
+                .skip_while(|field| *field != "w=")
+                .nth(1)
+                .and_then(|width| width.parse().ok())
+                .ok_or_else(|| invalid(index + 1, "missing motif width"))?;
+            if remaining == 0 {
+                return Err(invalid(index + 1, "motif width is zero"));
+            }
+        }
+    }
+
+    if remaining > 0 {
+        return Err(invalid(0, "file ends inside a letter-probability matrix"));
+    }
+    Ok(motifs)
+}
+
+/// Motif matrices prepared for scanning, with a score threshold and the
+/// best score achievable from each position onwards
+pub struct MotifScanner {
+    strands: [Vec<[f64; 4]>; 2],
+    best_suffix: [Vec<f64>; 2],
+    threshold: f64,
+}
+
+impl MotifScanner {
+    /// `relative` is the threshold as a fraction of the score range, so 0
+    /// accepts any site and 1 only the best scoring sequence
+    pub fn new(motif: &Motif, relative: f64) -> Self {
+        let minimum: f64 = motif.weights.iter().map(|row| row.iter().cloned().fold(f64::INFINITY, f64::min)).sum();
+        let maximum: f64 = motif.weights.iter().map(|row| row.iter().cloned().fold(f64::NEG_INFINITY, f64::max)).sum();
+        let strands = [motif.weights.clone(), motif.reverse_complement()];
+        let best_suffix = [suffix_maximum(&strands[0]), suffix_maximum(&strands[1])];
+        MotifScanner {
+            strands,
+            best_suffix,
+            threshold: minimum + relative * (maximum - minimum),
+        }
+    }
+
+    /// Whether the motif scores above the threshold anywhere on either strand
+    /// of an encoded sequence (see `encode`). Windows containing N are skipped.
+    pub fn matches(&self, sequence: &[u8]) -> bool {
+        let width = self.strands[0].len();
+        if sequence.len() < width {
+            return false;
+        }
+        for start in 0..=sequence.len() - width {
+            let window = &sequence[start..start + width];
+            for (weights, best_suffix) in self.strands.iter().zip(self.best_suffix.iter()) {
+                let mut score = 0.0;
+                let mut position = 0;
+                while position < width {
+                    let base = window[position];
+                    if base > 3 {
+                        break;
+                    }
+                    score += weights[position][base as usize];
+                    position += 1;
+                    // stop once the threshold is out of reach
+                    if score + best_suffix[position] < self.threshold {
+                        break;
+                    }
+                }
+                if position == width && score >= self.threshold {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Best possible score from each position to the end of the matrix
+fn suffix_maximum(weights: &[[f64; 4]]) -> Vec<f64> {
+    let mut best = vec![0.0; weights.len() + 1];
+    for position in (0..weights.len()).rev() {
+        let row_max = weights[position].iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        best[position] = best[position + 1] + row_max;
+    }
+    best
+}
+
+/// Convert uppercase bases to 0-3 for A, C, G, T and 4 for anything else
+pub fn encode(sequence: &mut [u8]) {
+    for base in sequence.iter_mut() {
+        *base = match *base {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => 4,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    const MEME: &str = "MEME version 4
+
+ALPHABET= ACGT
+
+Background letter frequencies
+A 0.3 C 0.2 G 0.2 T 0.3
+
+MOTIF MA0001.1 FIRST
+letter-probability matrix: alength= 4 w= 3 nsites= 20 E= 0
+ 1.0 0.0 0.0 0.0
+ 0.0 1.0 0.0 0.0
+
+ 0.0 0.0 1.0 0.0
+
+MOTIF MA0002.1
+letter-probability matrix: alength= 4 w= 1
+ 0.25 0.25 0.25 0.25
+";
+
+    fn encoded(sequence: &str) -> Vec<u8> {
+        let mut sequence = sequence.as_bytes().to_vec();
+        encode(&mut sequence);
+        sequence
+    }
+
+    #[test]
+    fn reads_names_background_and_matrices() {
+        let dir = TestDir::new("meme");
+        let motifs = read_meme(&dir.write("motifs.meme", MEME)).unwrap();
+        let names: Vec<&str> = motifs.iter().map(|motif| motif.name.as_str()).collect();
+        assert_eq!(names, ["MA0001.1_FIRST", "MA0002.1"]);
+        assert_eq!(motifs[0].weights.len(), 3);
+        // uniform probabilities against the file's background
+        let weights = motifs[1].weights[0];
+        assert!((weights[0] - (0.25f64 / 0.3).log2()).abs() < 1e-9);
+        assert!((weights[1] - (0.25f64 / 0.2).log2()).abs() < 1e-9);
+
+        let truncated = MEME.trim_end().trim_end_matches(" 0.25 0.25 0.25 0.25");
+        let error = read_meme(&dir.write("truncated.meme", truncated)).map(|_| ()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = read_meme(&dir.write("unnamed.meme", "letter-probability matrix: w= 1\n1 0 0 0\n")).map(|_| ()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn scanner_matches_either_strand() {
+        let dir = TestDir::new("meme-scan");
+        let motifs = read_meme(&dir.write("motifs.meme", MEME)).unwrap();
+        let scanner = MotifScanner::new(&motifs[0], 0.9);
+        assert!(scanner.matches(&encoded("TTACGTT")));
+        // reverse complement of ACG
+        assert!(scanner.matches(&encoded("TTCGTTT")));
+        assert!(!scanner.matches(&encoded("TTAGGTT")));
+        assert!(!scanner.matches(&encoded("ANG")));
+        assert!(!scanner.matches(&encoded("AC")));
+        // a threshold of zero accepts any window without N
+        assert!(MotifScanner::new(&motifs[0], 0.0).matches(&encoded("TTT")));
+    }
+}
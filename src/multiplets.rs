@@ -0,0 +1,273 @@
+use std::{
+    io,
+    fs,
+    path::Path,
+    error::Error,
+    io::Write,
+};
+use log::info;
+use rustc_hash::FxHashMap;
+use crate::barcodes::BarcodeMap;
+use crate::chroms::ChromFilter;
+use crate::dedup::{collapse_fragments, CollapseOptions};
+use crate::f2m::load_cells;
+use crate::fragments::FragmentStream;
+use crate::input::{input_path, is_stdin, stdin_unsupported};
+use crate::output::{deflate_level, OutputCompression};
+use crate::stats::{benjamini_hochberg, poisson_upper_log10};
+
+pub fn multiplets(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = input_path(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+    if is_stdin(&frag_file) {
+        return Err(stdin_unsupported("multiplets").into());
+    }
+
+    let output_path = Path::new(matches.get_one::<String>("outdir").unwrap());
+    info!("Received output directory: {:?}", output_path);
+    fs::create_dir_all(output_path)?;
+
+    let options = MultipletOptions {
+        min_fragments: *matches.get_one::<u64>("min-fragments").unwrap(),
+        max_barcodes: *matches.get_one::<usize>("max-barcodes").unwrap(),
+        min_shared: *matches.get_one::<u64>("min-shared").unwrap(),
+        q_threshold: *matches.get_one::<f64>("q-value").unwrap(),
+    };
+    if options.max_barcodes < 2 {
+        return Err("--max-barcodes must be at least 2".into());
+    }
+    let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let compression = OutputCompression::from_matches(matches, num_threads)?;
+
+    let chrom_filter = ChromFilter::from_matches(matches)?;
+    let barcode_map = BarcodeMap::from_matches(matches)?;
+    let stream = || FragmentStream::open(&frag_file, &chrom_filter, barcode_map.as_ref());
+
+    // barcodes to test, with their fragment counts
+    let cell_list = match matches.get_one::<String>("cells") {
+        Some(cell_file) => {
+            info!("Received cell file: {:?}", cell_file);
+            let (cells, _) = load_cells(Path::new(cell_file))?;
+            Some(cells)
+        }
+        None => None,
+    };
+    info!("Counting fragments per barcode");
+    let mut counts: FxHashMap<String, u64> = FxHashMap::default();
+    let mut fragments = stream()?;
+    while let Some(fragment) = fragments.next_fragment()? {
+        match counts.get_mut(fragment.barcode.as_ref()) {
+            Some(count) => *count += 1,
+            None => {
+                counts.insert(fragment.barcode.into_owned(), 1);
+            }
+        }
+    }
+    let mut barcodes: Vec<String> = match cell_list {
+        Some(cells) => cells,
+        None => counts.iter()
+            .filter(|(_, &count)| count >= options.min_fragments)
+            .map(|(barcode, _)| barcode.clone())
+            .collect(),
+    };
+    barcodes.sort();
+    barcodes.dedup();
+    let totals: Vec<u64> = barcodes.iter().map(|barcode| counts.get(barcode).copied().unwrap_or(0)).collect();
+    let index: FxHashMap<&str, u32> = barcodes.iter()
+        .enumerate()
+        .map(|(i, barcode)| (barcode.as_str(), i as u32))
+        .collect();
+    info!("Testing {} barcodes for shared fragments", barcodes.len());
+
+    info!("Counting identical fragments shared between barcodes");
+    let shared = shared_fragments(stream()?, &index, options.max_barcodes)?;
+
+    // chance of a given fragment pair being identical, from all barcode
+    // pairs, so that each pair can be compared to its expected overlap
+    let total_fragments: f64 = totals.iter().map(|&n| n as f64).sum();
+    let sum_squares: f64 = totals.iter().map(|&n| (n as f64).powi(2)).sum();
+    let pair_opportunities = (total_fragments.powi(2) - sum_squares) / 2.0;
+    let total_shared: u64 = shared.values().sum();
+    let rate = if pair_opportunities > 0.0 { total_shared as f64 / pair_opportunities } else { 0.0 };
+
+    let pairs: Vec<((u32, u32), u64)> = shared.into_iter().collect();
+    let p_values = pair_p_values(&pairs, &totals, rate);
+    let q_values = benjamini_hochberg(&p_values);
+
+    let pairs_path = output_path.join(compression.file_name("pairs.tsv"));
+    info!("Writing multiplet barcode pairs: {:?}", pairs_path);
+    let mut writer = compression.create(&pairs_path)?;
+    writeln!(writer, "barcode1\tbarcode2\tshared\tp_value\tq_value")?;
+    let mut components = UnionFind::new(barcodes.len());
+    for (&((a, b), count), (&p, &q)) in pairs.iter().zip(p_values.iter().zip(q_values.iter())) {
+        if count < options.min_shared || q >= options.q_threshold {
+            continue;
+        }
+        components.union(a as usize, b as usize);
+        writeln!(writer, "{}\t{}\t{}\t{:.6e}\t{:.6e}", barcodes[a as usize], barcodes[b as usize], count, p, q)?;
+    }
+    writer.finish()?;
+
+    // each multiplet is named after its barcode with the most fragments
+    let mut groups: FxHashMap<usize, Vec<usize>> = FxHashMap::default();
+    for barcode in 0..barcodes.len() {
+        groups.entry(components.find(barcode)).or_default().push(barcode);
+    }
+    let mut multiplets: Vec<Vec<usize>> = groups.into_values().filter(|members| members.len() > 1).collect();
+    for members in multiplets.iter_mut() {
+        members.sort_by(|&a, &b| totals[b].cmp(&totals[a]).then(barcodes[a].cmp(&barcodes[b])));
+    }
+    multiplets.sort_by(|a, b| barcodes[a[0]].cmp(&barcodes[b[0]]));
+
+    let multiplet_path = output_path.join(compression.file_name("multiplets.tsv"));
+    info!("Writing {} multiplets: {:?}", multiplets.len(), multiplet_path);
+    let mut writer = compression.create(&multiplet_path)?;
+    writeln!(writer, "barcode\tmultiplet\tfragments")?;
+    let mut rename: FxHashMap<String, String> = FxHashMap::default();
+    for members in multiplets.iter() {
+        let major = &barcodes[members[0]];
+        for &member in members.iter() {
+            writeln!(writer, "{}\t{}\t{}", barcodes[member], major, totals[member])?;
+            if member != members[0] {
+                rename.insert(barcodes[member].clone(), major.clone());
+            }
+        }
+    }
+    writer.finish()?;
+
+    if matches.get_flag("rewrite") {
+        let path = output_path.join("fragments.tsv.gz");
+        info!("Writing fragments with multiplet barcodes merged: {:?}", path);
+        let options = CollapseOptions { max_barcodes: None, rename: Some(&rename), level: deflate_level(matches)?, num_threads };
+        let stats = collapse_fragments(&frag_file, &path, &options, &chrom_filter, barcode_map.as_ref())?;
+        info!("Collapsed {} fragments made identical by merging barcodes", stats.duplicates);
+    }
+
+    Ok(())
+}
+
+/// Poisson p-value of each barcode pair sharing its number of identical
+/// fragments, given the chance `rate` of any fragment pair being identical
+fn pair_p_values(pairs: &[((u32, u32), u64)], totals: &[u64], rate: f64) -> Vec<f64> {
+    pairs.iter()
+        .map(|&((a, b), count)| {
+            let lambda = totals[a as usize] as f64 * totals[b as usize] as f64 * rate;
+            10f64.powf(-poisson_upper_log10(count, lambda))
+        })
+        .collect()
+}
+
+struct MultipletOptions {
+    min_fragments: u64,
+    max_barcodes: usize,
+    min_shared: u64,
+    q_threshold: f64,
+}
+
+/// Number of identical fragments for each pair of tested barcodes (lower
+/// index first). Fragments found in more than `max_barcodes` barcodes are
+/// ignored, as they are more likely to come from hotspots than multiplets.
+fn shared_fragments(
+    mut fragments: FragmentStream,
+    index: &FxHashMap<&str, u32>,
+    max_barcodes: usize,
+) -> io::Result<FxHashMap<(u32, u32), u64>> {
+    let mut shared: FxHashMap<(u32, u32), u64> = FxHashMap::default();
+
+    // fragments at the current start position: (end, barcode)
+    let mut at_start: Vec<(u32, u32)> = Vec::new();
+    let mut current_chrom = String::new();
+    let mut current_start: u32 = 0;
+
+    let mut flush = |at_start: &mut Vec<(u32, u32)>| {
+        at_start.sort_unstable();
+        at_start.dedup();
+        for same_end in at_start.chunk_by(|a, b| a.0 == b.0) {
+            if same_end.len() < 2 || same_end.len() > max_barcodes {
+                continue;
+            }
+            for (i, &(_, a)) in same_end.iter().enumerate() {
+                for &(_, b) in same_end[i + 1..].iter() {
+                    *shared.entry((a, b)).or_insert(0) += 1;
+                }
+            }
+        }
+        at_start.clear();
+    };
+
+    while let Some(fragment) = fragments.next_fragment()? {
+        let barcode = match index.get(fragment.barcode.as_ref()) {
+            Some(&barcode) => barcode,
+            None => continue,
+        };
+        if fragment.chrom != current_chrom {
+            flush(&mut at_start);
+            current_chrom = fragment.chrom.to_string();
+            current_start = fragment.start;
+        } else if fragment.start != current_start {
+            if fragment.start < current_start {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Fragment file is not sorted by position",
+                ));
+            }
+            flush(&mut at_start);
+            current_start = fragment.start;
+        }
+        at_start.push((fragment.end, barcode));
+    }
+    flush(&mut at_start);
+
+    Ok(shared)
+}
+
+/// Disjoint sets of barcodes linked by significant pairs
+struct UnionFind {
+    parent: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(size: usize) -> Self {
+        UnionFind { parent: (0..size).collect() }
+    }
+
+    fn find(&mut self, mut item: usize) -> usize {
+        while self.parent[item] != item {
+            self.parent[item] = self.parent[self.parent[item]];
+            item = self.parent[item];
+        }
+        item
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parent[b] = a;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn union_find_joins_linked_barcodes() {
+        let mut components = UnionFind::new(5);
+        components.union(0, 1);
+        components.union(3, 1);
+        assert_eq!(components.find(3), components.find(0));
+        assert_ne!(components.find(2), components.find(0));
+        assert_ne!(components.find(4), components.find(2));
+    }
+
+    #[test]
+    fn pairs_sharing_more_than_expected_have_small_p_values() {
+        // both pairs expect 1 shared fragment
+        let pairs = [((0, 1), 1), ((0, 2), 12)];
+        let p_values = pair_p_values(&pairs, &[100, 100, 100], 1e-4);
+        assert!(p_values[0] > 0.5);
+        assert!(p_values[1] < 1e-8);
+    }
+}
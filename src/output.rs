@@ -0,0 +1,201 @@
+use std::{
+    io,
+    path::Path,
+    fs::File,
+    io::BufWriter,
+    io::Write,
+};
+use flate2::Compression;
+use gzp::{
+    deflate::Gzip,
+    ZWriter,
+    par::compress::{ParCompress, ParCompressBuilder},
+};
+use crate::bgzf::{compress_blocks, BLOCK_SIZE, EOF_BLOCK};
+
+/// Compression format for text output
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputFormat {
+    Gzip,
+    /// blocked gzip, readable by gzip and indexable by tabix
+    Bgzf,
+    Zstd,
+    None,
+}
+
+/// Compression settings for text output, from `--compression` and `--level`
+#[derive(Clone, Copy, Debug)]
+pub struct OutputCompression {
+    pub format: OutputFormat,
+    // None for the format's default level
+    level: Option<u32>,
+    num_threads: usize,
+}
+
+/// Read a deflate compression level from `--level`, for subcommands that
+/// always write bgzip-compressed, tabix-indexed output
+pub fn deflate_level(matches: &clap::ArgMatches) -> io::Result<Compression> {
+    match matches.get_one::<u32>("level") {
+        Some(&level) if level > 9 => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Compression level {} is out of range, gzip levels are 0-9", level),
+        )),
+        Some(&level) => Ok(Compression::new(level)),
+        None => Ok(Compression::default()),
+    }
+}
+
+impl OutputCompression {
+    pub fn from_matches(matches: &clap::ArgMatches, num_threads: usize) -> io::Result<Self> {
+        let format = match matches.get_one::<String>("compression").map(String::as_str) {
+            Some("zstd") => OutputFormat::Zstd,
+            Some("bgzf") => OutputFormat::Bgzf,
+            Some("none") => OutputFormat::None,
+            _ => OutputFormat::Gzip,
+        };
+        let level = matches.get_one::<u32>("level").copied();
+        let range = match format {
+            OutputFormat::Zstd => 1..=22,
+            _ => 0..=9,
+        };
+        if let Some(level) = level {
+            if format != OutputFormat::None && !range.contains(&level) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Compression level {} is out of range for {:?}, expected {}-{}", level, format, range.start(), range.end()),
+                ));
+            }
+        }
+        Ok(OutputCompression { format, level, num_threads })
+    }
+
+    /// File name extension added by this compression, including the dot
+    pub fn extension(&self) -> &'static str {
+        match self.format {
+            OutputFormat::Gzip | OutputFormat::Bgzf => ".gz",
+            OutputFormat::Zstd => ".zst",
+            OutputFormat::None => "",
+        }
+    }
+
+    /// `name` with the extension for this compression, e.g. matrix.mtx.gz
+    pub fn file_name(&self, name: &str) -> String {
+        format!("{}{}", name, self.extension())
+    }
+
+    pub fn create(&self, path: &Path) -> io::Result<OutputWriter> {
+        self.wrap(File::create(path)?)
+    }
+
+    /// Compress everything written to `writer`, e.g. stdout
+    pub fn wrap<W: Write + Send + 'static>(&self, writer: W) -> io::Result<OutputWriter> {
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        Ok(match self.format {
+            OutputFormat::Gzip => {
+                let level = self.level.map(Compression::new).unwrap_or_default();
+                let encoder: ParCompress<Gzip> = ParCompressBuilder::new()
+                    .compression_level(level)
+                    .num_threads(self.num_threads)
+                    .map_err(io::Error::other)?
+                    .from_writer(writer);
+                OutputWriter::Gzip(encoder)
+            }
+            OutputFormat::Bgzf => OutputWriter::Bgzf(BgzfStream {
+                writer: BufWriter::with_capacity(1024 * 1024, writer),
+                buffer: Vec::new(),
+                level: self.level.map(Compression::new).unwrap_or_default(),
+                num_threads: self.num_threads,
+            }),
+            OutputFormat::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(writer, self.level.unwrap_or(0) as i32)?;
+                if self.num_threads > 1 {
+                    encoder.multithread(self.num_threads as u32)?;
+                }
+                OutputWriter::Zstd(encoder)
+            }
+            OutputFormat::None => OutputWriter::Plain(BufWriter::with_capacity(1024 * 1024, writer)),
+        })
+    }
+}
+
+/// Writer returned by `OutputCompression`. `finish` must be called to
+/// write the end of the compressed stream.
+pub enum OutputWriter {
+    Gzip(ParCompress<Gzip>),
+    Bgzf(BgzfStream),
+    Zstd(zstd::stream::write::Encoder<'static, Box<dyn Write + Send>>),
+    Plain(BufWriter<Box<dyn Write + Send>>),
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Gzip(writer) => writer.write(data),
+            OutputWriter::Bgzf(writer) => writer.write(data),
+            OutputWriter::Zstd(writer) => writer.write(data),
+            OutputWriter::Plain(writer) => writer.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Gzip(writer) => writer.flush(),
+            OutputWriter::Bgzf(writer) => writer.flush(),
+            OutputWriter::Zstd(writer) => writer.flush(),
+            OutputWriter::Plain(writer) => writer.flush(),
+        }
+    }
+}
+
+impl OutputWriter {
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            OutputWriter::Gzip(mut writer) => writer.finish().map_err(io::Error::other),
+            OutputWriter::Bgzf(writer) => writer.finish(),
+            OutputWriter::Zstd(writer) => writer.finish()?.flush(),
+            OutputWriter::Plain(mut writer) => writer.flush(),
+        }
+    }
+}
+
+/// Streaming BGZF output without an index, compressing complete blocks
+/// in parallel as the buffer fills
+pub struct BgzfStream {
+    writer: BufWriter<Box<dyn Write + Send>>,
+    buffer: Vec<u8>,
+    level: Compression,
+    num_threads: usize,
+}
+
+impl BgzfStream {
+    fn write_blocks(&mut self, length: usize) -> io::Result<()> {
+        for block in compress_blocks(&self.buffer[..length], self.level, self.num_threads)? {
+            self.writer.write_all(&block)?;
+        }
+        self.buffer.drain(..length);
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.write_blocks(self.buffer.len())?;
+        self.writer.write_all(&EOF_BLOCK)?;
+        self.writer.flush()
+    }
+}
+
+impl Write for BgzfStream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= BLOCK_SIZE * 16 * self.num_threads.max(1) {
+            let complete = (self.buffer.len() / BLOCK_SIZE) * BLOCK_SIZE;
+            self.write_blocks(complete)?;
+        }
+        Ok(data.len())
+    }
+
+    /// Blocks are only written once full, so that the output is identical
+    /// however often the caller flushes
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
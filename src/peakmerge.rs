@@ -0,0 +1,42 @@
+use std::{
+    path::Path,
+    error::Error,
+};
+use log::info;
+use crate::chroms::{load_chrom_sizes, ChromFilter};
+use crate::output::OutputCompression;
+use crate::peaks::{iterative_overlap_merge, normalize_scores, read_peaks, write_bed, Peak};
+
+pub fn merge(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let peak_files: Vec<&String> = matches.get_many::<String>("input").unwrap().collect();
+    let output = Path::new(matches.get_one::<String>("output").unwrap());
+    let width = *matches.get_one::<u32>("width").unwrap();
+    if width == 0 {
+        return Err("Peak width must be greater than zero".into());
+    }
+    let chrom_filter = ChromFilter::from_matches(matches)?;
+    let chrom_sizes = match matches.get_one::<String>("genome") {
+        Some(genome) => Some(load_chrom_sizes(Path::new(genome))?),
+        None => None,
+    };
+    let compression = OutputCompression::from_matches(matches, 1)?;
+
+    // scores are normalized within each file so that peak sets from
+    // samples of different depth contribute fairly
+    let mut all_peaks: Vec<Peak> = Vec::new();
+    for peak_file in peak_files {
+        let path = Path::new(peak_file);
+        let mut peaks = read_peaks(path, &chrom_filter)?;
+        info!("Read {} peaks from {:?}", peaks.len(), path);
+        normalize_scores(&mut peaks);
+        all_peaks.extend(peaks);
+    }
+
+    let total = all_peaks.len();
+    let merged = iterative_overlap_merge(all_peaks, width, chrom_sizes.as_ref());
+    info!("Writing {} of {} peaks after overlap removal: {:?}", merged.len(), total, output);
+    write_bed(output, &merged, &compression)?;
+
+    Ok(())
+}
@@ -0,0 +1,213 @@
+use std::{
+    io,
+    path::Path,
+    io::Write,
+    io::BufRead,
+    collections::BTreeSet,
+};
+use log::warn;
+use rustc_hash::FxHashMap;
+use crate::input::open_input;
+use crate::chroms::ChromFilter;
+use crate::output::OutputCompression;
+
+/// A peak with its summit position and a score used to rank overlapping
+/// peaks. `signal` and `pvalue` hold the narrowPeak signal value and
+/// -log10 p-value columns.
+pub struct Peak {
+    pub chrom: String,
+    pub start: u32,
+    pub end: u32,
+    pub summit: u32,
+    pub name: String,
+    pub score: f64,
+    pub signal: f64,
+    pub pvalue: f64,
+}
+
+/// Read peaks from a narrowPeak or BED file. Lines of a `.narrowPeak` file,
+/// or with exactly ten columns, are read as narrowPeak: the summit offset in
+/// column 10 and the -log10 p-value in column 8 are used, falling back to
+/// the signal value. Otherwise, e.g. for BED12, the summit is the peak
+/// midpoint and the score is taken from column 5 if present.
+pub fn read_peaks(path: &Path, chrom_filter: &ChromFilter) -> io::Result<Vec<Peak>> {
+    let reader = open_input(path)?;
+    let mut peaks: Vec<Peak> = Vec::new();
+    let narrowpeak_file = path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.contains(".narrowPeak"));
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") || line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 3 {
+            warn!("{:?} line {}: Less than three fields", path, index + 1);
+            continue;
+        }
+        let (start, end) = match (fields[1].parse::<u32>(), fields[2].parse::<u32>()) {
+            (Ok(start), Ok(end)) if end > start => (start, end),
+            _ => {
+                warn!("{:?} line {}: Failed to parse peak coordinates", path, index + 1);
+                continue;
+            }
+        };
+        let chrom = match chrom_filter.resolve(fields[0]) {
+            Some(chrom) => chrom.to_string(),
+            None => continue,
+        };
+
+        let narrowpeak = narrowpeak_file || fields.len() == 10;
+        let column = |i: usize| fields.get(i).and_then(|field| field.parse::<f64>().ok());
+        let (signal, pvalue) = if narrowpeak {
+            (column(6).unwrap_or(-1.0), column(7).unwrap_or(-1.0))
+        } else {
+            (-1.0, -1.0)
+        };
+        let summit = match fields.get(9).filter(|_| narrowpeak).and_then(|field| field.parse::<u32>().ok()) {
+            Some(offset) if start + offset < end => start + offset,
+            _ => start + (end - start) / 2,
+        };
+        let score = if pvalue >= 0.0 {
+            pvalue
+        } else if signal >= 0.0 {
+            signal
+        } else {
+            column(4).unwrap_or(0.0)
+        };
+
+        peaks.push(Peak {
+            chrom,
+            start,
+            end,
+            summit,
+            name: fields.get(3).unwrap_or(&".").to_string(),
+            score,
+            signal,
+            pvalue,
+        });
+    }
+    Ok(peaks)
+}
+
+/// Replace scores by their quantile within the peak set, so that peak sets
+/// with different sequencing depths can be merged fairly
+pub fn normalize_scores(peaks: &mut [Peak]) {
+    let n = peaks.len();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&a, &b| peaks[a].score.total_cmp(&peaks[b].score));
+    for (rank, index) in order.into_iter().enumerate() {
+        peaks[index].score = (rank + 1) as f64 / n as f64;
+    }
+}
+
+/// Iterative overlap removal: peaks are resized to a fixed width around
+/// their summit, then taken in order of decreasing score, discarding any
+/// peak that overlaps a peak already kept. The result is sorted by position,
+/// with chromosomes in order of first appearance. With chromosome sizes,
+/// peaks are trimmed to the end of their chromosome, and peaks with a summit
+/// beyond it are dropped.
+pub fn iterative_overlap_merge(peaks: Vec<Peak>, width: u32, chrom_sizes: Option<&FxHashMap<String, u32>>) -> Vec<Peak> {
+    let half = width / 2;
+
+    let mut chrom_order: FxHashMap<String, usize> = FxHashMap::default();
+    let mut outside: usize = 0;
+    let mut resized: Vec<Peak> = peaks.into_iter()
+        .filter_map(|peak| {
+            let next = chrom_order.len();
+            chrom_order.entry(peak.chrom.clone()).or_insert(next);
+            let start = peak.summit.saturating_sub(half);
+            let size = chrom_sizes.and_then(|sizes| sizes.get(&peak.chrom)).copied().unwrap_or(u32::MAX);
+            if peak.summit >= size {
+                outside += 1;
+                return None;
+            }
+            Some(Peak { start, end: start.saturating_add(width).min(size), ..peak })
+        })
+        .collect();
+    if outside > 0 {
+        warn!("Dropped {} peaks with a summit beyond the end of the chromosome", outside);
+    }
+    resized.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    // starts of kept peaks on each chromosome; all peaks have the same width
+    // so a peak overlaps a kept peak if their starts are less than `width` apart
+    let mut kept_starts: FxHashMap<String, BTreeSet<u32>> = FxHashMap::default();
+    let mut kept: Vec<Peak> = Vec::new();
+    for peak in resized {
+        let starts = kept_starts.entry(peak.chrom.clone()).or_default();
+        let low = peak.start.saturating_sub(width - 1);
+        let high = peak.start.saturating_add(width - 1);
+        if starts.range(low..=high).next().is_none() {
+            starts.insert(peak.start);
+            kept.push(peak);
+        }
+    }
+
+    kept.sort_by_key(|peak| (chrom_order[&peak.chrom], peak.start));
+    kept
+}
+
+/// Write peaks as BED with name and score columns
+pub fn write_bed(path: &Path, peaks: &[Peak], compression: &OutputCompression) -> io::Result<()> {
+    let mut writer = compression.create(path)?;
+    for peak in peaks {
+        writeln!(writer, "{}\t{}\t{}\t{}\t{:.5}", peak.chrom, peak.start, peak.end, peak.name, peak.score)?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peak(chrom: &str, summit: u32, score: f64) -> Peak {
+        Peak {
+            chrom: chrom.to_string(),
+            start: summit,
+            end: summit + 1,
+            summit,
+            name: String::from("."),
+            score,
+            pvalue: 0.0,
+            signal: 0.0,
+        }
+    }
+
+    fn read(name: &str, contents: &str) -> Vec<(u32, u32, f64)> {
+        let dir = crate::testing::TestDir::new("peaks-read");
+        let path = dir.write(name, contents);
+        read_peaks(&path, &ChromFilter::default()).unwrap()
+            .iter()
+            .map(|peak| (peak.start, peak.summit, peak.score))
+            .collect()
+    }
+
+    #[test]
+    fn narrowpeak_columns_give_summit_and_score() {
+        let line = "chr1\t1000\t1500\tpeak1\t50\t.\t8.5\t12.25\t-1\t100\n";
+        assert_eq!(read("peaks.bed", line), [(1000, 1100, 12.25)]);
+        // a .narrowPeak file without the summit column
+        let line = "chr1\t1000\t1500\tpeak1\t50\t.\t8.5\t12.25\t-1\n";
+        assert_eq!(read("peaks.narrowPeak", line), [(1000, 1250, 12.25)]);
+    }
+
+    #[test]
+    fn bed12_uses_the_score_column() {
+        let line = "chr1\t1000\t1500\tgene\t7\t+\t1100\t1400\t0\t2\t100,100\t0,400\n";
+        assert_eq!(read("genes.bed", line), [(1000, 1250, 7.0)]);
+    }
+
+    #[test]
+    fn peaks_are_trimmed_to_chromosome_ends() {
+        let peaks = vec![peak("chr1", 500, 1.0), peak("chr1", 990, 0.5), peak("chr1", 1200, 0.9), peak("chr2", 100, 1.0)];
+        let sizes: FxHashMap<String, u32> = [("chr1".to_string(), 1000)].into_iter().collect();
+        let merged = iterative_overlap_merge(peaks, 101, Some(&sizes));
+        let coordinates: Vec<(&str, u32, u32)> = merged.iter()
+            .map(|peak| (peak.chrom.as_str(), peak.start, peak.end))
+            .collect();
+        assert_eq!(coordinates, [("chr1", 450, 551), ("chr1", 940, 1000), ("chr2", 50, 151)]);
+    }
+}
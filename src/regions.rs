@@ -0,0 +1,89 @@
+use std::{
+    io,
+    path::Path,
+    io::BufRead,
+};
+use rust_lapper::{Interval, Lapper};
+use log::error;
+use rustc_hash::FxHashMap;
+use crate::input::open_input;
+
+/// Interval index for a set of genomic regions, keyed by chromosome name.
+/// The interval value is the index of the region in the BED file.
+pub type RegionIndex = FxHashMap<String, Lapper<u32, usize>>;
+
+/// A parsed BED line, with all of its tab-separated columns
+pub struct BedRecord<'a> {
+    /// 1-based line number in the BED file
+    pub line: usize,
+    pub chrom: &'a str,
+    pub start: u32,
+    pub end: u32,
+    pub fields: &'a [&'a str],
+}
+
+/// Read a plain or compressed BED file into an interval index. Header,
+/// comment and blank lines are skipped, and lines without valid
+/// coordinates are logged and skipped. `value` gives the interval value
+/// for each record, or None to skip it.
+pub fn read_bed<F>(bed_file: &Path, mut value: F) -> io::Result<RegionIndex>
+where
+    F: FnMut(&BedRecord) -> Option<usize>,
+{
+    let mut chromosome_trees: FxHashMap<String, Vec<Interval<u32, usize>>> = FxHashMap::default();
+
+    for (index, line) in open_input(bed_file)?.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 3 {
+            error!("Line {}: Less than three fields", index + 1);
+            continue;
+        }
+        let start: u32 = match fields[1].parse() {
+            Ok(num) => num,
+            Err(_) => {
+                error!("Line {}: Failed to parse start position", index + 1);
+                continue;
+            }
+        };
+        let end: u32 = match fields[2].parse() {
+            Ok(num) => num,
+            Err(_) => {
+                error!("Line {}: Failed to parse end position", index + 1);
+                continue;
+            }
+        };
+        let record = BedRecord { line: index + 1, chrom: fields[0], start, end, fields: &fields };
+        if let Some(val) = value(&record) {
+            chromosome_trees
+                .entry(fields[0].to_string())
+                .or_default()
+                .push(Interval { start, stop: end, val });
+        }
+    }
+
+    Ok(chromosome_trees.into_iter()
+        .map(|(chr, intervals)| (chr, Lapper::new(intervals)))
+        .collect())
+}
+
+/// Read a BED file into an interval index, with each interval valued by
+/// its index among the valid lines of the file
+pub fn load_regions(bed_file: &Path) -> io::Result<RegionIndex> {
+    let mut region_index: usize = 0;
+    read_bed(bed_file, |_| {
+        region_index += 1;
+        Some(region_index - 1)
+    })
+}
+
+/// Check whether the half-open interval [start, end) overlaps any region
+pub fn overlaps(regions: &RegionIndex, chrom: &str, start: u32, end: u32) -> bool {
+    match regions.get(chrom) {
+        Some(lapper) => lapper.find(start, end).next().is_some(),
+        None => false,
+    }
+}
@@ -0,0 +1,116 @@
+use std::{
+    io,
+    fs,
+    path::Path,
+    error::Error,
+};
+use flate2::Compression;
+use log::{info, warn};
+use crate::barcodes::{load_groups, BarcodeMap, CellGroups};
+use crate::bgzf::{BgzfWriter, BLOCK_SIZE};
+use crate::chroms::ChromFilter;
+use crate::fragments::FragmentStream;
+use crate::input::input_path;
+use crate::output::deflate_level;
+use crate::tabix::TabixBuilder;
+
+pub fn split(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = input_path(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+
+    let group_file = Path::new(matches.get_one::<String>("groups").unwrap());
+    info!("Received group file: {:?}", group_file);
+
+    let output_path = Path::new(matches.get_one::<String>("outdir").unwrap());
+    info!("Received output directory: {:?}", output_path);
+    fs::create_dir_all(output_path)?;
+
+    let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let level = deflate_level(matches)?;
+
+    let groups = load_groups(group_file)?;
+    let chrom_filter = ChromFilter::from_matches(matches)?;
+    let barcode_map = BarcodeMap::from_matches(matches)?;
+
+    split_fragments(&frag_file, &groups, output_path, level, num_threads, &chrom_filter, barcode_map.as_ref())?;
+
+    Ok(())
+}
+
+/// Output fragment file and index for one group
+struct GroupOutput {
+    writer: BgzfWriter,
+    index: TabixBuilder,
+}
+
+fn split_fragments(
+    frag_file: &Path,
+    groups: &CellGroups,
+    output: &Path,
+    level: Compression,
+    num_threads: usize,
+    chrom_filter: &ChromFilter,
+    barcode_map: Option<&BarcodeMap>,
+) -> io::Result<()> {
+
+    // data is held in memory per group and compressed once enough
+    // blocks have accumulated to keep all threads busy
+    let flush_size = BLOCK_SIZE * 16 * num_threads.max(1);
+
+    let file_names = groups.file_names()?;
+    let mut outputs: Vec<Option<GroupOutput>> = groups.names.iter().map(|_| None).collect();
+
+    let mut fragments = FragmentStream::open(frag_file, chrom_filter, barcode_map)?;
+    let mut line_out: Vec<u8> = Vec::with_capacity(1024);
+
+    while let Some(fragment) = fragments.next_line()? {
+        let group = match groups.cells.get(fragment.barcode.as_ref()) {
+            Some(&group) => group,
+            None => continue,
+        };
+
+        let out = match &mut outputs[group] {
+            Some(out) => out,
+            slot => {
+                let path = output.join(format!("{}.fragments.tsv.gz", file_names[group]));
+                info!("Creating output file: {:?}", path);
+                slot.insert(GroupOutput {
+                    writer: BgzfWriter::create(&path, level, num_threads)?,
+                    index: TabixBuilder::default(),
+                })
+            }
+        };
+
+        line_out.clear();
+        fragment.write(&mut line_out)?;
+
+        let ustart = out.writer.position();
+        out.writer.write_all(&line_out)?;
+        out.index.add(fragment.chrom, fragment.start, fragment.end, ustart, out.writer.position());
+
+        if out.writer.buffered() >= flush_size {
+            out.writer.flush_blocks()?;
+        }
+    }
+
+    for (group, out) in outputs.into_iter().enumerate() {
+        let name = &groups.names[group];
+        let out = match out {
+            Some(out) => out,
+            None => {
+                warn!("No fragments found for group {}", name);
+                continue;
+            }
+        };
+        let layout = out.writer.finish()?;
+        if out.index.is_unsorted() {
+            warn!("Fragments for group {} are not sorted, skipping index", name);
+            continue;
+        }
+        let index_path = output.join(format!("{}.fragments.tsv.gz.tbi", file_names[group]));
+        out.index.write(&index_path, &layout)?;
+    }
+
+    Ok(())
+}
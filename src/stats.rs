@@ -0,0 +1,91 @@
+use std::f64::consts::LN_10;
+
+/// Natural log of n!
+pub fn ln_factorial(n: u64) -> f64 {
+    if n < 2 {
+        return 0.0;
+    }
+    if n < 32 {
+        return (2..=n).map(|i| (i as f64).ln()).sum();
+    }
+    // Stirling series
+    let n = n as f64;
+    n * n.ln() - n + 0.5 * (2.0 * std::f64::consts::PI * n).ln() + 1.0 / (12.0 * n)
+        - 1.0 / (360.0 * n.powi(3))
+}
+
+/// -log10 of the Poisson upper tail P(X >= k) for mean `lambda`
+pub fn poisson_upper_log10(k: u64, lambda: f64) -> f64 {
+    if k == 0 || lambda <= 0.0 {
+        return if k > 0 && lambda <= 0.0 { f64::INFINITY } else { 0.0 };
+    }
+
+    // sum pmf terms from k upwards in log space
+    let ln_lambda = lambda.ln();
+    let first = k as f64 * ln_lambda - lambda - ln_factorial(k);
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut i = k;
+    loop {
+        i += 1;
+        term *= lambda / i as f64;
+        sum += term;
+        if term < sum * 1e-12 || i > k + 100_000 {
+            break;
+        }
+    }
+    let ln_p = (first + sum.ln()).min(0.0);
+    -ln_p / LN_10
+}
+
+/// Benjamini-Hochberg adjusted p-values, in the order of `p_values`
+pub fn benjamini_hochberg(p_values: &[f64]) -> Vec<f64> {
+    let n = p_values.len();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&a, &b| p_values[b].total_cmp(&p_values[a]));
+
+    let mut adjusted = vec![0.0; n];
+    let mut running_min = 1.0f64;
+    for (rank, &index) in order.iter().enumerate() {
+        // rank 0 holds the largest p-value, which has rank n among n tests
+        let q = p_values[index] * n as f64 / (n - rank) as f64;
+        running_min = running_min.min(q);
+        adjusted[index] = running_min;
+    }
+    adjusted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6 * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn ln_factorial_matches_exact_values() {
+        assert_eq!(ln_factorial(0), 0.0);
+        assert!(close(ln_factorial(5), 120f64.ln()));
+        // above 31 the Stirling series is used
+        assert!(close(ln_factorial(40), 110.32063971475738));
+    }
+
+    #[test]
+    fn poisson_upper_tail() {
+        assert_eq!(poisson_upper_log10(0, 2.0), 0.0);
+        assert_eq!(poisson_upper_log10(3, 0.0), f64::INFINITY);
+        // P(X >= 1) = 1 - e^-1
+        assert!(close(poisson_upper_log10(1, 1.0), 0.19920008462778144));
+        assert!(close(poisson_upper_log10(3, 0.5), 1.842009291365952));
+        assert!(close(poisson_upper_log10(40, 20.0), 4.274071836157972));
+    }
+
+    #[test]
+    fn benjamini_hochberg_is_monotone_in_input_order() {
+        let adjusted = benjamini_hochberg(&[0.01, 0.04, 0.03, 0.5]);
+        let expected = [0.04, 0.04 * 4.0 / 3.0, 0.04 * 4.0 / 3.0, 0.5];
+        assert!(adjusted.iter().zip(expected.iter()).all(|(&a, &b)| close(a, b)));
+        assert!(benjamini_hochberg(&[]).is_empty());
+    }
+}
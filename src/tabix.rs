@@ -0,0 +1,253 @@
+use std::{
+    io,
+    fs::File,
+    io::Write,
+    path::Path,
+};
+use flate2::Compression;
+use rustc_hash::FxHashMap;
+use crate::bgzf::{compress_blocks, BlockLayout, EOF_BLOCK};
+
+const MIN_SHIFT: u32 = 14;
+
+// tabix preset for BED-like files: 0-based coordinates (TBX_UCSC flag),
+// sequence in column 1, start in column 2, end in column 3
+const FORMAT_UCSC: i32 = 0x10000;
+
+/// Index of a single reference sequence. Offsets are uncompressed positions
+/// until the index is written, when they are converted to virtual offsets.
+#[derive(Default)]
+struct RefIndex {
+    bins: FxHashMap<u32, Vec<(u64, u64)>>,
+    linear: Vec<u64>,
+}
+
+/// Incrementally builds a tabix index for a sorted, BED-like file
+#[derive(Default)]
+pub struct TabixBuilder {
+    names: Vec<String>,
+    refs: Vec<RefIndex>,
+    ref_ids: FxHashMap<String, usize>,
+    last_start: u32,
+    unsorted: bool,
+}
+
+/// Smallest bin containing [beg, end), using the standard 5-level binning scheme
+fn reg2bin(beg: u32, end: u32) -> u32 {
+    let end = end.max(beg + 1) - 1;
+    if beg >> 14 == end >> 14 { return 4681 + (beg >> 14); }
+    if beg >> 17 == end >> 17 { return 585 + (beg >> 17); }
+    if beg >> 20 == end >> 20 { return 73 + (beg >> 20); }
+    if beg >> 23 == end >> 23 { return 9 + (beg >> 23); }
+    if beg >> 26 == end >> 26 { return 1 + (beg >> 26); }
+    0
+}
+
+impl TabixBuilder {
+    /// Record a line spanning uncompressed positions [ustart, uend)
+    pub fn add(&mut self, chrom: &str, start: u32, end: u32, ustart: u64, uend: u64) {
+        let current = self.names.last().map(String::as_str);
+        if current != Some(chrom) {
+            if self.ref_ids.contains_key(chrom) {
+                // chromosome seen before, file is not sorted
+                self.unsorted = true;
+            }
+            self.ref_ids.insert(chrom.to_string(), self.refs.len());
+            self.names.push(chrom.to_string());
+            self.refs.push(RefIndex::default());
+            self.last_start = 0;
+        }
+        if start < self.last_start {
+            self.unsorted = true;
+        }
+        self.last_start = start;
+
+        let index = self.refs.last_mut().expect("reference created above");
+
+        // extend the last chunk of the bin if this line directly follows it
+        let chunks = index.bins.entry(reg2bin(start, end)).or_default();
+        match chunks.last_mut() {
+            Some(chunk) if chunk.1 == ustart => chunk.1 = uend,
+            _ => chunks.push((ustart, uend)),
+        }
+
+        let first_window = (start >> MIN_SHIFT) as usize;
+        let last_window = (end.max(start + 1) - 1) >> MIN_SHIFT;
+        if index.linear.len() <= last_window as usize {
+            index.linear.resize(last_window as usize + 1, u64::MAX);
+        }
+        for offset in &mut index.linear[first_window..=last_window as usize] {
+            if *offset == u64::MAX {
+                *offset = ustart;
+            }
+        }
+    }
+
+    /// Returns true if the records were not sorted by chromosome and position
+    pub fn is_unsorted(&self) -> bool {
+        self.unsorted
+    }
+
+    /// Write the index as a BGZF-compressed .tbi file
+    pub fn write(self, path: &Path, layout: &BlockLayout) -> io::Result<()> {
+        let mut data: Vec<u8> = Vec::new();
+
+        let mut names: Vec<u8> = Vec::new();
+        for name in &self.names {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+
+        data.extend_from_slice(b"TBI\x01");
+        data.extend_from_slice(&(self.refs.len() as i32).to_le_bytes());
+        for value in [FORMAT_UCSC, 1, 2, 3, b'#' as i32, 0, names.len() as i32] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&names);
+
+        for mut index in self.refs {
+            let mut bins: Vec<(u32, Vec<(u64, u64)>)> = index.bins.into_iter().collect();
+            bins.sort_by_key(|(bin, _)| *bin);
+
+            data.extend_from_slice(&(bins.len() as i32).to_le_bytes());
+            for (bin, chunks) in bins {
+                data.extend_from_slice(&bin.to_le_bytes());
+                data.extend_from_slice(&(chunks.len() as i32).to_le_bytes());
+                for (ustart, uend) in chunks {
+                    data.extend_from_slice(&layout.virtual_offset(ustart).to_le_bytes());
+                    data.extend_from_slice(&layout.virtual_offset(uend).to_le_bytes());
+                }
+            }
+
+            // windows without records point at the closest preceding record
+            let first = index.linear.iter().copied().find(|&o| o != u64::MAX).unwrap_or(0);
+            let mut previous = first;
+            for offset in &mut index.linear {
+                if *offset == u64::MAX {
+                    *offset = previous;
+                }
+                previous = *offset;
+            }
+            data.extend_from_slice(&(index.linear.len() as i32).to_le_bytes());
+            for offset in index.linear {
+                data.extend_from_slice(&layout.virtual_offset(offset).to_le_bytes());
+            }
+        }
+
+        let mut file = File::create(path)?;
+        for block in compress_blocks(&data, Compression::default(), 1)? {
+            file.write_all(&block)?;
+        }
+        file.write_all(&EOF_BLOCK)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, io::Read};
+    use flate2::read::MultiGzDecoder;
+    use crate::bgzf::{BgzfWriter, BLOCK_SIZE};
+    use crate::testing::TestDir;
+
+    fn i32_at(data: &[u8], offset: usize) -> i32 {
+        i32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn bins_for_fragments_spanning_windows() {
+        // within the first 16 kb window
+        assert_eq!(reg2bin(100, 200), 4681);
+        assert_eq!(reg2bin(16_383, 16_384), 4681);
+        // second window
+        assert_eq!(reg2bin(16_384, 16_500), 4682);
+        // spanning two 16 kb windows, in the first 128 kb bin
+        assert_eq!(reg2bin(16_000, 17_000), 585);
+        // spanning two 128 kb bins
+        assert_eq!(reg2bin(131_000, 132_000), 73);
+    }
+
+    #[test]
+    fn index_entries_for_fragments_spanning_windows() {
+        let mut builder = TabixBuilder::default();
+        builder.add("chr1", 16_000, 17_000, 0, 20);
+        builder.add("chr1", 16_100, 16_200, 20, 40);
+        builder.add("chr1", 50_000, 50_100, 40, 60);
+        builder.add("chr2", 10, 20, 60, 80);
+        assert!(!builder.is_unsorted());
+
+        let chr1 = &builder.refs[0];
+        assert_eq!(chr1.bins[&585], [(0, 20)]);
+        assert_eq!(chr1.bins[&4681], [(20, 40)]);
+        assert_eq!(chr1.bins[&4684], [(40, 60)]);
+        // windows 0 and 1 start at the spanning fragment, window 2 is empty
+        assert_eq!(chr1.linear, [0, 0, u64::MAX, 40]);
+        assert_eq!(builder.refs[1].linear, [60]);
+
+        builder.add("chr1", 0, 10, 80, 100);
+        assert!(builder.is_unsorted());
+    }
+
+    #[test]
+    fn written_index_uses_virtual_offsets() {
+        let dir = TestDir::new("tabix");
+        let data_path = dir.join("fragments.tsv.gz");
+        let mut index_path = data_path.as_os_str().to_owned();
+        index_path.push(".tbi");
+
+        // the third line starts in the second BGZF block
+        let mut writer = BgzfWriter::create(&data_path, Compression::default(), 1).unwrap();
+        let mut builder = TabixBuilder::default();
+        let lines = [
+            ("chr1", 16_000, 17_000, "chr1\t16000\t17000\tA\t1\n".to_string()),
+            ("chr1", 20_000, 20_100, "x".repeat(BLOCK_SIZE)),
+            ("chr1", 50_000, 50_100, "chr1\t50000\t50100\tB\t1\n".to_string()),
+        ];
+        for (chrom, start, end, line) in &lines {
+            let ustart = writer.position();
+            writer.write_all(line.as_bytes()).unwrap();
+            builder.add(chrom, *start, *end, ustart, writer.position());
+        }
+        let layout = writer.finish().unwrap();
+        builder.write(Path::new(&index_path), &layout).unwrap();
+
+        let mut index = Vec::new();
+        MultiGzDecoder::new(fs::File::open(&index_path).unwrap()).read_to_end(&mut index).unwrap();
+        assert_eq!(&index[..4], b"TBI\x01");
+        assert_eq!(i32_at(&index, 4), 1);
+        assert_eq!(i32_at(&index, 8), FORMAT_UCSC);
+        assert_eq!(i32_at(&index, 32), 5); // length of "chr1\0"
+        assert_eq!(&index[36..41], b"chr1\0");
+
+        // bins 585 (spanning windows), 4682 and 4684, sorted by bin number
+        let mut offset = 41;
+        assert_eq!(i32_at(&index, offset), 3);
+        offset += 4;
+        let mut chunks = Vec::new();
+        for _ in 0..3 {
+            let bin = i32_at(&index, offset);
+            assert_eq!(i32_at(&index, offset + 4), 1);
+            chunks.push((bin, u64_at(&index, offset + 8), u64_at(&index, offset + 16)));
+            offset += 24;
+        }
+        let second_line = lines[0].3.len() as u64;
+        let third_line = second_line + BLOCK_SIZE as u64;
+        assert_eq!(chunks, [
+            (585, 0, layout.virtual_offset(second_line)),
+            (4682, layout.virtual_offset(second_line), layout.virtual_offset(third_line)),
+            (4684, layout.virtual_offset(third_line), layout.virtual_offset(third_line + lines[2].3.len() as u64)),
+        ]);
+        assert!(layout.virtual_offset(third_line) >> 16 > 0);
+
+        // the empty window 2 points at the closest preceding record
+        assert_eq!(i32_at(&index, offset), 4);
+        let linear: Vec<u64> = (0..4).map(|window| u64_at(&index, offset + 4 + 8 * window)).collect();
+        assert_eq!(linear, [0, 0, 0, layout.virtual_offset(third_line)]);
+
+    }
+}